@@ -1,5 +1,5 @@
 use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+use easy_fs::{BlockDevice, EasyFileSystem, FsError};
 use std::fs::{read_dir, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
@@ -14,18 +14,20 @@ struct BlockFile(Mutex<File>);
 
 impl BlockDevice for BlockFile {
     /// Read a block from file
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), FsError> {
         let mut file = self.0.lock().unwrap();
         file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
             .expect("Error when seeking!");
         assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        Ok(())
     }
     /// Write a block into file
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), FsError> {
         let mut file = self.0.lock().unwrap();
         file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        Ok(())
     }
 }
 
@@ -67,7 +69,6 @@ fn easy_fs_pack() -> std::io::Result<()> {
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
     let apps: Vec<_> = read_dir(src_path)
         .unwrap()
-        .into_iter()
         .map(|dir_entry| {
             let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
             name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
@@ -82,7 +83,7 @@ fn easy_fs_pack() -> std::io::Result<()> {
         // create a file in easy-fs
         let inode = root_inode.create(app.as_str()).unwrap();
         // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+        inode.write_at(0, all_data.as_slice()).unwrap();
     }
     // list apps
     for app in root_inode.ls() {
@@ -91,48 +92,109 @@ fn easy_fs_pack() -> std::io::Result<()> {
     Ok(())
 }
 
+/// Create a zeroed image of BLOCK_NUM blocks at `path` for a test
+#[cfg(test)]
+fn test_block_file(path: &str) -> std::io::Result<Arc<BlockFile>> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    f.set_len((BLOCK_NUM * BLOCK_SZ) as u64)?;
+    Ok(Arc::new(BlockFile(Mutex::new(f))))
+}
+
+/// A block device failing every request while `failing` is set, for a test
+#[cfg(test)]
+struct FailingBlockFile {
+    block_file: Arc<BlockFile>,
+    failing: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(test)]
+impl FailingBlockFile {
+    fn check(&self) -> Result<(), FsError> {
+        if self.failing.load(std::sync::atomic::Ordering::Relaxed) {
+            Err(FsError::Io)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for FailingBlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        self.check()?;
+        self.block_file.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), FsError> {
+        self.check()?;
+        self.block_file.write_block(block_id, buf)
+    }
+}
+
+/// A block device whose users cannot wait for the filesystem, for a test
+#[cfg(test)]
+struct NoWaitBlockFile {
+    block_file: Arc<BlockFile>,
+    notified: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl BlockDevice for NoWaitBlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        self.block_file.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), FsError> {
+        self.block_file.write_block(block_id, buf)
+    }
+    fn relax(&self, _held: &dyn Fn() -> bool) -> bool {
+        false
+    }
+    fn notify(&self) {
+        self.notified
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len(BLOCK_NUM * BLOCK_SZ).unwrap();
-        f
-    })));
+    let block_file = test_block_file("target/fs.img")?;
     EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
     let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
     for name in root_inode.ls() {
         println!("{}", name);
     }
     let filea = root_inode.find("filea").unwrap();
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
     //let mut buffer = [0u8; BLOCK_SZ];
     let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
+    let len = filea.read_at(0, &mut buffer).unwrap();
     assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
 
     let mut random_str_test = |len: usize| {
         filea.clear();
-        assert_eq!(filea.read_at(0, &mut buffer), 0,);
+        assert_eq!(filea.read_at(0, &mut buffer).unwrap(), 0,);
+        assert_eq!(filea.size(), 0);
         let mut str = String::new();
         use rand;
         // random digit
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
+        assert_eq!(filea.size(), len);
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
         loop {
-            let len = filea.read_at(offset, &mut read_buffer);
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
             if len == 0 {
                 break;
             }
@@ -151,5 +213,229 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(1000 * BLOCK_SZ);
     random_str_test(2000 * BLOCK_SZ);
 
+    // the blocks reserved hold the data of the file in order
+    let fileb = root_inode.find("fileb").unwrap();
+    let blocks = fileb.reserve_blocks(40 * BLOCK_SZ).unwrap();
+    assert_eq!(blocks.len(), 40);
+    assert_eq!(fileb.size(), 40 * BLOCK_SZ);
+    fileb.write_at(30 * BLOCK_SZ, &[0x5au8; BLOCK_SZ]).unwrap();
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(blocks[30] as usize, &mut block).unwrap();
+    assert_eq!(block, [0x5au8; BLOCK_SZ]);
+
+    Ok(())
+}
+
+#[test]
+fn efs_dir_test() -> std::io::Result<()> {
+    let block_file = test_block_file("target/fs_dir.img")?;
+    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    assert!(root_inode.find_path("..").unwrap().is_dir());
+
+    let dira = root_inode.mkdir("dira").unwrap();
+    assert!(dira.is_dir());
+    assert_eq!(root_inode.mkdir("dira").err(), Some(FsError::Exists));
+    assert_eq!(root_inode.mkdir("a-name-longer-than-the-limit").err(), Some(FsError::NameTooLong));
+    assert_eq!(root_inode.mkdir("..").err(), Some(FsError::InvalidName));
+    let dirb = dira.mkdir("dirb").unwrap();
+    let filec = dirb.create("filec").unwrap();
+    assert!(!filec.is_dir());
+    assert_eq!(filec.create("filed").err(), Some(FsError::NotDir));
+    filec.write_at(0, "Hello, world!".as_bytes()).unwrap();
+    assert_eq!(dirb.ls(), vec![".", "..", "filec"]);
+
+    let mut buffer = [0u8; 32];
+    let found = root_inode.find_path("/dira/./dirb//filec").unwrap();
+    let len = found.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], "Hello, world!".as_bytes());
+    let found = dirb.find_path("../../dira/dirb/filec").unwrap();
+    assert_eq!(found.read_at(0, &mut buffer).unwrap(), len);
+    assert_eq!(root_inode.find_path("dira/filec").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.find_path("dira/dirb/filec/x").err(), Some(FsError::NotDir));
+
+    // only empty directories can be removed
+    assert_eq!(root_inode.rmdir("dira"), Err(FsError::NotEmpty));
+    assert_eq!(dira.rmdir("."), Err(FsError::InvalidName));
+    assert_eq!(dirb.rmdir("filec"), Err(FsError::NotDir));
+    let dirc = dira.mkdir("dirc").unwrap();
+    assert_eq!(dira.rmdir("dirc"), Ok(()));
+    assert_eq!(dira.find("dirc").err(), Some(FsError::NotFound));
+    // a removed directory still open is empty for good
+    assert_eq!(dirc.find("..").err(), Some(FsError::NotFound));
+    assert_eq!(dirc.create("filef").err(), Some(FsError::NotFound));
+    assert_eq!(dirc.mkdir("dird").err(), Some(FsError::NotFound));
+    assert_eq!(dira.ls(), vec![".", "..", "dirb"]);
+    // removing a dirent moves the last one into its place, so a new one is appended
+    dira.create("filee").unwrap();
+    assert_eq!(dira.ls(), vec![".", "..", "dirb", "filee"]);
+    Ok(())
+}
+
+#[test]
+fn efs_link_test() -> std::io::Result<()> {
+    let block_file = test_block_file("target/fs_link.img")?;
+    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    assert_eq!(root_inode.nlink(), 2);
+
+    let dira = root_inode.mkdir("dira").unwrap();
+    assert_eq!(root_inode.nlink(), 3);
+    assert_eq!(dira.nlink(), 2);
+    let filea = root_inode.create("filea").unwrap();
+    assert_eq!(filea.nlink(), 1);
+    filea.write_at(0, "Hello, world!".as_bytes()).unwrap();
+
+    // a second dirent refers to the same inode
+    assert_eq!(dira.link("fileb", &filea), Ok(()));
+    assert_eq!(dira.link("fileb", &filea), Err(FsError::Exists));
+    assert_eq!(root_inode.link("dirb", &dira), Err(FsError::IsDir));
+    assert_eq!(filea.nlink(), 2);
+    let fileb = root_inode.find_path("dira/fileb").unwrap();
+    assert_eq!(fileb.inode_id(), filea.inode_id());
+
+    // the inode is freed with its last link
+    assert_eq!(root_inode.unlink("dira"), Err(FsError::IsDir));
+    assert_eq!(root_inode.unlink("filea"), Ok(()));
+    assert_eq!(root_inode.unlink("filea"), Err(FsError::NotFound));
+    assert_eq!(fileb.nlink(), 1);
+    let mut buffer = [0u8; 32];
+    let len = fileb.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], "Hello, world!".as_bytes());
+    assert_eq!(dira.unlink("fileb"), Ok(()));
+    // an unlinked file is kept for the handles still open to it
+    let filec = root_inode.create("filec").unwrap();
+    assert_ne!(filec.inode_id(), filea.inode_id());
+    let len = filea.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], "Hello, world!".as_bytes());
+    // and freed with the last of them
+    let inode_id = filea.inode_id();
+    drop(filea);
+    drop(fileb);
+    let filed = root_inode.create("filed").unwrap();
+    assert_eq!(filed.inode_id(), inode_id);
+    assert_eq!(filed.read_at(0, &mut buffer).unwrap(), 0);
+
+    assert_eq!(root_inode.rmdir("dira"), Ok(()));
+    assert_eq!(root_inode.nlink(), 2);
+    Ok(())
+}
+
+#[test]
+fn efs_space_test() -> std::io::Result<()> {
+    let block_file = test_block_file("target/fs_space.img")?;
+    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    let filea = root_inode.create("filea").unwrap();
+    let fill = || {
+        let mut size = 0;
+        for chunk_size in [16 * BLOCK_SZ, BLOCK_SZ] {
+            let chunk = vec![0x5au8; chunk_size];
+            while filea.write_at(size, &chunk).is_ok() {
+                size += chunk.len();
+            }
+        }
+        size
+    };
+
+    // running out of space is reported instead of panicking
+    let full_size = fill();
+    assert!(full_size > 0);
+    // the last free block may be too few for an indirect block of filea
+    let fileb = root_inode.create("fileb").unwrap();
+    let _ = fileb.write_at(0, &[0x5au8; BLOCK_SZ]);
+    assert_eq!(fileb.write_at(BLOCK_SZ, &[0x5au8; BLOCK_SZ]), Err(FsError::NoSpace));
+    assert_eq!(root_inode.mkdir("dira").err(), Some(FsError::NoSpace));
+    assert_eq!(root_inode.unlink("fileb"), Ok(()));
+    filea.clear();
+
+    // a directory gives back its blocks as its dirents are removed
+    let dira = root_inode.mkdir("dira").unwrap();
+    for i in 0..500 {
+        dira.create(format!("file{}", i).as_str()).unwrap();
+    }
+    assert_eq!(root_inode.rmdir("dira"), Err(FsError::NotEmpty));
+    for i in (0..500).step_by(2).chain((1..500).step_by(2)) {
+        assert_eq!(dira.unlink(format!("file{}", i).as_str()), Ok(()));
+    }
+    assert_eq!(dira.ls(), vec![".", ".."]);
+    assert_eq!(root_inode.rmdir("dira"), Ok(()));
+    assert_eq!(fill(), full_size);
+    filea.clear();
+
+    // inodes are given back on unlink
+    for _ in 0..5000 {
+        root_inode.create("fileb").unwrap();
+        assert_eq!(root_inode.unlink("fileb"), Ok(()));
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_corrupted_test() -> std::io::Result<()> {
+    let block_file = test_block_file("target/fs_corrupted.img")?;
+    // an image without a valid super block is refused
+    assert!(matches!(EasyFileSystem::open(block_file), Err(FsError::Corrupted)));
+    Ok(())
+}
+
+#[test]
+fn efs_io_error_test() -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let block_file = test_block_file("target/fs_io_error.img")?;
+    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let device = Arc::new(FailingBlockFile {
+        block_file,
+        failing: AtomicBool::new(false),
+    });
+    let efs = EasyFileSystem::open(device.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    // more blocks than the block cache holds, so that the first ones are read again
+    let data = vec![0x5au8; 32 * BLOCK_SZ];
+    assert_eq!(filea.write_at(0, &data), Ok(data.len()));
+
+    // a failure of the device is reported instead of panicking
+    device.failing.store(true, Ordering::Relaxed);
+    let mut buffer = [0u8; BLOCK_SZ];
+    assert_eq!(filea.read_at(0, &mut buffer), Err(FsError::Io));
+    assert_eq!(filea.write_at(0, &[0xa5u8; BLOCK_SZ]), Err(FsError::Io));
+    assert_eq!(root_inode.find("filea").err(), Some(FsError::Io));
+
+    // the blocks which failed are read again, and were never written back
+    device.failing.store(false, Ordering::Relaxed);
+    assert_eq!(filea.read_at(0, &mut buffer), Ok(BLOCK_SZ));
+    assert_eq!(buffer, [0x5au8; BLOCK_SZ]);
+    assert!(root_inode.find("filea").is_ok());
+    Ok(())
+}
+
+#[test]
+fn efs_busy_test() -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let block_file = test_block_file("target/fs_busy.img")?;
+    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let device = Arc::new(NoWaitBlockFile {
+        block_file,
+        notified: AtomicUsize::new(0),
+    });
+    let efs = EasyFileSystem::open(device.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+
+    // a user who cannot wait for the filesystem held by another fails instead of spinning
+    let fs = efs.lock();
+    assert_eq!(root_inode.find("filea").err(), Some(FsError::Busy));
+    assert_eq!(filea.write_at(0, b"busy"), Err(FsError::Busy));
+    drop(fs);
+
+    // the waiters are notified each time the filesystem is released
+    let notified = device.notified.load(Ordering::Relaxed);
+    assert_eq!(filea.write_at(0, b"busy"), Ok(4));
+    assert!(root_inode.find("filea").is_ok());
+    assert_eq!(device.notified.load(Ordering::Relaxed), notified + 2);
     Ok(())
 }
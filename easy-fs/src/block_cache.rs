@@ -2,7 +2,7 @@ use super::{
     BLOCK_SZ,
     BlockDevice,
 };
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeSet, VecDeque};
 use alloc::sync::Arc;
 use lazy_static::*;
 use spin::Mutex;
@@ -17,6 +17,9 @@ pub struct BlockCache {
     block_device: Arc<dyn BlockDevice>,
     /// whether the block is dirty
     modified: bool,
+    /// whether the block could not be read, so that its data is made up
+    /// and must never be written back
+    failed: bool,
 }
 
 impl BlockCache {
@@ -26,12 +29,16 @@ impl BlockCache {
         block_device: Arc<dyn BlockDevice>
     ) -> Self {
         let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
+        let failed = block_device.read_block(block_id, &mut cache).is_err();
+        if failed {
+            set_io_error(&block_device);
+        }
         Self {
             cache,
             block_id,
             block_device,
             modified: false,
+            failed,
         }
     }
     /// Get the address of an offset inside the cached block data
@@ -63,9 +70,12 @@ impl BlockCache {
     }
 
     pub fn sync(&mut self) {
-        if self.modified {
-            self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
+        if self.modified && !self.failed {
+            // the block stays dirty to be written again later
+            match self.block_device.write_block(self.block_id, &self.cache) {
+                Ok(()) => self.modified = false,
+                Err(_) => set_io_error(&self.block_device),
+            }
         }
     }
 }
@@ -79,8 +89,14 @@ impl Drop for BlockCache {
 /// Use a block cache of 16 blocks
 const BLOCK_CACHE_SIZE: usize = 16;
 
+/// Identify a block device by the address it lives at
+pub fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    /// (block id, device id, cache) of cached blocks
+    queue: VecDeque<(usize, usize, Arc<Mutex<BlockCache>>)>,
 }
 
 impl BlockCacheManager {
@@ -93,28 +109,33 @@ impl BlockCacheManager {
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
+        let device_id = device_id(&block_device);
         if let Some(pair) = self.queue
             .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
+            .find(|pair| pair.0 == block_id && pair.1 == device_id) {
+                Arc::clone(&pair.2)
         } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self.queue
+            // substitute from front to tail until there is room again,
+            // if every block is in use, the cache grows beyond its size
+            // for a while instead of failing, and shrinks back later
+            while self.queue.len() >= BLOCK_CACHE_SIZE {
+                match self.queue
                     .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
+                    .position(|pair| Arc::strong_count(&pair.2) == 1) {
+                    Some(idx) => {
+                        self.queue.remove(idx);
+                    }
+                    None => break,
                 }
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(
-                BlockCache::new(block_id, Arc::clone(&block_device))
-            ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
+            // load block into mem and push back,
+            // unless it failed to load and should be read again next time
+            let block_cache = BlockCache::new(block_id, Arc::clone(&block_device));
+            let failed = block_cache.failed;
+            let block_cache = Arc::new(Mutex::new(block_cache));
+            if !failed {
+                self.queue.push_back((block_id, device_id, Arc::clone(&block_cache)));
+            }
             block_cache
         }
     }
@@ -127,6 +148,20 @@ lazy_static! {
     );
 }
 
+lazy_static! {
+    /// The block devices which have failed since the last [`take_io_error`] of each
+    static ref IO_ERRORS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+}
+
+fn set_io_error(block_device: &Arc<dyn BlockDevice>) {
+    IO_ERRORS.lock().insert(device_id(block_device));
+}
+
+/// Whether a block device has failed to read or write a block since the last call
+pub fn take_io_error(block_device: &Arc<dyn BlockDevice>) -> bool {
+    IO_ERRORS.lock().remove(&device_id(block_device))
+}
+
 /// Get the block cache corresponding to the given block id and block device
 pub fn get_block_cache(
     block_id: usize,
@@ -138,7 +173,7 @@ pub fn get_block_cache(
 /// Sync all block cache to block device
 pub fn block_cache_sync_all() {
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    for (_, _, cache) in manager.queue.iter() {
         cache.lock().sync();
     }
 }
@@ -1,8 +1,20 @@
 use core::any::Any;
+use super::FsError;
 
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
 pub trait BlockDevice : Send + Sync + Any {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    /// Read a block, failing with [`FsError::Io`] if the device reports an error
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), FsError>;
+    /// Write a block, failing with [`FsError::Io`] if the device reports an error
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), FsError>;
+    /// Wait for the filesystem another user holds, who may be waiting for this device,
+    /// until `held` tells that it is released or for a moment.
+    /// Return false if the caller cannot wait, which then fails with [`FsError::Busy`].
+    fn relax(&self, _held: &dyn Fn() -> bool) -> bool {
+        core::hint::spin_loop();
+        true
+    }
+    /// Wake up the users waiting in [`BlockDevice::relax`], as the filesystem is released
+    fn notify(&self) {}
 }
@@ -6,6 +6,7 @@ use super::{
     SuperBlock,
     DiskInode,
     DiskInodeType,
+    FsError,
     Inode,
     get_block_cache,
     block_cache_sync_all,
@@ -19,6 +20,7 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    data_area_blocks: u32,
 }
 
 /// A data block of block size
@@ -50,6 +52,7 @@ impl EasyFileSystem {
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            data_area_blocks,
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -76,7 +79,7 @@ impl EasyFileSystem {
         });
         // write back immediately
         // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        assert_eq!(efs.alloc_inode(), Ok(0));
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
         get_block_cache(
             root_inode_block_id as usize,
@@ -86,16 +89,38 @@ impl EasyFileSystem {
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
             disk_inode.initialize(DiskInodeType::Directory);
         });
+        let efs = Arc::new(Mutex::new(efs));
+        // both "." and ".." of the root refer to itself
+        let root_inode = Self::root_inode(&efs);
+        root_inode
+            .init_dir_entries(0, &mut efs.lock())
+            .expect("No space for the root directory!");
         block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+        efs
     }
     /// Open a block device as a filesystem
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>, FsError> {
         // read SuperBlock
         get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
+                if !super_block.is_valid() {
+                    return Err(FsError::Corrupted);
+                }
+                // the areas must fill the disk and the bitmaps must cover their areas
+                let inode_num = Bitmap::new(1, super_block.inode_bitmap_blocks as usize).maximum();
+                let data_num = Bitmap::new(0, super_block.data_bitmap_blocks as usize).maximum();
+                if 1 + super_block.inode_bitmap_blocks as u64
+                    + super_block.inode_area_blocks as u64
+                    + super_block.data_bitmap_blocks as u64
+                    + super_block.data_area_blocks as u64
+                    != super_block.total_blocks as u64
+                    || inode_num * core::mem::size_of::<DiskInode>()
+                        > super_block.inode_area_blocks as usize * BLOCK_SZ
+                    || data_num < super_block.data_area_blocks as usize
+                {
+                    return Err(FsError::Corrupted);
+                }
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
                 let efs = Self {
@@ -110,8 +135,9 @@ impl EasyFileSystem {
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    data_area_blocks: super_block.data_area_blocks,
                 };
-                Arc::new(Mutex::new(efs))
+                Ok(Arc::new(Mutex::new(efs)))
             })
     }
     /// Get the root inode of the filesystem
@@ -121,6 +147,7 @@ impl EasyFileSystem {
         let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
         // release efs lock
         Inode::new(
+            0,
             block_id,
             block_offset,
             Arc::clone(efs),
@@ -139,12 +166,25 @@ impl EasyFileSystem {
         self.data_area_start_block + data_block_id
     }
     /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    pub fn alloc_inode(&mut self) -> Result<u32, FsError> {
+        self.inode_bitmap
+            .alloc(&self.block_device)
+            .map(|inode_id| inode_id as u32)
+            .ok_or(FsError::NoSpace)
+    }
+    /// Deallocate an inode
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        self.inode_bitmap.dealloc(&self.block_device, inode_id as usize)
     }
     /// Allocate a data block
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    pub fn alloc_data(&mut self) -> Result<u32, FsError> {
+        let bit = self.data_bitmap.alloc(&self.block_device).ok_or(FsError::NoSpace)?;
+        // the last bitmap block may cover more bits than the data area has blocks
+        if bit >= self.data_area_blocks as usize {
+            self.data_bitmap.dealloc(&self.block_device, bit);
+            return Err(FsError::NoSpace);
+        }
+        Ok(bit as u32 + self.data_area_start_block)
     }
     /// Deallocate a data block
     pub fn dealloc_data(&mut self, block_id: u32) {
@@ -0,0 +1,26 @@
+/// Errors of easy-fs operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// No free inode or data block is left
+    NoSpace,
+    /// A directory is required
+    NotDir,
+    /// A file is required
+    IsDir,
+    /// The name is already in use
+    Exists,
+    /// The name is not found
+    NotFound,
+    /// The name is longer than the limit of a dirent
+    NameTooLong,
+    /// The name is empty, contains '/', or is "." or ".." where not allowed
+    InvalidName,
+    /// The directory to remove is not empty
+    NotEmpty,
+    /// The on-disk metadata is corrupted
+    Corrupted,
+    /// The block device failed to read or write a block
+    Io,
+    /// The filesystem is held by another user, whom the caller cannot wait for
+    Busy,
+}
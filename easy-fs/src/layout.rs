@@ -10,9 +10,9 @@ use alloc::vec::Vec;
 /// Magic number for sanity check
 const EFS_MAGIC: u32 = 0x3b800001;
 /// The max number of direct inodes
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 27;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 27;
 /// The max number of indirect1 inodes
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 inodes
@@ -92,6 +92,8 @@ pub struct DiskInode {
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    /// The number of dirents referring to this inode
+    pub nlink: u32,
     type_: DiskInodeType,
 }
 
@@ -103,6 +105,11 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        // a directory is also referred to by its own "."
+        self.nlink = match type_ {
+            DiskInodeType::File => 1,
+            DiskInodeType::Directory => 2,
+        };
         self.type_ = type_;
     }
     /// Whether this inode is a directory
@@ -255,6 +262,52 @@ impl DiskInode {
             }
         });
     }
+    /// Decrease the size of current disk inode and return blocks that
+    /// should be deallocated, including indirect blocks no longer needed
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size);
+        let mut v: Vec<u32> = Vec::new();
+        let old_blocks = self.data_blocks() as usize;
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        // data blocks
+        for inner_id in new_blocks..old_blocks {
+            v.push(self.get_block_id(inner_id as u32, block_device));
+        }
+        for inner_id in new_blocks..old_blocks.min(INODE_DIRECT_COUNT) {
+            self.direct[inner_id] = 0;
+        }
+        // indirect1 block
+        if old_blocks > INODE_DIRECT_COUNT && new_blocks <= INODE_DIRECT_COUNT {
+            v.push(self.indirect1);
+            self.indirect1 = 0;
+        }
+        // low-level indirect1 blocks under indirect2
+        if old_blocks > INDIRECT1_BOUND {
+            let sub_blocks = |blocks: usize| {
+                (blocks.saturating_sub(INDIRECT1_BOUND) + INODE_INDIRECT1_COUNT - 1)
+                    / INODE_INDIRECT1_COUNT
+            };
+            get_block_cache(
+                self.indirect2 as usize,
+                Arc::clone(block_device),
+            )
+            .lock()
+            .read(0, |indirect2: &IndirectBlock| {
+                v.extend_from_slice(&indirect2[sub_blocks(new_blocks)..sub_blocks(old_blocks)]);
+            });
+            // indirect2 block
+            if new_blocks <= INDIRECT1_BOUND {
+                v.push(self.indirect2);
+                self.indirect2 = 0;
+            }
+        }
+        self.size = new_size;
+        v
+    }
     /// Clear size to zero and return blocks that should be deallocated
     /// and clear the block contents to zero later
     pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
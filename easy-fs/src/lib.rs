@@ -8,12 +8,14 @@ mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod error;
 
 /// Use a block size of 512 bytes
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
 pub use efs::EasyFileSystem;
 pub use vfs::Inode;
+pub use error::FsError;
 use layout::*;
 use bitmap::Bitmap;
-use block_cache::{get_block_cache, block_cache_sync_all};
+use block_cache::{get_block_cache, block_cache_sync_all, device_id, take_io_error};
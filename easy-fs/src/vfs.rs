@@ -4,17 +4,69 @@ use super::{
     DiskInodeType,
     DirEntry,
     EasyFileSystem,
+    FsError,
     DIRENT_SZ,
+    NAME_LENGTH_LIMIT,
     get_block_cache,
     block_cache_sync_all,
+    device_id,
+    take_io_error,
 };
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use lazy_static::*;
 use spin::{Mutex, MutexGuard};
 
+/// The vfs inodes alive for an inode
+struct OpenInode {
+    count: usize,
+    /// Whether the inode has lost its last link, so that it is freed with its last vfs inode
+    unlinked: bool,
+    /// Held so that the device id is not reused while the inode waits to be freed
+    _block_device: Arc<dyn BlockDevice>,
+}
+
+/// The filesystem locked by a vfs inode,
+/// whose block device is notified once it is released for the users waiting for it
+struct FsGuard<'a> {
+    fs: Option<MutexGuard<'a, EasyFileSystem>>,
+    block_device: &'a Arc<dyn BlockDevice>,
+}
+
+impl<'a> Deref for FsGuard<'a> {
+    type Target = MutexGuard<'a, EasyFileSystem>;
+    fn deref(&self) -> &Self::Target {
+        self.fs.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for FsGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.fs.as_mut().unwrap()
+    }
+}
+
+impl Drop for FsGuard<'_> {
+    fn drop(&mut self) {
+        self.fs = None;
+        self.block_device.notify();
+    }
+}
+
+lazy_static! {
+    /// The vfs inodes alive for each (device id, inode id),
+    /// with the unlinked inodes left by their last vfs inode at a count of 0 until freed
+    static ref OPEN_INODES: Mutex<BTreeMap<(usize, u32), OpenInode>> =
+        Mutex::new(BTreeMap::new());
+}
+
 /// Virtual filesystem layer over easy-fs
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
@@ -24,18 +76,110 @@ pub struct Inode {
 impl Inode {
     /// Create a vfs inode
     pub fn new(
+        inode_id: u32,
         block_id: u32,
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
+        OPEN_INODES
+            .lock()
+            .entry((device_id(&block_device), inode_id))
+            .or_insert_with(|| OpenInode {
+                count: 0,
+                unlinked: false,
+                _block_device: Arc::clone(&block_device),
+            })
+            .count += 1;
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
         }
     }
+    /// Lock the filesystem, waiting through the block device while another user holds it,
+    /// or fail with [`FsError::Busy`] if the caller cannot wait for it.
+    /// The failures of the block device before are forgotten,
+    /// which belong to the operations they happened in.
+    fn try_lock_fs(&self) -> Result<FsGuard<'_>, FsError> {
+        let mut fs = loop {
+            if let Some(fs) = self.fs.try_lock() {
+                break fs;
+            }
+            if !self.block_device.relax(&|| self.fs.is_locked()) {
+                return Err(FsError::Busy);
+            }
+        };
+        take_io_error(&self.block_device);
+        self.free_unlinked(&mut fs);
+        Ok(FsGuard {
+            fs: Some(fs),
+            block_device: &self.block_device,
+        })
+    }
+    /// Lock the filesystem for an operation which cannot fail,
+    /// spinning instead if the caller cannot wait for it
+    fn lock_fs(&self) -> FsGuard<'_> {
+        loop {
+            if let Ok(fs) = self.try_lock_fs() {
+                return fs;
+            }
+            spin_loop();
+        }
+    }
+    /// Free the unlinked inodes whose last vfs inode has been dropped
+    fn free_unlinked(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        let device_id = device_id(&self.block_device);
+        let mut unlinked: Vec<u32> = Vec::new();
+        OPEN_INODES
+            .lock()
+            .retain(|&(device, inode_id), open_inode| {
+                if device == device_id && open_inode.count == 0 {
+                    unlinked.push(inode_id);
+                    return false;
+                }
+                true
+            });
+        if unlinked.is_empty() {
+            return;
+        }
+        for inode_id in unlinked {
+            if let Ok(inode) = self.get_inode(inode_id, fs) {
+                inode.free(fs);
+            }
+        }
+        block_cache_sync_all();
+    }
+    /// Free an inode which has lost its last link,
+    /// or leave it to the last vfs inode of it if others than current one are alive
+    fn free_unlinked_inode(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        {
+            let mut open_inodes = OPEN_INODES.lock();
+            let open_inode = open_inodes
+                .get_mut(&(device_id(&self.block_device), self.inode_id))
+                .unwrap();
+            if open_inode.count > 1 {
+                open_inode.unlinked = true;
+                return;
+            }
+        }
+        self.free(fs);
+    }
+    /// Run an operation with the filesystem locked, which fails with [`FsError::Io`]
+    /// instead if the block device fails meanwhile, since it may have used a block never read
+    fn with_fs<T>(
+        &self,
+        f: impl FnOnce(&mut MutexGuard<EasyFileSystem>) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let mut fs = self.try_lock_fs()?;
+        let result = f(&mut fs);
+        if take_io_error(&self.block_device) {
+            return Err(FsError::Io);
+        }
+        result
+    }
     /// Call a function over a disk inode to read it
     fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
         get_block_cache(
@@ -50,116 +194,331 @@ impl Inode {
             Arc::clone(&self.block_device)
         ).lock().modify(self.block_offset, f)
     }
-    /// Find inode under a disk inode by name
-    fn find_inode_id(
+    /// Create a vfs inode by id
+    fn get_inode(
+        &self,
+        inode_id: u32,
+        fs: &MutexGuard<EasyFileSystem>,
+    ) -> Result<Arc<Inode>, FsError> {
+        if inode_id as usize >= fs.inode_bitmap.maximum() {
+            return Err(FsError::Corrupted);
+        }
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Ok(Arc::new(Self::new(
+            inode_id,
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+        )))
+    }
+    /// Find the position and inode id of a dirent under a disk inode by name
+    fn find_dirent(
         &self,
         name: &str,
         disk_inode: &DiskInode,
-    ) -> Option<u32> {
-        // assert it is a directory
-        assert!(disk_inode.is_dir());
+    ) -> Result<(usize, u32), FsError> {
+        if !disk_inode.is_dir() {
+            return Err(FsError::NotDir);
+        }
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+        // a size which is not a whole number of dirents is corrupted
+        if file_count * DIRENT_SZ != disk_inode.size as usize {
+            return Err(FsError::Corrupted);
+        }
         let mut dirent = DirEntry::empty();
         for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(
-                    DIRENT_SZ * i,
-                    dirent.as_bytes_mut(),
-                    &self.block_device,
-                ),
-                DIRENT_SZ,
-            );
+            if disk_inode.read_at(
+                DIRENT_SZ * i,
+                dirent.as_bytes_mut(),
+                &self.block_device,
+            ) != DIRENT_SZ {
+                return Err(FsError::Corrupted);
+            }
             if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+                return Ok((i, dirent.inode_number()));
             }
         }
-        None
+        Err(FsError::NotFound)
+    }
+    /// Find inode under a disk inode by name
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+    ) -> Result<u32, FsError> {
+        self.find_dirent(name, disk_inode).map(|(_, inode_id)| inode_id)
+    }
+    /// Check that a name is not in use under a disk inode,
+    /// which must not be a removed directory
+    fn check_absent(&self, name: &str, disk_inode: &DiskInode) -> Result<(), FsError> {
+        if disk_inode.is_dir() && disk_inode.nlink == 0 {
+            return Err(FsError::NotFound);
+        }
+        match self.find_inode_id(name, disk_inode) {
+            Ok(_) => Err(FsError::Exists),
+            Err(FsError::NotFound) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+    /// Get the inode number of current inode
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+    /// Get the number of hard links to current inode
+    pub fn nlink(&self) -> u32 {
+        let _fs = self.lock_fs();
+        self.read_disk_inode(|disk_inode| disk_inode.nlink)
+    }
+    /// Get the size of current inode in bytes
+    pub fn size(&self) -> usize {
+        let _fs = self.lock_fs();
+        self.read_disk_inode(|disk_inode| disk_inode.size as usize)
+    }
+    /// Whether current inode is a directory
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.lock_fs();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
     }
     /// Find inode under current inode by name
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
-            .map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
+    pub fn find(&self, name: &str) -> Result<Arc<Inode>, FsError> {
+        self.with_fs(|fs| {
+            let inode_id =
+                self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode))?;
+            self.get_inode(inode_id, fs)
         })
     }
-    /// Increase the size of a disk inode
+    /// Find inode by a path relative to current inode,
+    /// components are separated by '/' and may be "." or ".."
+    pub fn find_path(self: &Arc<Self>, path: &str) -> Result<Arc<Inode>, FsError> {
+        let mut inode = Arc::clone(self);
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = inode.find(name)?;
+        }
+        Ok(inode)
+    }
+    /// Increase the size of a disk inode,
+    /// leave it untouched if the disk is full
     fn increase_size(
         &self,
         new_size: u32,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
+    ) -> Result<(), FsError> {
         if new_size < disk_inode.size {
-            return;
+            return Ok(());
         }
         let blocks_needed = disk_inode.blocks_num_needed(new_size);
         let mut v: Vec<u32> = Vec::new();
         for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+            match fs.alloc_data() {
+                Ok(block_id) => v.push(block_id),
+                Err(err) => {
+                    // give back what has been allocated
+                    for block_id in v.into_iter() {
+                        fs.dealloc_data(block_id);
+                    }
+                    return Err(err);
+                }
+            }
         }
         disk_inode.increase_size(new_size, v, &self.block_device);
+        Ok(())
     }
-    /// Create inode under current inode by name
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        if self.modify_disk_inode(|root_inode| {
-            // assert it is a directory
-            assert!(root_inode.is_dir());
-            // has the file been created?
-            self.find_inode_id(name, root_inode)
-        }).is_some() {
-            return None;
-        }
-        // create a new file
-        // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
-        // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) 
-            = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(
-            new_inode_block_id as usize,
-            Arc::clone(&self.block_device)
-        ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-            new_inode.initialize(DiskInodeType::File);
-        });
+    /// Write the "." and ".." dirents of a new directory
+    pub(crate) fn init_dir_entries(
+        &self,
+        parent_inode_id: u32,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<(), FsError> {
+        self.modify_disk_inode(|disk_inode| {
+            self.increase_size(2 * DIRENT_SZ as u32, disk_inode, fs)?;
+            disk_inode.write_at(
+                0,
+                DirEntry::new(".", self.inode_id).as_bytes(),
+                &self.block_device,
+            );
+            disk_inode.write_at(
+                DIRENT_SZ,
+                DirEntry::new("..", parent_inode_id).as_bytes(),
+                &self.block_device,
+            );
+            Ok(())
+        })
+    }
+    /// Check that a name can be used for a new dirent
+    fn check_name(name: &str) -> Result<(), FsError> {
+        if name.len() > NAME_LENGTH_LIMIT {
+            Err(FsError::NameTooLong)
+        } else if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            Err(FsError::InvalidName)
+        } else {
+            Ok(())
+        }
+    }
+    /// Append a dirent under current inode
+    fn add_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<(), FsError> {
         self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
             let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
             // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
+            let new_size = (file_count + 1) * DIRENT_SZ;
+            self.increase_size(new_size as u32, root_inode, fs)?;
             // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
+            let dirent = DirEntry::new(name, inode_id);
             root_inode.write_at(
                 file_count * DIRENT_SZ,
                 dirent.as_bytes(),
                 &self.block_device,
             );
+            Ok(())
+        })
+    }
+    /// Remove the dirent at the given position under current inode,
+    /// moving the last dirent into its place and shrinking the directory
+    fn remove_dirent(&self, pos: usize, fs: &mut MutexGuard<EasyFileSystem>) {
+        self.modify_disk_inode(|disk_inode| {
+            let last = disk_inode.size as usize / DIRENT_SZ - 1;
+            if pos != last {
+                let mut dirent = DirEntry::empty();
+                disk_inode.read_at(last * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                disk_inode.write_at(pos * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+            }
+            let data_blocks_dealloc =
+                disk_inode.decrease_size((last * DIRENT_SZ) as u32, &self.block_device);
+            for data_block in data_blocks_dealloc.into_iter() {
+                fs.dealloc_data(data_block);
+            }
         });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
-        // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
-        // release efs lock automatically by compiler
+    }
+    /// Create inode of given type under current inode by name
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Result<Arc<Inode>, FsError> {
+        Self::check_name(name)?;
+        self.with_fs(|fs| {
+            // it must be a directory without the name in it
+            self.read_disk_inode(|root_inode| self.check_absent(name, root_inode))?;
+            // create a new file
+            // alloc a inode with an indirect block
+            let new_inode_id = fs.alloc_inode()?;
+            // initialize inode
+            let (new_inode_block_id, new_inode_block_offset) 
+                = fs.get_disk_inode_pos(new_inode_id);
+            let is_dir = type_ == DiskInodeType::Directory;
+            get_block_cache(
+                new_inode_block_id as usize,
+                Arc::clone(&self.block_device)
+            ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+                new_inode.initialize(type_);
+            });
+            let new_inode = self.get_inode(new_inode_id, fs)?;
+            if is_dir {
+                if let Err(err) = new_inode.init_dir_entries(self.inode_id, fs) {
+                    fs.dealloc_inode(new_inode_id);
+                    return Err(err);
+                }
+            }
+            if let Err(err) = self.add_dirent(name, new_inode_id, fs) {
+                new_inode.free(fs);
+                return Err(err);
+            }
+            if is_dir {
+                // ".." of the new directory links to current inode
+                self.modify_disk_inode(|disk_inode| disk_inode.nlink += 1);
+            }
+            block_cache_sync_all();
+            // return inode
+            Ok(new_inode)
+            // release efs lock automatically by compiler
+        })
+    }
+    /// Create a file under current inode by name
+    pub fn create(&self, name: &str) -> Result<Arc<Inode>, FsError> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+    /// Create a directory under current inode by name
+    pub fn mkdir(&self, name: &str) -> Result<Arc<Inode>, FsError> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+    /// Create a hard link to a file under current inode by name
+    pub fn link(&self, name: &str, target: &Inode) -> Result<(), FsError> {
+        Self::check_name(name)?;
+        self.with_fs(|fs| {
+            self.read_disk_inode(|root_inode| self.check_absent(name, root_inode))?;
+            // directories can not be linked
+            if target.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+                return Err(FsError::IsDir);
+            }
+            self.add_dirent(name, target.inode_id, fs)?;
+            target.modify_disk_inode(|disk_inode| disk_inode.nlink += 1);
+            block_cache_sync_all();
+            Ok(())
+        })
+    }
+    /// Remove a link to a file under current inode by name,
+    /// the file is freed once no link to it is left and no vfs inode of it is alive
+    pub fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.with_fs(|fs| {
+            let (dirent_pos, inode_id) =
+                self.read_disk_inode(|disk_inode| self.find_dirent(name, disk_inode))?;
+            let inode = self.get_inode(inode_id, fs)?;
+            // directories are removed by rmdir
+            if inode.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+                return Err(FsError::IsDir);
+            }
+            self.remove_dirent(dirent_pos, fs);
+            let nlink = inode.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink = disk_inode.nlink.saturating_sub(1);
+                disk_inode.nlink
+            });
+            if nlink == 0 {
+                inode.free_unlinked_inode(fs);
+            }
+            block_cache_sync_all();
+            Ok(())
+        })
+    }
+    /// Remove an empty directory under current inode by name,
+    /// which is freed once no vfs inode of it is alive
+    pub fn rmdir(&self, name: &str) -> Result<(), FsError> {
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidName);
+        }
+        self.with_fs(|fs| {
+            let (dirent_pos, inode_id) =
+                self.read_disk_inode(|disk_inode| self.find_dirent(name, disk_inode))?;
+            let dir = self.get_inode(inode_id, fs)?;
+            // only "." and ".." may be left in it
+            dir.read_disk_inode(|disk_inode| {
+                if !disk_inode.is_dir() {
+                    return Err(FsError::NotDir);
+                }
+                let is_empty = (0..disk_inode.size as usize / DIRENT_SZ).all(|i| {
+                    let mut dirent = DirEntry::empty();
+                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                    matches!(dirent.name(), "." | "..")
+                });
+                if is_empty { Ok(()) } else { Err(FsError::NotEmpty) }
+            })?;
+            self.remove_dirent(dirent_pos, fs);
+            // ".." of the removed directory linked to current inode
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink = disk_inode.nlink.saturating_sub(1)
+            });
+            // nothing can be found or created in it from the vfs inodes still alive
+            dir.clear_data(fs);
+            dir.modify_disk_inode(|disk_inode| disk_inode.nlink = 0);
+            dir.free_unlinked_inode(fs);
+            block_cache_sync_all();
+            Ok(())
+        })
     }
     /// List inodes under current inode
     pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
+        let _fs = self.lock_fs();
         self.read_disk_inode(|disk_inode| {
             let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             let mut v: Vec<String> = Vec::new();
@@ -179,25 +538,40 @@ impl Inode {
         })
     }
     /// Read data from current inode
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            disk_inode.read_at(offset, buf, &self.block_device)
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.with_fs(|_| {
+            Ok(self.read_disk_inode(|disk_inode| {
+                disk_inode.read_at(offset, buf, &self.block_device)
+            }))
         })
     }
-    /// Write data to current inode
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
-        });
-        block_cache_sync_all();
-        size
+    /// Write data to current inode, nothing is written if the disk is full
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        self.with_fs(|fs| {
+            let size = self.modify_disk_inode(|disk_inode| {
+                self.increase_size((offset + buf.len()) as u32, disk_inode, fs)?;
+                Ok(disk_inode.write_at(offset, buf, &self.block_device))
+            });
+            block_cache_sync_all();
+            size
+        })
     }
-    /// Clear the data in current inode
-    pub fn clear(&self) {
-        let mut fs = self.fs.lock();
+    /// Grow current inode to `size` bytes, and return the blocks holding its data in order,
+    /// which its owner may then read and write on the block device directly
+    pub fn reserve_blocks(&self, size: usize) -> Result<Vec<u32>, FsError> {
+        self.with_fs(|fs| {
+            let blocks = self.modify_disk_inode(|disk_inode| {
+                self.increase_size(size as u32, disk_inode, fs)?;
+                Ok((0..disk_inode.data_blocks())
+                    .map(|inner_id| disk_inode.get_block_id(inner_id, &self.block_device))
+                    .collect())
+            });
+            block_cache_sync_all();
+            blocks
+        })
+    }
+    /// Free the data blocks of current inode
+    fn clear_data(&self, fs: &mut MutexGuard<EasyFileSystem>) {
         self.modify_disk_inode(|disk_inode| {
             let size = disk_inode.size;
             let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
@@ -206,6 +580,30 @@ impl Inode {
                 fs.dealloc_data(data_block);
             }
         });
+    }
+    /// Free the data blocks of current inode and the inode itself
+    fn free(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        self.clear_data(fs);
+        fs.dealloc_inode(self.inode_id);
+    }
+    /// Clear the data in current inode
+    pub fn clear(&self) {
+        let mut fs = self.lock_fs();
+        self.clear_data(&mut fs);
         block_cache_sync_all();
     }
 }
+
+impl Drop for Inode {
+    /// Leave an unlinked inode to be freed at the next lock of the filesystem,
+    /// which may not be taken here
+    fn drop(&mut self) {
+        let key = (device_id(&self.block_device), self.inode_id);
+        let mut open_inodes = OPEN_INODES.lock();
+        let open_inode = open_inodes.get_mut(&key).unwrap();
+        open_inode.count -= 1;
+        if open_inode.count == 0 && !open_inode.unlinked {
+            open_inodes.remove(&key);
+        }
+    }
+}
@@ -1,14 +1,42 @@
 //! Constants used in rCore
 
 pub const USER_STACK_SIZE: usize = 4096 * 2;
+/// The room for the heap after the elf, which the user stacks follow
+pub const MAX_USER_HEAP_SIZE: usize = 0x400_0000;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
 pub const MEMORY_END: usize = 0x88000000;
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const MAX_SYSCALL_NUM: usize = 500;
+/// The harts brought up at most, whose ids must be below it, as entry.asm sizes the boot stacks
+pub const MAX_HARTS: usize = 8;
+pub const BIG_STRIDE: usize = 0x10_0000;
+pub const DEFAULT_PRIORITY: usize = 16;
+pub const DEFAULT_TIME_SLICE: usize = 1;
+pub const MLFQ_LEVELS: usize = 3;
+pub const MLFQ_BOOST_INTERVAL: usize = 100;
+/// The size of the swap file, which is reserved on the disk at boot
+pub const SWAP_SIZE: usize = 0x20_0000;
 
+/// The end of the lower half of Sv39, where the mappings of user programs are made
+pub const USER_SPACE_END: usize = 0x40_0000_0000;
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
+/// Shared memory is attached in `[SHM_START, SHM_END)` unless the caller chooses where
+pub const SHM_START: usize = 0x20_0000_0000;
+pub const SHM_END: usize = 0x40_0000_0000;
 pub const CLOCK_FREQ: usize = 12500000;
-pub const MMIO: &[(usize, usize)] = &[(0x10001000, 0x1000)];
+pub const MMIO: &[(usize, usize)] = &[
+    (0x0C00_0000, 0x40_0000), // PLIC
+    (0x1000_0000, 0x1000),    // UART
+    (0x1000_1000, 0x1000),    // VIRTIO0
+];
+/// The platform-level interrupt controller of QEMU virt
+pub const VIRT_PLIC: usize = 0x0C00_0000;
+/// The interrupt source of the block device on the first virtio-mmio slot
+pub const VIRTIO0_IRQ: u32 = 1;
+/// The NS16550A UART of QEMU virt
+pub const VIRT_UART: usize = 0x1000_0000;
+/// The interrupt source of the UART, raised when it has received some input
+pub const UART_IRQ: u32 = 10;
@@ -1,21 +1,25 @@
-//! SBI console driver, for text output
+//! Console output through the UART
 
-use crate::sbi::console_putchar;
+use crate::drivers::UART;
+use crate::sync::SpinLock;
 use core::fmt::{self, Write};
 
 struct Stdout;
 
+/// Keep the lines printed by different harts apart
+static STDOUT: SpinLock<Stdout> = SpinLock::new(Stdout);
+
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            console_putchar(c as usize);
+        for byte in s.bytes() {
+            UART.putchar(byte);
         }
         Ok(())
     }
 }
 
 pub fn print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+    STDOUT.exclusive_access().write_fmt(args).unwrap();
 }
 
 #[macro_export]
@@ -57,7 +61,8 @@ pub fn print_colorized(
     foreground_color: impl Into<u8>,
     background_color: impl Into<u8>,
 ) {
-    Stdout
+    STDOUT
+        .exclusive_access()
         .write_fmt(colorize!(args, foreground_color, background_color))
         .unwrap();
 }
@@ -6,7 +6,7 @@ use easy_fs::BlockDevice;
 type BlockDeviceImpl = virtio_blk::VirtIOBlock;
 
 lazy_static! {
-    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(BlockDeviceImpl::new());
+    pub static ref BLOCK_DEVICE: Arc<BlockDeviceImpl> = Arc::new(BlockDeviceImpl::new());
 }
 
 #[allow(unused)]
@@ -16,8 +16,8 @@ pub fn block_device_test() {
     let mut read_buffer = [0u8; 512];
     for i in 0..512 {
         for byte in write_buffer.iter_mut() { *byte = i as u8; }
-        block_device.write_block(i as usize, &write_buffer);
-        block_device.read_block(i as usize, &mut read_buffer);
+        block_device.write_block(i as usize, &write_buffer).unwrap();
+        block_device.read_block(i as usize, &mut read_buffer).unwrap();
         assert_eq!(write_buffer, read_buffer);
     }
     println!("block device test passed!");
@@ -1,42 +1,83 @@
 
-use virtio_drivers::{VirtIOBlk, VirtIOHeader};
+use virtio_drivers::{BlkResp, RespStatus, VirtIOBlk, VirtIOHeader};
 use crate::mm::{
     PhysAddr,
     VirtAddr,
-    frame_alloc,
-    frame_dealloc,
+    frame_alloc_contiguous,
     PhysPageNum,
-    FrameTracker,
+    ContiguousFrameTracker,
     PageTable,
-    StepByOne,
     kernel_token,
 };
 use super::BlockDevice;
-use crate::sync::UPSafeCell;
+use crate::sync::{holds_spinlock, SpinLock};
+use crate::task::{block_current_and_run_next, current_task, wakeup_task, TaskControlBlock};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::hint::spin_loop;
+use easy_fs::FsError;
 use lazy_static::*;
 
 #[allow(unused)]
 const VIRTIO0: usize = 0x10001000;
 
-pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static>>);
+pub struct VirtIOBlock {
+    inner: SpinLock<VirtIOBlockInner>,
+    /// The tasks sleeping until the filesystem on the disk is released
+    fs_waiters: SpinLock<Vec<Arc<TaskControlBlock>>>,
+}
+
+struct VirtIOBlockInner {
+    blk: VirtIOBlk<'static>,
+    /// The requests in flight by token, with the task sleeping on each of them
+    waiters: BTreeMap<u16, Option<Arc<TaskControlBlock>>>,
+}
 
 lazy_static! {
-    static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameTracker>> = unsafe { 
-        UPSafeCell::new(Vec::new())
-    };
+    static ref QUEUE_FRAMES: SpinLock<Vec<ContiguousFrameTracker>> = SpinLock::new(Vec::new());
+}
+
+/// Whether the caller may sleep until its request completes,
+/// otherwise it polls the device, such as at boot or under a lock
+fn may_sleep() -> bool {
+    !holds_spinlock() && current_task().is_some()
 }
 
 impl BlockDevice for VirtIOBlock {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        self.0.exclusive_access()
-        .read_block(block_id, buf)
-        .expect("Error when reading VirtIOBlk");
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut resp = BlkResp::default();
+        let submitted = self.wait_for(|blk| unsafe { blk.read_block_nb(block_id, buf, &mut resp) });
+        check(submitted, &resp, "reading", block_id)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), FsError> {
+        let mut resp = BlkResp::default();
+        let submitted =
+            self.wait_for(|blk| unsafe { blk.write_block_nb(block_id, buf, &mut resp) });
+        check(submitted, &resp, "writing", block_id)
     }
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        self.0.exclusive_access()
-        .write_block(block_id, buf)
-        .expect("Error when writing VirtIOBlk");
+    fn relax(&self, held: &dyn Fn() -> bool) -> bool {
+        // the filesystem may be held by a task sleeping on the disk,
+        // which the caller would wait for forever if it could not switch out
+        if !may_sleep() {
+            return false;
+        }
+        let task = current_task().unwrap();
+        self.fs_waiters.exclusive_access().push(task.clone());
+        // a release after the check wakes the task up before it sleeps
+        if held() {
+            block_current_and_run_next();
+        }
+        self.fs_waiters
+            .exclusive_access()
+            .retain(|waiter| !Arc::ptr_eq(waiter, &task));
+        true
+    }
+    fn notify(&self) {
+        let waiters = core::mem::take(&mut *self.fs_waiters.exclusive_access());
+        for task in waiters {
+            wakeup_task(task);
+        }
     }
 }
 
@@ -44,33 +85,104 @@ impl VirtIOBlock {
     #[allow(unused)]
     pub fn new() -> Self {
         unsafe {
-            Self(UPSafeCell::new(VirtIOBlk::new(
-                &mut *(VIRTIO0 as *mut VirtIOHeader)
-            ).unwrap()))
+            Self {
+                inner: SpinLock::new(VirtIOBlockInner {
+                    blk: VirtIOBlk::new(&mut *(VIRTIO0 as *mut VirtIOHeader)).unwrap(),
+                    waiters: BTreeMap::new(),
+                }),
+                fs_waiters: SpinLock::new(Vec::new()),
+            }
+        }
+    }
+    /// Submit a request and wait until the device completes it,
+    /// or fail if it cannot be submitted
+    fn wait_for(
+        &self,
+        submit: impl FnOnce(&mut VirtIOBlk<'static>) -> virtio_drivers::Result<u16>,
+    ) -> virtio_drivers::Result {
+        let sleep = may_sleep();
+        let mut inner = self.inner.exclusive_access();
+        let token = submit(&mut inner.blk)?;
+        if sleep {
+            // the completion interrupt wakes the task up, maybe on another hart,
+            // and a signal may wake it up before that
+            inner.waiters.insert(token, current_task());
+            while inner.waiters.contains_key(&token) {
+                drop(inner);
+                block_current_and_run_next();
+                inner = self.inner.exclusive_access();
+            }
+        } else {
+            inner.waiters.insert(token, None);
+            while inner.waiters.contains_key(&token) {
+                spin_loop();
+                inner.retire_used();
+            }
+        }
+        Ok(())
+    }
+    /// Serve the completion interrupt of the device
+    pub fn handle_irq(&self) {
+        let mut inner = self.inner.exclusive_access();
+        inner.blk.ack_interrupt();
+        inner.retire_used();
+    }
+}
+
+/// Turn a request which could not be submitted or which the device failed into an error,
+/// which is logged here since the filesystem only tells that some I/O failed
+fn check(
+    submitted: virtio_drivers::Result,
+    resp: &BlkResp,
+    op: &str,
+    block_id: usize,
+) -> Result<(), FsError> {
+    match submitted.map(|_| resp.status()) {
+        Ok(RespStatus::Ok) => Ok(()),
+        status => {
+            println!(
+                "[kernel] Error {:?} when {} block {} of VirtIOBlk",
+                status, op, block_id
+            );
+            Err(FsError::Io)
+        }
+    }
+}
+
+impl VirtIOBlockInner {
+    /// Forget the requests the device has completed and wake up the tasks sleeping on them
+    fn retire_used(&mut self) {
+        while let Ok(token) = self.blk.pop_used() {
+            if let Some(Some(task)) = self.waiters.remove(&token) {
+                wakeup_task(task);
+            }
         }
     }
 }
 
 #[no_mangle]
 pub extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
-    let mut ppn_base = PhysPageNum(0);
-    for i in 0..pages {
-        let frame = frame_alloc().unwrap();
-        if i == 0 { ppn_base = frame.ppn; }
-        assert_eq!(frame.ppn.0, ppn_base.0 + i);
-        QUEUE_FRAMES.exclusive_access().push(frame);
-    }
+    let frames = frame_alloc_contiguous(pages, 1).expect("No frames for virtio DMA!");
+    let ppn_base = frames.ppn;
+    QUEUE_FRAMES.exclusive_access().push(frames);
     ppn_base.into()
 }
 
 #[no_mangle]
 pub extern "C" fn virtio_dma_dealloc(pa: PhysAddr, pages: usize) -> i32 {
-    let mut ppn_base: PhysPageNum = pa.into();
-    for _ in 0..pages {
-        frame_dealloc(ppn_base);
-        ppn_base.step();
+    let ppn_base: PhysPageNum = pa.into();
+    let mut queue_frames = QUEUE_FRAMES.exclusive_access();
+    // the frames are freed when their tracker is dropped
+    match queue_frames
+        .iter()
+        .position(|frames| frames.ppn == ppn_base && frames.pages == pages)
+    {
+        Some(idx) => {
+            queue_frames.remove(idx);
+            0
+        }
+        None => -1,
     }
-    0
 }
 
 #[no_mangle]
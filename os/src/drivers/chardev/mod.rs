@@ -0,0 +1,7 @@
+mod ns16550a;
+
+use crate::config::VIRT_UART;
+type CharDeviceImpl = ns16550a::NS16550a;
+
+/// The serial port of the console, which works before the heap as well
+pub static UART: CharDeviceImpl = CharDeviceImpl::new(VIRT_UART);
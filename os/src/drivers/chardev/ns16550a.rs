@@ -0,0 +1,158 @@
+//! The NS16550A UART of QEMU virt
+
+use crate::sync::SpinLock;
+use crate::syscall::errno::{Errno, SysResult};
+use crate::task::{
+    block_current_and_run_next, current_signal_pending, current_task, wakeup_task, TaskControlBlock,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+/// Receive buffer, or transmit holding register on write
+const RBR_THR: usize = 0;
+/// Interrupt enable register
+const IER: usize = 1;
+/// FIFO control register
+const FCR: usize = 2;
+/// Line control register
+const LCR: usize = 3;
+/// Modem control register
+const MCR: usize = 4;
+/// Line status register
+const LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const FCR_ENABLE_AND_CLEAR: u8 = 0b111;
+/// 8 data bits, no parity and 1 stop bit
+const LCR_8N1: u8 = 0b11;
+/// DTR, RTS and OUT2, which routes the interrupt out of the chip
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// The bytes received and not read yet
+const RX_BUFFER_SIZE: usize = 512;
+
+/// The input of a UART kept until it is read, which drops the bytes
+/// arriving while it is full
+struct RingBuffer {
+    buf: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+    fn push(&mut self, byte: u8) {
+        if self.len < RX_BUFFER_SIZE {
+            self.buf[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+    /// Move the oldest bytes into `buf`, return how many were moved
+    fn pop_into(&mut self, buf: &mut [u8]) -> usize {
+        let read_size = self.len.min(buf.len());
+        for byte in buf[..read_size].iter_mut() {
+            *byte = self.buf[self.head];
+            self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        }
+        self.len -= read_size;
+        read_size
+    }
+}
+
+/// A UART whose input is kept by its interrupt until the tasks sleeping on it read it,
+/// and whose output is sent by polling
+pub struct NS16550a {
+    base_addr: usize,
+    inner: SpinLock<NS16550aInner>,
+}
+
+struct NS16550aInner {
+    rx_buffer: RingBuffer,
+    /// The tasks sleeping until some input arrives
+    readers: Vec<Arc<TaskControlBlock>>,
+}
+
+impl NS16550a {
+    pub const fn new(base_addr: usize) -> Self {
+        Self {
+            base_addr,
+            inner: SpinLock::new(NS16550aInner {
+                rx_buffer: RingBuffer::new(),
+                readers: Vec::new(),
+            }),
+        }
+    }
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { read_volatile((self.base_addr + reg) as *const u8) }
+    }
+    fn write_reg(&self, reg: usize, value: u8) {
+        unsafe {
+            write_volatile((self.base_addr + reg) as *mut u8, value);
+        }
+    }
+    /// Set the line up and interrupt on the input,
+    /// keeping the baud rate the firmware has chosen
+    pub fn init(&self) {
+        self.write_reg(IER, 0);
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(FCR, FCR_ENABLE_AND_CLEAR);
+        self.write_reg(MCR, MCR_DTR_RTS_OUT2);
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+    /// Send a byte once the transmitter has room for it
+    pub fn putchar(&self, byte: u8) {
+        while self.read_reg(LSR) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_reg(RBR_THR, byte);
+    }
+    /// Sleep until some input is buffered, then read as much of it as `buf` holds,
+    /// or fail with EINTR once the process has a signal to handle
+    pub fn read(&self, buf: &mut [u8]) -> SysResult {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let read_size = self.read_buffered(buf);
+            if read_size > 0 {
+                return Ok(read_size);
+            }
+            // the process is locked to check it, which is never done under the UART lock
+            if current_signal_pending() {
+                return Err(Errno::EINTR);
+            }
+            let mut inner = self.inner.exclusive_access();
+            if inner.rx_buffer.len > 0 {
+                continue;
+            }
+            // another reader may take the input first, so check again once woken up
+            inner.readers.push(current_task().unwrap());
+            drop(inner);
+            block_current_and_run_next();
+        }
+    }
+    /// Read as much of the buffered input as `buf` holds without waiting
+    pub fn read_buffered(&self, buf: &mut [u8]) -> usize {
+        self.inner.exclusive_access().rx_buffer.pop_into(buf)
+    }
+    /// Move the received bytes to the buffer and wake up the readers
+    pub fn handle_irq(&self) {
+        let mut inner = self.inner.exclusive_access();
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR_THR);
+            inner.rx_buffer.push(byte);
+        }
+        for task in inner.readers.drain(..) {
+            wakeup_task(task);
+        }
+    }
+}
@@ -1,3 +1,41 @@
 mod block;
+mod chardev;
+mod plic;
 
 pub use block::BLOCK_DEVICE;
+pub use chardev::UART;
+
+use crate::config::{UART_IRQ, VIRTIO0_IRQ, VIRT_PLIC};
+use crate::task::hart_id;
+use plic::Plic;
+
+static PLIC: Plic = Plic::new(VIRT_PLIC);
+
+/// Set the devices up, once on the boot hart
+pub fn init() {
+    UART.init();
+}
+
+/// Route the interrupts of the devices to this hart
+pub fn init_irq() {
+    let hart_id = hart_id();
+    for source in [VIRTIO0_IRQ, UART_IRQ] {
+        PLIC.set_priority(source, 1);
+        PLIC.enable(hart_id, source);
+    }
+    PLIC.set_threshold(hart_id, 0);
+}
+
+/// Serve the interrupts of the devices claimed by this hart
+pub fn handle_irq() {
+    let hart_id = hart_id();
+    while let Some(source) = PLIC.claim(hart_id) {
+        match source {
+            VIRTIO0_IRQ => BLOCK_DEVICE.handle_irq(),
+            UART_IRQ => UART.handle_irq(),
+            // a source which is never enabled cannot be served, only completed
+            _ => println!("[kernel] Unsupported external interrupt {}!", source),
+        }
+        PLIC.complete(hart_id, source);
+    }
+}
@@ -0,0 +1,66 @@
+//! The platform-level interrupt controller, which routes the interrupts of
+//! the devices to the harts
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// Registers of the PLIC on QEMU virt, where every hart has a context
+/// for its M mode and one for its S mode
+pub struct Plic {
+    base_addr: usize,
+}
+
+impl Plic {
+    pub const fn new(base_addr: usize) -> Self {
+        Self { base_addr }
+    }
+    /// The S-mode context of a hart
+    fn context(hart_id: usize) -> usize {
+        hart_id * 2 + 1
+    }
+    fn priority_ptr(&self, source: u32) -> *mut u32 {
+        (self.base_addr + source as usize * 4) as *mut u32
+    }
+    fn enable_ptr(&self, hart_id: usize, source: u32) -> *mut u32 {
+        (self.base_addr + 0x2000 + Self::context(hart_id) * 0x80 + source as usize / 32 * 4)
+            as *mut u32
+    }
+    fn threshold_ptr(&self, hart_id: usize) -> *mut u32 {
+        (self.base_addr + 0x20_0000 + Self::context(hart_id) * 0x1000) as *mut u32
+    }
+    fn claim_ptr(&self, hart_id: usize) -> *mut u32 {
+        (self.base_addr + 0x20_0004 + Self::context(hart_id) * 0x1000) as *mut u32
+    }
+    /// Set the priority of a source, where 0 never interrupts
+    pub fn set_priority(&self, source: u32, priority: u32) {
+        unsafe {
+            write_volatile(self.priority_ptr(source), priority);
+        }
+    }
+    /// Let a source interrupt the S mode of a hart
+    pub fn enable(&self, hart_id: usize, source: u32) {
+        let ptr = self.enable_ptr(hart_id, source);
+        unsafe {
+            write_volatile(ptr, read_volatile(ptr) | 1 << (source % 32));
+        }
+    }
+    /// Mask the sources of a priority up to `threshold` on the S mode of a hart
+    pub fn set_threshold(&self, hart_id: usize, threshold: u32) {
+        unsafe {
+            write_volatile(self.threshold_ptr(hart_id), threshold);
+        }
+    }
+    /// Take the pending source of the highest priority for a hart,
+    /// which no other hart claims until it is completed
+    pub fn claim(&self, hart_id: usize) -> Option<u32> {
+        match unsafe { read_volatile(self.claim_ptr(hart_id)) } {
+            0 => None,
+            source => Some(source),
+        }
+    }
+    /// Tell the PLIC that a hart has served a source it claimed
+    pub fn complete(&self, hart_id: usize, source: u32) {
+        unsafe {
+            write_volatile(self.claim_ptr(hart_id), source);
+        }
+    }
+}
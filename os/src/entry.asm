@@ -1,12 +1,28 @@
     .section .text.entry
     .globl _start
 _start:
+    # a0 = hart id, a1 = device tree, and the hart id stays in tp
+    mv tp, a0
+    # every hart has a boot stack of 4096 * 16 bytes below boot_stack_top by its id
     la sp, boot_stack_top
+    slli t0, a0, 16
+    sub sp, sp, t0
     call rust_main
 
+    .section .text
+    .globl _start_secondary
+_start_secondary:
+    # the other harts start here through SBI HSM, with the same a0 and tp
+    mv tp, a0
+    la sp, boot_stack_top
+    slli t0, a0, 16
+    sub sp, sp, t0
+    call rust_main_secondary
+
     .section .bss.stack
     .globl boot_stack
 boot_stack:
-    .space 4096 * 16
+    # 8 harts as MAX_HARTS tells
+    .space 4096 * 16 * 8
     .globl boot_stack_top
-boot_stack_top:
\ No newline at end of file
+boot_stack_top:
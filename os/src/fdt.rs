@@ -0,0 +1,73 @@
+//! Minimal flattened device tree parsing
+//!
+//! Only what is needed to read the kernel command line, which the SBI passes
+//! to the kernel inside the device tree as `/chosen/bootargs`.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// read a big-endian u32 at a physical address
+fn read_be32(pa: usize) -> u32 {
+    u32::from_be(unsafe { (pa as *const u32).read_volatile() })
+}
+
+/// read a null-terminated string at a physical address
+fn read_str(pa: usize) -> &'static str {
+    let len = (0usize..)
+        .find(|i| unsafe { ((pa + *i) as *const u8).read_volatile() } == 0)
+        .unwrap();
+    core::str::from_utf8(unsafe { core::slice::from_raw_parts(pa as *const u8, len) })
+        .unwrap_or("")
+}
+
+fn align4(pa: usize) -> usize {
+    (pa + 3) & !3
+}
+
+/// Get `/chosen/bootargs` from the device tree at `dtb_pa`, if any.
+///
+/// The device tree lies in memory later handed out by the frame allocator,
+/// so this must be called, and the result consumed, before `mm::init`.
+pub fn bootargs(dtb_pa: usize) -> Option<&'static str> {
+    if dtb_pa == 0 || dtb_pa % 4 != 0 || read_be32(dtb_pa) != FDT_MAGIC {
+        return None;
+    }
+    let strings_base = dtb_pa + read_be32(dtb_pa + 12) as usize;
+    let mut p = dtb_pa + read_be32(dtb_pa + 8) as usize;
+    let mut depth = 0usize;
+    let mut in_chosen = false;
+    loop {
+        let token = read_be32(p);
+        p += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = read_str(p);
+                p = align4(p + name.len() + 1);
+                depth += 1;
+                // the root node is at depth 1
+                in_chosen = depth == 2 && name == "chosen";
+            }
+            FDT_END_NODE => {
+                if in_chosen && depth == 2 {
+                    return None;
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = read_be32(p) as usize;
+                let name = read_str(strings_base + read_be32(p + 4) as usize);
+                p += 8;
+                if in_chosen && name == "bootargs" {
+                    return Some(read_str(p));
+                }
+                p = align4(p + len);
+            }
+            FDT_NOP => {}
+            // FDT_END or a corrupted tree
+            _ => return None,
+        }
+    }
+}
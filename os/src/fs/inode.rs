@@ -1,12 +1,15 @@
 use easy_fs::{
     EasyFileSystem,
+    FsError,
     Inode,
 };
 use crate::drivers::BLOCK_DEVICE;
-use crate::sync::UPSafeCell;
+use crate::sync::SpinLock;
+use crate::syscall::errno::SysResult;
 use alloc::sync::Arc;
 use lazy_static::*;
 use bitflags::*;
+use alloc::string::String;
 use alloc::vec::Vec;
 use super::File;
 use crate::mm::UserBuffer;
@@ -16,10 +19,10 @@ use crate::mm::UserBuffer;
 pub struct OSInode {
     readable: bool,
     writable: bool,
-    inner: UPSafeCell<OSInodeInner>,
+    inner: SpinLock<OSInodeInner>,
 }
 
-/// The OS inode inner in 'UPSafeCell'
+/// The OS inode inner in 'SpinLock'
 pub struct OSInodeInner {
     offset: usize,
     inode: Arc<Inode>,
@@ -35,33 +38,33 @@ impl OSInode {
         Self {
             readable,
             writable,
-            inner: unsafe { UPSafeCell::new(OSInodeInner {
-                offset: 0,
-                inode,
-            })},
+            inner: SpinLock::new(OSInodeInner { offset: 0, inode }),
         }
     }
-    /// Read all data inside a inode into vector
-    pub fn read_all(&self) -> Vec<u8> {
+    /// Move the offset past the `len` bytes from it which a read or write is about to access,
+    /// so that the tasks sharing the file never access the same bytes,
+    /// and return the range `[start, end)` taken
+    fn reserve(&self, len: impl FnOnce(usize) -> usize) -> (usize, usize) {
         let mut inner = self.inner.exclusive_access();
-        let mut buffer = [0u8; 512];
-        let mut v: Vec<u8> = Vec::new();
-        loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
-            if len == 0 {
-                break;
-            }
-            inner.offset += len;
-            v.extend_from_slice(&buffer[..len]);
+        let start = inner.offset;
+        let end = start + len(start);
+        inner.offset = end;
+        (start, end)
+    }
+    /// Give back the bytes from `done` to `end` which have not been accessed,
+    /// unless another read or write has taken the offset past them since
+    fn unreserve(&self, done: usize, end: usize) {
+        let mut inner = self.inner.exclusive_access();
+        if inner.offset == end {
+            inner.offset = done;
         }
-        v
     }
 }
 
 lazy_static! {
     /// The root of all inodes, or '/' in short
     pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
+        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone()).expect("Error loading EFS!");
         Arc::new(EasyFileSystem::root_inode(&efs))
     };
 }
@@ -70,6 +73,10 @@ lazy_static! {
 pub fn list_apps() {
     println!("/**** APPS ****");
     for app in ROOT_INODE.ls() {
+        // skip "." and ".." as well as hidden files such as the swap file
+        if app.starts_with('.') {
+            continue;
+        }
         println!("{}", app);
     }
     println!("**************/");
@@ -101,69 +108,175 @@ impl OpenFlags {
     }
 }
 
-/// Open a file by path
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+/// Find an inode by path, relative to `dir` unless the path is absolute
+pub fn find_inode_at(dir: &Arc<Inode>, path: &str) -> Result<Arc<Inode>, FsError> {
+    if path.starts_with('/') {
+        ROOT_INODE.find_path(path)
+    } else {
+        dir.find_path(path)
+    }
+}
+
+/// Split a path into the path of its parent directory and its last component
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Join `path` onto the absolute path `cwd`,
+/// resolving "." and ".." into a normalized absolute path
+pub fn join_path(cwd: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut names: Vec<&str> = Vec::new();
+    for name in base.split('/').chain(path.split('/')) {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            name => names.push(name),
+        }
+    }
+    if names.is_empty() {
+        return String::from("/");
+    }
+    let mut joined = String::new();
+    for name in names {
+        joined.push('/');
+        joined.push_str(name);
+    }
+    joined
+}
+
+/// Open a file by path, relative to `dir` unless the path is absolute
+pub fn open_file_at(
+    dir: &Arc<Inode>,
+    path: &str,
+    flags: OpenFlags,
+) -> Result<Arc<OSInode>, FsError> {
     let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(
-                readable,
-                writable,
-                inode,
-            )))
-        } else {
+    let inode = match find_inode_at(dir, path) {
+        Ok(inode) => {
+            // directories can only be opened read-only
+            if inode.is_dir()
+                && (writable || flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNC))
+            {
+                return Err(FsError::IsDir);
+            }
+            if flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNC) {
+                // clear size
+                inode.clear();
+            }
+            inode
+        }
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
             // create file
-            ROOT_INODE.create(name)
-                .map(|inode| {
-                    Arc::new(OSInode::new(
-                        readable,
-                        writable,
-                        inode,
-                    ))
-                })
+            let (parent_path, name) = split_path(path);
+            find_inode_at(dir, parent_path)?.create(name)?
         }
+        Err(err) => return Err(err),
+    };
+    Ok(Arc::new(OSInode::new(
+        readable,
+        writable,
+        inode,
+    )))
+}
+
+/// Open a file by absolute path
+pub fn open_file(path: &str, flags: OpenFlags) -> Result<Arc<OSInode>, FsError> {
+    open_file_at(&ROOT_INODE, path, flags)
+}
+
+/// Create a hard link at `new_path` to the file at `old_path`,
+/// each relative to its directory unless absolute
+pub fn link_at(
+    old_dir: &Arc<Inode>,
+    old_path: &str,
+    new_dir: &Arc<Inode>,
+    new_path: &str,
+) -> Result<(), FsError> {
+    let target = find_inode_at(old_dir, old_path)?;
+    let (parent_path, name) = split_path(new_path);
+    find_inode_at(new_dir, parent_path)?.link(name, &target)
+}
+
+/// Remove a file, or an empty directory if `remove_dir`, by path,
+/// relative to `dir` unless the path is absolute
+pub fn unlink_at(dir: &Arc<Inode>, path: &str, remove_dir: bool) -> Result<(), FsError> {
+    let (parent_path, name) = split_path(path);
+    let parent = find_inode_at(dir, parent_path)?;
+    if remove_dir {
+        parent.rmdir(name)
     } else {
-        ROOT_INODE.find(name)
-            .map(|inode| {
-                if flags.contains(OpenFlags::TRUNC) {
-                    inode.clear();
-                }
-                Arc::new(OSInode::new(
-                    readable,
-                    writable,
-                    inode
-                ))
-            })
+        parent.unlink(name)
     }
 }
 
+/// Create a directory by path, relative to `dir` unless the path is absolute
+pub fn make_dir_at(dir: &Arc<Inode>, path: &str) -> Result<Arc<Inode>, FsError> {
+    let (parent_path, name) = split_path(path);
+    find_inode_at(dir, parent_path)?.mkdir(name)
+}
+
 impl File for OSInode {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
-    fn read(&self, mut buf: UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
-        let mut total_read_size = 0usize;
+    fn inode(&self) -> Option<Arc<Inode>> {
+        Some(Arc::clone(&self.inner.exclusive_access().inode))
+    }
+    fn read(&self, mut buf: UserBuffer) -> SysResult {
+        let inode = self.inode().unwrap();
+        // the disk may put the task to sleep, so the size is found before the offset is locked
+        let size = inode.size();
+        let (start, end) = self.reserve(|offset| buf.len().min(size.saturating_sub(offset)));
+        let mut offset = start;
+        let mut result = Ok(());
         for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
-            if read_size == 0 {
-                break;
+            let len = slice.len().min(end - offset);
+            match inode.read_at(offset, &mut slice[..len]) {
+                Ok(0) => break,
+                Ok(read_size) => offset += read_size,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
             }
-            inner.offset += read_size;
-            total_read_size += read_size;
         }
-        total_read_size
+        if offset < end {
+            self.unreserve(offset, end);
+        }
+        // the bytes read so far are reported before the error
+        match result {
+            Err(err) if offset == start => Err(err.into()),
+            _ => Ok(offset - start),
+        }
     }
-    fn write(&self, buf: UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
-        let mut total_write_size = 0usize;
+    fn write(&self, buf: UserBuffer) -> SysResult {
+        let inode = self.inode().unwrap();
+        let (start, end) = self.reserve(|_| buf.len());
+        let mut offset = start;
+        let mut result = Ok(());
         for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
-            inner.offset += write_size;
-            total_write_size += write_size;
+            // stop once the disk is full or fails
+            match inode.write_at(offset, *slice) {
+                Ok(write_size) => offset += write_size,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        if offset < end {
+            self.unreserve(offset, end);
+        }
+        match result {
+            Err(err) if offset == start => Err(err.into()),
+            _ => Ok(offset - start),
         }
-        total_write_size
     }
 }
@@ -3,13 +3,24 @@ mod inode;
 mod pipe;
 
 use crate::mm::UserBuffer;
+use crate::syscall::errno::SysResult;
+use alloc::sync::Arc;
+use easy_fs::Inode;
 
 /// The common abstraction of all IO resources
 pub trait File : Send + Sync {
     fn readable(&self) -> bool;
     fn writable(&self) -> bool;
-    fn read(&self, buf: UserBuffer) -> usize;
-    fn write(&self, buf: UserBuffer) -> usize;
+    /// The filesystem inode behind this file, if any
+    fn inode(&self) -> Option<Arc<Inode>> {
+        None
+    }
+    /// Read into `buf`, return the size read, or fail with EINTR
+    /// if a signal to handle arrives before anything is read
+    fn read(&self, buf: UserBuffer) -> SysResult;
+    /// Write `buf`, return the size written, or fail with EINTR
+    /// if a signal to handle arrives before anything is written
+    fn write(&self, buf: UserBuffer) -> SysResult;
 }
 
 /// The stat of a inode
@@ -28,6 +39,19 @@ pub struct Stat {
     pad: [u64; 7],
 }
 
+impl Stat {
+    /// Describe an inode of easy-fs
+    pub fn from_inode(inode: &Inode) -> Self {
+        Self {
+            dev: 0,
+            ino: inode.inode_id() as u64,
+            mode: if inode.is_dir() { StatMode::DIR } else { StatMode::FILE },
+            nlink: inode.nlink(),
+            pad: [0; 7],
+        }
+    }
+}
+
 bitflags! {
     /// The mode of a inode
     /// whether a directory or a file
@@ -41,5 +65,8 @@ bitflags! {
 }    
 
 pub use stdio::{Stdin, Stdout};
-pub use inode::{OSInode, open_file, OpenFlags, list_apps};
+pub use inode::{
+    OSInode, open_file, open_file_at, find_inode_at, make_dir_at, link_at, unlink_at, join_path,
+    OpenFlags, list_apps, ROOT_INODE,
+};
 pub use pipe::{Pipe, make_pipe};
@@ -1,20 +1,21 @@
 use super::File;
 use alloc::sync::{Arc, Weak};
-use crate::sync::UPSafeCell;
+use crate::sync::SpinLock;
 use crate::mm::UserBuffer;
 
-use crate::task::suspend_current_and_run_next;
+use crate::syscall::errno::{Errno, SysResult};
+use crate::task::{current_signal_pending, suspend_current_and_run_next};
 
 /// One end of a pipe
 pub struct Pipe {
     readable: bool,
     writable: bool,
-    buffer: Arc<UPSafeCell<PipeRingBuffer>>,
+    buffer: Arc<SpinLock<PipeRingBuffer>>,
 }
 
 impl Pipe {
     /// Create the read end of a pipe from a ring buffer
-    pub fn read_end_with_buffer(buffer: Arc<UPSafeCell<PipeRingBuffer>>) -> Self {
+    pub fn read_end_with_buffer(buffer: Arc<SpinLock<PipeRingBuffer>>) -> Self {
         Self {
             readable: true,
             writable: false,
@@ -22,7 +23,7 @@ impl Pipe {
         }
     }
     /// Create the write end of a pipe with a ring buffer
-    pub fn write_end_with_buffer(buffer: Arc<UPSafeCell<PipeRingBuffer>>) -> Self {
+    pub fn write_end_with_buffer(buffer: Arc<SpinLock<PipeRingBuffer>>) -> Self {
         Self {
             readable: false,
             writable: true,
@@ -111,9 +112,7 @@ impl PipeRingBuffer {
 /// Crate a pipe
 /// return (read_end, write_end)
 pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
-    let buffer = Arc::new(unsafe {
-        UPSafeCell::new(PipeRingBuffer::new())
-    });
+    let buffer = Arc::new(SpinLock::new(PipeRingBuffer::new()));
     let read_end = Arc::new(
         Pipe::read_end_with_buffer(buffer.clone())
     );
@@ -124,10 +123,20 @@ pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
     (read_end, write_end)
 }
 
+/// The result of a read or write interrupted by a signal after `size` bytes,
+/// which fails with EINTR only if nothing has been transferred
+fn interrupted(size: usize) -> SysResult {
+    if size > 0 {
+        Ok(size)
+    } else {
+        Err(Errno::EINTR)
+    }
+}
+
 impl File for Pipe {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
-    fn read(&self, buf: UserBuffer) -> usize {
+    fn read(&self, buf: UserBuffer) -> SysResult {
         assert_eq!(self.readable(), true);
         let mut buf_iter = buf.into_iter();
         let mut read_size = 0usize;
@@ -136,9 +145,12 @@ impl File for Pipe {
             let loop_read = ring_buffer.available_read();
             if loop_read == 0 {
                 if ring_buffer.all_write_ends_closed() {
-                    return read_size;
+                    return Ok(read_size);
                 }
                 drop(ring_buffer);
+                if current_signal_pending() {
+                    return interrupted(read_size);
+                }
                 suspend_current_and_run_next();
                 continue;
             }
@@ -148,12 +160,12 @@ impl File for Pipe {
                     unsafe { *byte_ref = ring_buffer.read_byte(); }
                     read_size += 1;
                 } else {
-                    return read_size;
+                    return Ok(read_size);
                 }
             }
         }
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> SysResult {
         assert_eq!(self.writable(), true);
         let mut buf_iter = buf.into_iter();
         let mut write_size = 0usize;
@@ -162,6 +174,9 @@ impl File for Pipe {
             let loop_write = ring_buffer.available_write();
             if loop_write == 0 {
                 drop(ring_buffer);
+                if current_signal_pending() {
+                    return interrupted(write_size);
+                }
                 suspend_current_and_run_next();
                 continue;
             }
@@ -171,7 +186,7 @@ impl File for Pipe {
                     ring_buffer.write_byte(unsafe { *byte_ref });
                     write_size += 1;
                 } else {
-                    return write_size;
+                    return Ok(write_size);
                 }
             }
         }
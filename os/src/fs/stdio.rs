@@ -1,7 +1,7 @@
 use super::File;
+use crate::drivers::UART;
 use crate::mm::{UserBuffer};
-use crate::sbi::console_getchar;
-use crate::task::suspend_current_and_run_next;
+use crate::syscall::errno::SysResult;
 
 /// The standard input
 pub struct Stdin;
@@ -11,24 +11,24 @@ pub struct Stdout;
 impl File for Stdin {
     fn readable(&self) -> bool { true }
     fn writable(&self) -> bool { false }
-    fn read(&self, mut user_buf: UserBuffer) -> usize {
-        assert_eq!(user_buf.len(), 1);
-        // busy loop
-        let mut c: usize;
-        loop {
-            c = console_getchar();
-            if c == 0 {
-                suspend_current_and_run_next();
-                continue;
+    fn read(&self, mut user_buf: UserBuffer) -> SysResult {
+        let mut total_read_size = 0usize;
+        for slice in user_buf.buffers.iter_mut() {
+            // wait for the first byte only, then take whatever else has arrived
+            let read_size = if total_read_size == 0 {
+                // a signal interrupts the wait for the first byte with EINTR
+                UART.read(slice)?
             } else {
+                UART.read_buffered(slice)
+            };
+            total_read_size += read_size;
+            if read_size < slice.len() {
                 break;
             }
         }
-        let ch = c as u8;
-        unsafe { user_buf.buffers[0].as_mut_ptr().write_volatile(ch); }
-        1
+        Ok(total_read_size)
     }
-    fn write(&self, _user_buf: UserBuffer) -> usize {
+    fn write(&self, _user_buf: UserBuffer) -> SysResult {
         panic!("Cannot write to stdin!");
     }
 }
@@ -36,13 +36,13 @@ impl File for Stdin {
 impl File for Stdout {
     fn readable(&self) -> bool { false }
     fn writable(&self) -> bool { true }
-    fn read(&self, _user_buf: UserBuffer) -> usize{
+    fn read(&self, _user_buf: UserBuffer) -> SysResult {
         panic!("Cannot read from stdout!");
     }
-    fn write(&self, user_buf: UserBuffer) -> usize {
+    fn write(&self, user_buf: UserBuffer) -> SysResult {
         for buffer in user_buf.buffers.iter() {
             print!("{}", core::str::from_utf8(*buffer).unwrap());
         }
-        user_buf.len()
+        Ok(user_buf.len())
     }
 }
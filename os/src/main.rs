@@ -12,8 +12,8 @@
 //! initialize various pieces of functionality. (See its source code for
 //! details.)
 //!
-//! We then call [`task::run_first_task()`] and for the first time go to
-//! userspace.
+//! The boot hart then starts the other harts at [`rust_main_secondary()`],
+//! and every hart calls [`task::run_tasks()`] to go to userspace.
 
 #![no_std]
 #![no_main]
@@ -31,6 +31,7 @@ extern crate alloc;
 mod console;
 mod config;
 mod drivers;
+mod fdt;
 mod fs;
 mod lang_items;
 mod logging;
@@ -57,21 +58,59 @@ fn clear_bss() {
 }
 
 #[no_mangle]
-/// the rust entry-point of os
-pub fn rust_main() -> ! {
+/// the rust entry-point of os, with the hart id and the device tree from the SBI
+pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
     clear_bss();
     logging::init();
     println!("[kernel] Hello, world!");
+    // the device tree will be overwritten by allocated frames, so read it first
+    let bootargs = fdt::bootargs(dtb_pa);
+    let sched_policy = bootargs.and_then(task::SchedPolicy::from_bootargs);
+    if let Some(on) = bootargs.and_then(mm::w_xor_x_from_bootargs) {
+        mm::set_w_xor_x(on);
+    }
     mm::init();
     mm::remap_test();
+    drivers::init();
     trap::init();
     trap::enable_timer_interrupt();
     timer::set_next_trigger();
+    drivers::init_irq();
+    trap::enable_external_interrupt();
     // Uncomment following lines and see what happens!
     // task::kernel_stackless_coroutine_test();
     // task::kernel_stackful_coroutine_test();
     fs::list_apps();
+    mm::init_swap();
+    if let Some(policy) = sched_policy {
+        task::set_sched_policy(policy);
+    }
     task::add_initproc();
+    start_other_harts(hartid);
     task::run_tasks();
     panic!("Unreachable in rust_main!");
 }
+
+/// Start the other harts through SBI HSM, skipping the hart ids which do not exist
+fn start_other_harts(boot_hartid: usize) {
+    extern "C" {
+        fn _start_secondary();
+    }
+    for hartid in (0..config::MAX_HARTS).filter(|&hartid| hartid != boot_hartid) {
+        sbi::hart_start(hartid, _start_secondary as usize, 0);
+    }
+}
+
+#[no_mangle]
+/// the rust entry-point of the other harts, once the boot hart has initialized the kernel
+pub extern "C" fn rust_main_secondary(hartid: usize) -> ! {
+    mm::init_hart();
+    trap::init();
+    trap::enable_timer_interrupt();
+    timer::set_next_trigger();
+    drivers::init_irq();
+    trap::enable_external_interrupt();
+    println!("[kernel] Hart {} is online.", hartid);
+    task::run_tasks();
+    panic!("Unreachable in rust_main_secondary!");
+}
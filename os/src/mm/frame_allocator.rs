@@ -1,15 +1,17 @@
 //! Implementation of [`FrameAllocator`] which
 //! controls all the frames in the operating system.
 
+use super::swap::swap_out_one;
 use super::{PhysAddr, PhysPageNum};
 use crate::config::MEMORY_END;
-use crate::sync::UPSafeCell;
+use crate::sync::SpinLock;
+use alloc::collections::BTreeSet;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
 use lazy_static::*;
 
 /// manage a frame which has the same lifecycle as the tracker
-#[derive(Clone)]
 pub struct FrameTracker {
     pub ppn: PhysPageNum,
 }
@@ -37,61 +39,191 @@ impl Drop for FrameTracker {
     }
 }
 
+/// manage `pages` contiguous frames starting at `ppn`,
+/// which have the same lifecycle as the tracker
+pub struct ContiguousFrameTracker {
+    pub ppn: PhysPageNum,
+    pub pages: usize,
+}
+
+impl ContiguousFrameTracker {
+    pub fn new(ppn: PhysPageNum, pages: usize) -> Self {
+        // page cleaning
+        for i in 0..pages {
+            PhysPageNum(ppn.0 + i).get_bytes_array().fill(0);
+        }
+        Self { ppn, pages }
+    }
+}
+
+impl Debug for ContiguousFrameTracker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "ContiguousFrameTracker:PPN={:#x},pages={}",
+            self.ppn.0, self.pages
+        ))
+    }
+}
+
+impl Drop for ContiguousFrameTracker {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR
+            .exclusive_access()
+            .dealloc_contiguous(self.ppn, self.pages);
+    }
+}
+
+/// statistics of the frame allocator
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// frames managed by the allocator
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+    /// frames in the largest free block
+    pub largest_free: usize,
+    /// percentage of the free frames outside the largest free block,
+    /// which cannot serve a contiguous allocation as large as the free frames
+    pub fragmentation: usize,
+}
+
 trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysPageNum>;
+    /// allocate `pages` contiguous frames starting at a multiple of `align` frames
+    fn alloc_contiguous(&mut self, pages: usize, align: usize) -> Option<PhysPageNum>;
     fn dealloc(&mut self, ppn: PhysPageNum);
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, pages: usize);
+    fn stats(&self) -> FrameStats;
 }
 
-/// an implementation for frame allocator
-pub struct StackFrameAllocator {
-    current: usize,
+/// the largest block of a buddy frame allocator has 2^MAX_ORDER frames
+const MAX_ORDER: usize = 20;
+
+/// an implementation for frame allocator in the buddy system
+pub struct BuddyFrameAllocator {
+    start: usize,
     end: usize,
-    recycled: Vec<usize>,
+    /// free blocks of 2^order frames by order, each aligned to its size
+    free_lists: Vec<BTreeSet<usize>>,
+    free: usize,
 }
 
-impl StackFrameAllocator {
+impl BuddyFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
-        self.current = l.0;
+        self.start = l.0;
         self.end = r.0;
-        info!("last {} Physical Frames.", self.end - self.current);
+        self.free_range(l.0, r.0);
+        info!("last {} Physical Frames.", self.end - self.start);
+    }
+    /// free `[l, r)` as the largest aligned blocks which fit
+    fn free_range(&mut self, mut l: usize, r: usize) {
+        while l < r {
+            let mut order = (l.trailing_zeros() as usize).min(MAX_ORDER);
+            while l + (1 << order) > r {
+                order -= 1;
+            }
+            self.free_block(l, order);
+            l += 1 << order;
+        }
+    }
+    /// free a block, merging it with its buddy as long as the buddy is free too
+    fn free_block(&mut self, mut ppn: usize, order: usize) {
+        self.free += 1 << order;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = ppn ^ (1 << order);
+            if !self.free_lists[order].remove(&buddy) {
+                break;
+            }
+            ppn = ppn.min(buddy);
+            order += 1;
+        }
+        self.free_lists[order].insert(ppn);
+    }
+    /// allocate a block of 2^order frames, splitting a larger one if needed
+    fn alloc_block(&mut self, order: usize) -> Option<usize> {
+        let mut current = (order..=MAX_ORDER).find(|&o| !self.free_lists[o].is_empty())?;
+        let ppn = *self.free_lists[current].iter().next().unwrap();
+        self.free_lists[current].remove(&ppn);
+        // put back the upper halves
+        while current > order {
+            current -= 1;
+            self.free_lists[current].insert(ppn + (1 << current));
+        }
+        self.free -= 1 << order;
+        Some(ppn)
+    }
+    /// whether `ppn` lies in some free block
+    fn is_free(&self, ppn: usize) -> bool {
+        (0..=MAX_ORDER).any(|order| self.free_lists[order].contains(&(ppn & !((1 << order) - 1))))
+    }
+    fn check_allocated(&self, ppn: usize) {
+        // validity check
+        if ppn < self.start || ppn >= self.end || self.is_free(ppn) {
+            panic!("Frame ppn={:#x} has not been allocated!", ppn);
+        }
     }
 }
-impl FrameAllocator for StackFrameAllocator {
+
+impl FrameAllocator for BuddyFrameAllocator {
     fn new() -> Self {
         Self {
-            current: 0,
+            start: 0,
             end: 0,
-            recycled: Vec::new(),
+            free_lists: vec![BTreeSet::new(); MAX_ORDER + 1],
+            free: 0,
         }
     }
     fn alloc(&mut self) -> Option<PhysPageNum> {
-        if let Some(ppn) = self.recycled.pop() {
-            Some(ppn.into())
-        } else if self.current == self.end {
-            None
-        } else {
-            self.current += 1;
-            Some((self.current - 1).into())
+        self.alloc_block(0).map(PhysPageNum)
+    }
+    fn alloc_contiguous(&mut self, pages: usize, align: usize) -> Option<PhysPageNum> {
+        let order = pages.max(align).next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
         }
+        let ppn = self.alloc_block(order)?;
+        // give back the frames beyond `pages`
+        self.free_range(ppn + pages, ppn + (1 << order));
+        Some(PhysPageNum(ppn))
     }
     fn dealloc(&mut self, ppn: PhysPageNum) {
-        let ppn = ppn.0;
-        // validity check
-        if ppn >= self.current || self.recycled.iter().any(|v| *v == ppn) {
-            panic!("Frame ppn={:#x} has not been allocated!", ppn);
+        self.check_allocated(ppn.0);
+        self.free_block(ppn.0, 0);
+    }
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, pages: usize) {
+        for i in 0..pages {
+            self.check_allocated(ppn.0 + i);
+        }
+        self.free_range(ppn.0, ppn.0 + pages);
+    }
+    fn stats(&self) -> FrameStats {
+        let total = self.end - self.start;
+        let largest_free = (0..=MAX_ORDER)
+            .rev()
+            .find(|&order| !self.free_lists[order].is_empty())
+            .map_or(0, |order| 1 << order);
+        FrameStats {
+            total,
+            free: self.free,
+            used: total - self.free,
+            largest_free,
+            fragmentation: if self.free == 0 {
+                0
+            } else {
+                (self.free - largest_free) * 100 / self.free
+            },
         }
-        // recycle
-        self.recycled.push(ppn);
     }
 }
 
-type FrameAllocatorImpl = StackFrameAllocator;
+type FrameAllocatorImpl = BuddyFrameAllocator;
 
 lazy_static! {
     /// frame allocator instance through lazy_static!
-    pub static ref FRAME_ALLOCATOR: UPSafeCell<FrameAllocatorImpl> =
-        unsafe { UPSafeCell::new(FrameAllocatorImpl::new()) };
+    pub static ref FRAME_ALLOCATOR: SpinLock<FrameAllocatorImpl> =
+        SpinLock::new(FrameAllocatorImpl::new());
 }
 
 pub fn init_frame_allocator() {
@@ -104,12 +236,18 @@ pub fn init_frame_allocator() {
     );
 }
 
-/// initiate the frame allocator using `ekernel` and `MEMORY_END`
+/// allocate a frame, swapping out user pages to make room when there is none left,
+/// return `None` if no page can be swapped out either
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOCATOR
-        .exclusive_access()
-        .alloc()
-        .map(FrameTracker::new)
+    loop {
+        let ppn = FRAME_ALLOCATOR.exclusive_access().alloc();
+        if let Some(ppn) = ppn {
+            return Some(FrameTracker::new(ppn));
+        }
+        if !swap_out_one() {
+            return None;
+        }
+    }
 }
 
 /// deallocate a frame
@@ -117,6 +255,28 @@ pub fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
 }
 
+/// allocate `pages` contiguous frames starting at a multiple of `align` frames,
+/// which must be a power of two, swapping out user pages to make room like [`frame_alloc`]
+pub fn frame_alloc_contiguous(pages: usize, align: usize) -> Option<ContiguousFrameTracker> {
+    assert!(pages > 0 && align.is_power_of_two());
+    loop {
+        let ppn = FRAME_ALLOCATOR
+            .exclusive_access()
+            .alloc_contiguous(pages, align);
+        if let Some(ppn) = ppn {
+            return Some(ContiguousFrameTracker::new(ppn, pages));
+        }
+        if !swap_out_one() {
+            return None;
+        }
+    }
+}
+
+/// get the statistics of the frame allocator
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.exclusive_access().stats()
+}
+
 #[allow(unused)]
 /// a simple test for frame allocator
 pub fn frame_allocator_test() {
@@ -133,5 +293,10 @@ pub fn frame_allocator_test() {
         v.push(frame);
     }
     drop(v);
+    let frames = frame_alloc_contiguous(3, 4).unwrap();
+    info!("{:?}", frames);
+    assert_eq!(frames.ppn.0 % 4, 0);
+    drop(frames);
+    info!("{:?}", frame_stats());
     info!("frame_allocator_test passed!");
 }
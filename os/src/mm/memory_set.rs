@@ -1,14 +1,21 @@
 //! Implementation of [`MapArea`] and [`MemorySet`].
 
-use super::{frame_alloc, FrameTracker};
+use super::frame_alloc;
+use super::page_table::flush_tlb_others;
+use super::shm::ShmSegment;
+use super::swap::{Page, PinnedPage};
 use super::{PTEFlags, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
-use crate::config::{MEMORY_END, MMIO, PAGE_SIZE, TRAMPOLINE};
-use crate::sync::UPSafeCell;
+use crate::config::{MAX_USER_HEAP_SIZE, MEMORY_END, MMIO, PAGE_SIZE, TRAMPOLINE, USER_SPACE_END};
+use crate::sync::SpinLock;
+use crate::syscall::errno::Errno;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use easy_fs::{FsError, Inode};
 use lazy_static::*;
 use riscv::register::satp;
 
@@ -25,10 +32,32 @@ extern "C" {
     fn strampoline();
 }
 
+/// Whether user areas which are both writable and executable are refused,
+/// enabled at build time by setting the `WXORX` environment variable
+static W_XOR_X: AtomicBool = AtomicBool::new(option_env!("WXORX").is_some());
+
+/// Refuse user areas which are both writable and executable from now on if `on`
+pub fn set_w_xor_x(on: bool) {
+    W_XOR_X.store(on, Ordering::Relaxed);
+}
+
+/// The W^X option chosen by `wxorx=on` or `wxorx=off` on the kernel command line, if any
+pub fn w_xor_x_from_bootargs(bootargs: &str) -> Option<bool> {
+    bootargs
+        .split_whitespace()
+        .filter_map(|arg| arg.strip_prefix("wxorx="))
+        .last()
+        .and_then(|value| match value {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        })
+}
+
 lazy_static! {
     /// a memory set instance through lazy_static! managing kernel space
-    pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> =
-        Arc::new(unsafe { UPSafeCell::new(MemorySet::new_kernel()) });
+    pub static ref KERNEL_SPACE: Arc<SpinLock<MemorySet>> =
+        Arc::new(SpinLock::new(MemorySet::new_kernel()));
 }
 
 /// Get the token of the kernel memory space
@@ -40,6 +69,10 @@ pub fn kernel_token() -> usize {
 pub struct MemorySet {
     page_table: PageTable,
     areas: Vec<MapArea>,
+    /// Where the heap starts, right after the elf
+    heap_bottom: usize,
+    /// The program break, where the heap ends
+    brk: usize,
 }
 
 impl MemorySet {
@@ -47,6 +80,8 @@ impl MemorySet {
         Self {
             page_table: PageTable::new(),
             areas: Vec::new(),
+            heap_bottom: 0,
+            brk: 0,
         }
     }
     pub fn token(&self) -> usize {
@@ -64,6 +99,151 @@ impl MemorySet {
             None,
         );
     }
+    /// Map `[start_va, end_va)` lazily, whose pages get zero-filled frames
+    /// on first touch. Assume that no conflicts.
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) {
+        self.push(MapArea::new_lazy(start_va, end_va, permission, None), None);
+    }
+    /// Map `[start_va, end_va)` lazily to zero-filled frames like [`Self::insert_lazy_area`],
+    /// for mmap or brk, so that munmap may remove it. Assume that no conflicts.
+    pub fn insert_anonymous_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) {
+        let mut map_area = MapArea::new_lazy(start_va, end_va, permission, None);
+        map_area.removable = true;
+        self.push(map_area, None);
+    }
+    /// Map `[start_va, end_va)` to `file` lazily, whose pages are shared on fork
+    /// and written back if it is a shared mapping. `max_perm` is the most mprotect
+    /// may grant later. Assume that no conflicts.
+    pub fn insert_file_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+        max_perm: MapPermission,
+        file: MapFile,
+    ) {
+        let shared = file.writeback;
+        let mut map_area = MapArea::new_lazy(start_va, end_va, permission, Some(file));
+        map_area.shared = shared;
+        map_area.max_perm = max_perm;
+        map_area.removable = true;
+        self.push(map_area, None);
+    }
+    /// Write the dirty pages of shared file mappings in `[start_vpn, end_vpn)`
+    /// back to their files. Fail with ENOMEM if some page in the range is not mapped.
+    pub fn sync_range(
+        &mut self,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+    ) -> Result<(), Errno> {
+        let mut vpn = start_vpn;
+        while vpn < end_vpn {
+            if !self.areas.iter().any(|area| area.contains(vpn)) {
+                return Err(Errno::ENOMEM);
+            }
+            vpn.step();
+        }
+        for area in self.areas.iter() {
+            let start = start_vpn.max(area.vpn_range.get_start());
+            let end = end_vpn.min(area.vpn_range.get_end());
+            area.sync(&mut self.page_table, start, end)?;
+        }
+        // a cached PTE which is still dirty would not mark the page dirty again
+        flush_tlb_others();
+        Ok(())
+    }
+    /// Write back every shared file mapping, whose errors cannot be reported any more,
+    /// but fail with EAGAIN if the filesystem is held by a task the caller cannot wait for,
+    /// so that the caller can try again without its locks
+    pub fn sync_all(&mut self) -> Result<(), Errno> {
+        let mut result = Ok(());
+        for area in self.areas.iter() {
+            if let Err(FsError::Busy) = area.sync(
+                &mut self.page_table,
+                area.vpn_range.get_start(),
+                area.vpn_range.get_end(),
+            ) {
+                result = Err(Errno::EAGAIN);
+            }
+        }
+        result
+    }
+    /// Map the pages of `segment` at `start_va`, which stay shared instead of
+    /// being copied on fork. `max_perm` is the most mprotect may grant later.
+    /// munmap may remove the area if it is `removable`, as a mapping of mmap is.
+    /// Assume that no conflicts.
+    pub fn insert_shared_area(
+        &mut self,
+        start_va: VirtAddr,
+        segment: &ShmSegment,
+        permission: MapPermission,
+        max_perm: MapPermission,
+        removable: bool,
+    ) {
+        let end_va = VirtAddr::from(usize::from(start_va) + segment.size());
+        let mut map_area = MapArea::new(start_va, end_va, MapType::Framed, permission);
+        map_area.shared = true;
+        map_area.max_perm = max_perm;
+        map_area.removable = removable;
+        let pte_flags = PTEFlags::from_bits(permission.bits).unwrap();
+        for (vpn, page) in map_area.vpn_range.into_iter().zip(segment.pages.iter()) {
+            // the pages of a segment are never swapped out
+            self.page_table.map(vpn, page.ppn().unwrap(), pte_flags);
+            map_area.data_frames.insert(vpn, Arc::clone(page));
+        }
+        self.areas.push(map_area);
+    }
+    /// Unmap the shared area starting at `start_vpn`, return false if there is none
+    pub fn remove_shared_area(&mut self, start_vpn: VirtPageNum) -> bool {
+        match self
+            .areas
+            .iter()
+            .position(|area| area.shared && area.vpn_range.get_start() == start_vpn)
+        {
+            Some(idx) => {
+                let mut area = self.areas.remove(idx);
+                area.unmap(&mut self.page_table);
+                flush_tlb_others();
+                true
+            }
+            None => false,
+        }
+    }
+    /// The start of the lowest `pages` unmapped pages in `[start_vpn, end_vpn)`
+    pub fn find_free_range(
+        &self,
+        mut start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+        pages: usize,
+    ) -> Option<VirtPageNum> {
+        loop {
+            let end = VirtPageNum(start_vpn.0 + pages);
+            if end > end_vpn {
+                return None;
+            }
+            // skip past every area in the way
+            match self
+                .areas
+                .iter()
+                .filter(|area| area.vpn_range.get_start() < end && start_vpn < area.vpn_range.get_end())
+                .map(|area| area.vpn_range.get_end())
+                .max()
+            {
+                Some(area_end) => start_vpn = area_end,
+                None => return Some(start_vpn),
+            }
+        }
+    }
     pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
         if let Some((idx, area)) = self
             .areas
@@ -73,8 +253,125 @@ impl MemorySet {
         {
             area.unmap(&mut self.page_table);
             self.areas.remove(idx);
+            flush_tlb_others();
         }
     }
+    /// Whether any area overlaps with `[start_vpn, end_vpn)`
+    pub fn conflicts_with(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        self.areas.iter().any(|area| {
+            area.vpn_range.get_start() < end_vpn && start_vpn < area.vpn_range.get_end()
+        })
+    }
+    /// Unmap `[start_vpn, end_vpn)`, splitting the areas which are partially covered.
+    /// Fail without changing anything if some page in the range is not mapped by a user area
+    /// of mmap or brk, so that the trap contexts, user stacks and elf are never unmapped.
+    pub fn remove_user_range(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        let mut vpn = start_vpn;
+        while vpn < end_vpn {
+            if !self.areas.iter().any(|area| {
+                area.removable && area.map_perm.contains(MapPermission::U) && area.contains(vpn)
+            }) {
+                return false;
+            }
+            vpn.step();
+        }
+        for mut area in self.take_range(start_vpn, end_vpn) {
+            area.unmap(&mut self.page_table);
+        }
+        flush_tlb_others();
+        true
+    }
+    /// Change the permission of `[start_vpn, end_vpn)` to `permission`, splitting the
+    /// areas which are partially covered. Fail without changing anything with ENOMEM if
+    /// some page in the range is not mapped by a user area, or with EACCES if some area
+    /// may not get `permission`.
+    pub fn protect_range(
+        &mut self,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+        permission: MapPermission,
+    ) -> Result<(), Errno> {
+        let mut vpn = start_vpn;
+        while vpn < end_vpn {
+            match self.areas.iter().find(|area| area.contains(vpn)) {
+                Some(area) if area.map_perm.contains(MapPermission::U) => {
+                    if !area.max_perm.contains(permission) {
+                        return Err(Errno::EACCES);
+                    }
+                }
+                _ => return Err(Errno::ENOMEM),
+            }
+            vpn.step();
+        }
+        for mut area in self.take_range(start_vpn, end_vpn) {
+            area.protect(&mut self.page_table, permission);
+            self.areas.push(area);
+        }
+        flush_tlb_others();
+        Ok(())
+    }
+    /// The program break
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+    /// Move the program break to `new_brk`, growing or shrinking the heap whose
+    /// pages are allocated on first touch. Fail if the heap would leave its room
+    /// of MAX_USER_HEAP_SIZE bytes or overlap another area.
+    pub fn set_brk(&mut self, new_brk: usize) -> bool {
+        if new_brk < self.heap_bottom || new_brk - self.heap_bottom > MAX_USER_HEAP_SIZE {
+            return false;
+        }
+        let old_end = VirtAddr::from(self.brk).ceil();
+        let new_end = VirtAddr::from(new_brk).ceil();
+        if new_end > old_end {
+            if self.conflicts_with(old_end, new_end) {
+                return false;
+            }
+            let permission = MapPermission::R | MapPermission::W | MapPermission::U;
+            // the heap may have been split by mprotect or munmap since it last grew
+            match self.areas.iter_mut().find(|area| {
+                area.vpn_range.get_end() == old_end
+                    && area.lazy
+                    && area.removable
+                    && area.file.is_none()
+                    && !area.shared
+                    && area.map_perm == permission
+            }) {
+                Some(area) => area.vpn_range = VPNRange::new(area.vpn_range.get_start(), new_end),
+                None => self.insert_anonymous_area(old_end.into(), new_end.into(), permission),
+            }
+        } else if new_end < old_end {
+            for mut area in self.take_range(new_end, old_end) {
+                area.unmap(&mut self.page_table);
+            }
+            flush_tlb_others();
+        }
+        self.brk = new_brk;
+        true
+    }
+    /// Take the parts of the areas inside `[start_vpn, end_vpn)` out of the memory set,
+    /// splitting the areas which are partially covered
+    fn take_range(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> Vec<MapArea> {
+        let mut taken = Vec::new();
+        let areas = core::mem::take(&mut self.areas);
+        for mut area in areas {
+            let (l, r) = (area.vpn_range.get_start(), area.vpn_range.get_end());
+            if r <= start_vpn || l >= end_vpn {
+                self.areas.push(area);
+                continue;
+            }
+            if r > end_vpn {
+                self.areas.push(area.split_off(end_vpn));
+            }
+            if l < start_vpn {
+                taken.push(area.split_off(start_vpn));
+                self.areas.push(area);
+            } else {
+                taken.push(area);
+            }
+        }
+        taken
+    }
     fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
         map_area.map(&mut self.page_table);
         if let Some(data) = data {
@@ -169,22 +466,34 @@ impl MemorySet {
     }
     /// Include sections in elf and trampoline and TrapContext and user stack,
     /// also returns user_sp and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
+    /// Only the headers of the elf are read here, the sections are loaded lazily.
+    /// Fail with EACCES if a segment is both writable and executable under W^X,
+    /// with ENOEXEC if the elf is malformed, or with EIO if it cannot be read.
+    pub fn from_elf(elf_inode: &Arc<Inode>) -> Result<(Self, usize, usize), Errno> {
         let mut memory_set = Self::new_bare();
         // map trampoline
         memory_set.map_trampoline();
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf_headers = read_elf_headers(elf_inode)?;
+        let elf = xmas_elf::ElfFile::new(&elf_headers).map_err(|_| Errno::ENOEXEC)?;
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            return Err(Errno::ENOEXEC);
+        }
         let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
         for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+            let ph = elf.program_header(i).map_err(|_| Errno::ENOEXEC)?;
+            if ph.get_type().map_err(|_| Errno::ENOEXEC)? == xmas_elf::program::Type::Load {
+                // a segment must lie in the user half
+                let end = ph
+                    .virtual_addr()
+                    .checked_add(ph.mem_size())
+                    .filter(|&end| end as usize <= USER_SPACE_END)
+                    .ok_or(Errno::ENOEXEC)?;
                 let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
+                let end_va: VirtAddr = (end as usize).into();
                 let mut map_perm = MapPermission::U;
                 let ph_flags = ph.flags();
                 if ph_flags.is_read() {
@@ -196,33 +505,87 @@ impl MemorySet {
                 if ph_flags.is_execute() {
                     map_perm |= MapPermission::X;
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+                if !map_perm.allowed() {
+                    return Err(Errno::EACCES);
+                }
+                // the part beyond the file size, such as .bss, is zero-filled
+                let file = MapFile {
+                    inode: Arc::clone(elf_inode),
+                    start_va: start_va.into(),
+                    offset: ph.offset() as usize,
+                    len: ph.file_size() as usize,
+                    writeback: false,
+                };
+                let map_area = MapArea::new_lazy(start_va, end_va, map_perm, Some(file));
                 max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                memory_set.push(map_area, None);
             }
         }
+        // the heap starts empty after the elf, and the user stacks follow its room
+        let max_end_va: VirtAddr = max_end_vpn.into();
+        memory_set.heap_bottom = max_end_va.into();
+        memory_set.brk = memory_set.heap_bottom;
         // We don't map user stack and trapframe here since they will be later
         // allocated through TaskControlBlock::new()
-        let max_end_va: VirtAddr = max_end_vpn.into();
-        let mut user_stack_top: usize = max_end_va.into();
+        let mut user_stack_top: usize = memory_set.heap_bottom + MAX_USER_HEAP_SIZE;
         user_stack_top += PAGE_SIZE;
-        (
+        Ok((
             memory_set,
             user_stack_top,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
     }
-    /// Copy an identical user_space
-    pub fn from_existed_user(user_space: &MemorySet) -> MemorySet {
+    /// Copy an identical user_space, whose user pages are shared copy-on-write
+    /// with the new one by making them read-only in both
+    /// Fail if the untouched pages of a shared mapping cannot be loaded to be shared,
+    /// as [`MemorySet::handle_page_fault`] does.
+    pub fn from_existed_user(user_space: &mut MemorySet) -> Result<MemorySet, Errno> {
         let mut memory_set = Self::new_bare();
+        memory_set.heap_bottom = user_space.heap_bottom;
+        memory_set.brk = user_space.brk;
         // map trampoline
         memory_set.map_trampoline();
-        // copy data sections/trap_context/user_stack
+        // the pages of a shared mapping must exist to be shared,
+        // so the untouched ones are loaded in the parent first
+        for area in user_space.areas.iter_mut().filter(|area| area.shared && area.lazy) {
+            let mut vpn = area.vpn_range.get_start();
+            while vpn < area.vpn_range.get_end() {
+                if !area.data_frames.contains_key(&vpn) {
+                    area.load_one(&mut user_space.page_table, vpn)?;
+                }
+                vpn.step();
+            }
+        }
+        // share data sections/user_stack and copy trap_context
         for area in user_space.areas.iter() {
-            let new_area = MapArea::from_another(area);
+            let mut new_area = MapArea::from_another(area);
+            if area.map_type == MapType::Framed && area.map_perm.contains(MapPermission::U) {
+                let mut pte_flags = PTEFlags::from_bits(area.map_perm.bits).unwrap();
+                // pages which are not shared are copied on write by both sides
+                if !area.shared {
+                    pte_flags.remove(PTEFlags::W);
+                }
+                for (vpn, page) in area.data_frames.iter() {
+                    page.set_owner(None);
+                    // a page swapped out or not faulted in again since then
+                    // is mapped on the next access instead
+                    if let Some(pte) = user_space.translate(*vpn).filter(PageTableEntry::is_valid) {
+                        // the dirty bit of the parent is not seen by the child
+                        if pte.flags().contains(PTEFlags::D) {
+                            page.set_dirty();
+                        }
+                        let mut pte_flags = pte_flags;
+                        if area.writes_back() && !page.is_dirty() {
+                            pte_flags.remove(PTEFlags::W);
+                        }
+                        user_space.page_table.remap(*vpn, pte.ppn(), pte_flags);
+                        memory_set.page_table.map(*vpn, pte.ppn(), pte_flags);
+                    }
+                    new_area.data_frames.insert(*vpn, Arc::clone(page));
+                }
+                memory_set.areas.push(new_area);
+                continue;
+            }
             memory_set.push(new_area, None);
             // copy data from another space
             for vpn in area.vpn_range {
@@ -233,7 +596,111 @@ impl MemorySet {
                     .copy_from_slice(src_ppn.get_bytes_array());
             }
         }
-        memory_set
+        // the other threads of the parent must not write the pages shared now
+        flush_tlb_others();
+        Ok(memory_set)
+    }
+    /// Resolve a page fault of user at `vpn` caused by `access`, one of R, W and X,
+    /// against the area owning the page. The page is mapped if it is untouched in a
+    /// lazy area or swapped out, or gets a frame of its own if it is shared copy-on-write
+    /// and the area is not shared.
+    /// Fail with EFAULT if the access is not permitted, with ENOMEM if there is no frame left,
+    /// or with the error of the file the page is loaded from, which is EAGAIN
+    /// if the filesystem is held by a task the caller cannot wait for.
+    pub fn handle_page_fault(
+        &mut self,
+        vpn: VirtPageNum,
+        access: MapPermission,
+    ) -> Result<(), Errno> {
+        let token = self.token();
+        let area = match self.areas.iter_mut().find(|area| area.contains(vpn)) {
+            Some(area) => area,
+            None => return Err(Errno::EFAULT),
+        };
+        if area.map_type != MapType::Framed || !area.map_perm.contains(access | MapPermission::U) {
+            return Err(Errno::EFAULT);
+        }
+        if !area.data_frames.contains_key(&vpn) {
+            if !area.lazy {
+                return Err(Errno::EFAULT);
+            }
+            area.load_one(&mut self.page_table, vpn)?;
+        }
+        let read_only = area.write_faults(vpn);
+        let page = area.data_frames.get_mut(&vpn).unwrap();
+        let page_table = &mut self.page_table;
+        let mapped = page_table
+            .translate(vpn)
+            .map_or(false, |pte| pte.is_valid());
+        // a page just mapped counts as accessed, so that it is not swapped out at once
+        let mut pte_flags = PTEFlags::from_bits(area.map_perm.bits).unwrap() | PTEFlags::A;
+        if !access.contains(MapPermission::W) {
+            if mapped {
+                // the page is already mapped with the permission of the area
+                return Ok(());
+            }
+            if read_only {
+                pte_flags.remove(PTEFlags::W);
+            }
+            // the page may be swapped out, or have been swapped in by another one sharing it
+            if !page.swap_in(|ppn| page_table.map(vpn, ppn, pte_flags)) {
+                return Err(Errno::ENOMEM);
+            }
+            return Ok(());
+        }
+        if Arc::strong_count(page) == 1 {
+            // the last one sharing the page can simply take it
+            page.set_owner(Some((token, vpn)));
+        } else if !area.shared {
+            let new_frame = frame_alloc().ok_or(Errno::ENOMEM)?;
+            // the page shared may be swapped out, and is copied without swapping it in
+            page.read(new_frame.ppn.get_bytes_array())?;
+            *page = Page::new(new_frame, Some((token, vpn)));
+        }
+        // otherwise the page is written in place for everyone sharing it,
+        // whose mappings cannot tell that it is dirty by their own dirty bits
+        page.set_dirty();
+        let swapped_in = page.swap_in(|ppn| {
+            // the page may have been swapped out since it was found mapped
+            if page_table
+                .translate(vpn)
+                .map_or(false, |pte| pte.is_valid())
+            {
+                page_table.remap(vpn, ppn, pte_flags);
+            } else {
+                page_table.map(vpn, ppn, pte_flags);
+            }
+        });
+        if !swapped_in {
+            return Err(Errno::ENOMEM);
+        }
+        Ok(())
+    }
+    /// Keep the user page at `vpn` resident until the returned pin is dropped,
+    /// return `None` if it is not mapped
+    pub fn pin(&self, vpn: VirtPageNum) -> Option<PinnedPage> {
+        self.areas
+            .iter()
+            .find(|area| area.contains(vpn))?
+            .data_frames
+            .get(&vpn)
+            .map(Page::pin)
+    }
+    /// Make every page in `[start_va, end_va)` present and writable, so that the
+    /// kernel can write to them through the page table directly, and keep them
+    /// resident until the returned pins are dropped.
+    /// Return `None` if there is no frame left.
+    pub fn populate(&mut self, start_va: VirtAddr, end_va: VirtAddr) -> Option<Vec<PinnedPage>> {
+        let mut pins = Vec::new();
+        let mut vpn = start_va.floor();
+        while vpn < end_va.ceil() {
+            self.handle_page_fault(vpn, MapPermission::W).ok()?;
+            pins.push(self.pin(vpn).unwrap());
+            // the page may have been swapped out before it was pinned
+            self.handle_page_fault(vpn, MapPermission::W).ok()?;
+            vpn.step();
+        }
+        Some(pins)
     }
     pub fn activate(&self) {
         let satp = self.page_table.token();
@@ -247,6 +714,10 @@ impl MemorySet {
     }
     pub fn recycle_data_pages(&mut self) {
         //*self = Self::new_bare();
+        let _ = self.sync_all();
+        for area in self.areas.iter() {
+            area.disown();
+        }
         self.areas.clear();
     }
     pub fn kernel_copy() -> Self {
@@ -258,13 +729,72 @@ impl MemorySet {
     }
 }
 
+impl Drop for MemorySet {
+    fn drop(&mut self) {
+        let _ = self.sync_all();
+        // the page table goes away before the areas
+        for area in self.areas.iter() {
+            area.disown();
+        }
+    }
+}
+
+/// Read the elf header and the program headers at the start of an elf file,
+/// failing with ENOEXEC if the file is too short to hold them, or EIO if it cannot be read
+fn read_elf_headers(elf_inode: &Inode) -> Result<Vec<u8>, Errno> {
+    // an elf64 header takes 64 bytes
+    let mut data = vec![0u8; 64];
+    if elf_inode.read_at(0, &mut data)? < data.len() {
+        return Err(Errno::ENOEXEC);
+    }
+    let header = xmas_elf::header::parse_header(&data).map_err(|_| Errno::ENOEXEC)?;
+    let ph_end = (header.pt2.ph_count() as usize * header.pt2.ph_entry_size() as usize)
+        .checked_add(header.pt2.ph_offset() as usize)
+        .ok_or(Errno::ENOEXEC)?;
+    // the program headers must be within the file
+    if ph_end > elf_inode.size() {
+        return Err(Errno::ENOEXEC);
+    }
+    if ph_end > data.len() {
+        data.resize(ph_end, 0);
+        elf_inode.read_at(0, &mut data)?;
+    }
+    Ok(data)
+}
+
+/// The part of a file which a lazy area is loaded from
+#[derive(Clone)]
+pub struct MapFile {
+    /// The file to read from
+    pub inode: Arc<Inode>,
+    /// The virtual address the data of the file starts at
+    pub start_va: usize,
+    /// The offset of the data in the file
+    pub offset: usize,
+    /// The length of the data, the rest of the area is zero-filled
+    pub len: usize,
+    /// Whether the pages written are written back to the file, as those of a shared mapping
+    pub writeback: bool,
+}
+
 /// map area structure, controls a contiguous piece of virtual memory
 #[derive(Clone)]
 pub struct MapArea {
     vpn_range: VPNRange,
-    data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    data_frames: BTreeMap<VirtPageNum, Arc<Page>>,
     map_type: MapType,
     map_perm: MapPermission,
+    /// Whether the pages are mapped on first touch instead of up front
+    lazy: bool,
+    file: Option<MapFile>,
+    /// Whether the pages are shared with other address spaces instead of
+    /// copied on write, as those of shared memory
+    shared: bool,
+    /// The permission mprotect may grant, which leaves out W for
+    /// read-only shared memory and shared mappings of read-only files
+    max_perm: MapPermission,
+    /// Whether munmap may remove the area, which only those of mmap and brk are
+    removable: bool,
 }
 
 impl MapArea {
@@ -281,6 +811,25 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            lazy: false,
+            file: None,
+            shared: false,
+            max_perm: MapPermission::all(),
+            removable: false,
+        }
+    }
+    /// A framed area whose pages are mapped on first touch,
+    /// loaded from `file` if any and zero-filled otherwise
+    pub fn new_lazy(
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        map_perm: MapPermission,
+        file: Option<MapFile>,
+    ) -> Self {
+        Self {
+            lazy: true,
+            file,
+            ..Self::new(start_va, end_va, MapType::Framed, map_perm)
         }
     }
     pub fn from_another(another: &MapArea) -> Self {
@@ -289,6 +838,32 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type: another.map_type,
             map_perm: another.map_perm,
+            lazy: another.lazy,
+            file: another.file.clone(),
+            shared: another.shared,
+            max_perm: another.max_perm,
+            removable: another.removable,
+        }
+    }
+    /// Whether `vpn` lies inside this area
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.vpn_range.get_start() <= vpn && vpn < self.vpn_range.get_end()
+    }
+    /// Split this area at `vpn`: `self` keeps `[start, vpn)` and the returned
+    /// area takes `[vpn, end)` together with the frames mapped there.
+    pub fn split_off(&mut self, vpn: VirtPageNum) -> Self {
+        let end = self.vpn_range.get_end();
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), vpn);
+        Self {
+            vpn_range: VPNRange::new(vpn, end),
+            data_frames: self.data_frames.split_off(&vpn),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+            lazy: self.lazy,
+            file: self.file.clone(),
+            shared: self.shared,
+            max_perm: self.max_perm,
+            removable: self.removable,
         }
     }
     pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
@@ -300,31 +875,205 @@ impl MapArea {
             MapType::Framed => {
                 let frame = frame_alloc().unwrap();
                 ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                let owner = self.owner(page_table, vpn);
+                self.data_frames.insert(vpn, Page::new(frame, owner));
             }
         }
         let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
         page_table.map(vpn, ppn, pte_flags);
     }
+    /// Map the untouched page of `vpn` in a lazy area,
+    /// fail with ENOMEM if there is no frame left or with the error of reading the file
+    fn load_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Result<(), Errno> {
+        let frame = frame_alloc().ok_or(Errno::ENOMEM)?;
+        if let Some(file) = &self.file {
+            let page_start: usize = VirtAddr::from(vpn).into();
+            let start = page_start.max(file.start_va);
+            let end = (page_start + PAGE_SIZE).min(file.start_va + file.len);
+            if start < end {
+                file.inode.read_at(
+                    file.offset + start - file.start_va,
+                    &mut frame.ppn.get_bytes_array()[start - page_start..end - page_start],
+                )?;
+            }
+        }
+        let mut pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap() | PTEFlags::A;
+        // the page is clean until the first write faults
+        if self.writes_back() {
+            pte_flags.remove(PTEFlags::W);
+        }
+        page_table.map(vpn, frame.ppn, pte_flags);
+        let owner = self.owner(page_table, vpn);
+        self.data_frames.insert(vpn, Page::new(frame, owner));
+        Ok(())
+    }
+    /// Whether this area is a shared file mapping, whose pages are written back
+    fn writes_back(&self) -> bool {
+        matches!(&self.file, Some(file) if file.writeback)
+    }
+    /// Whether the page of `vpn` must be mapped read-only so that the next write faults,
+    /// since it is shared copy-on-write, or it is clean in a shared file mapping
+    /// and the write must mark it dirty for every address space sharing it
+    fn write_faults(&self, vpn: VirtPageNum) -> bool {
+        self.data_frames.get(&vpn).map_or(false, |page| {
+            (Arc::strong_count(page) > 1 && !self.shared)
+                || (self.writes_back() && !page.is_dirty())
+        })
+    }
+    /// Where a page of this area is mapped if it may be swapped out,
+    /// which only pages of user space may
+    fn owner(&self, page_table: &PageTable, vpn: VirtPageNum) -> Option<(usize, VirtPageNum)> {
+        if self.map_perm.contains(MapPermission::U) {
+            Some((page_table.token(), vpn))
+        } else {
+            None
+        }
+    }
+
+    /// Keep the pages of this area, which may outlive it in the clock of swapping,
+    /// from being swapped out through a page table which is about to go away
+    fn disown(&self) {
+        for page in self.data_frames.values() {
+            page.set_owner(None);
+        }
+    }
 
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
         #[allow(clippy::single_match)]
         match self.map_type {
             MapType::Framed => {
-                self.data_frames.remove(&vpn);
+                // a page of a lazy area may have never been touched,
+                // and a page swapped out is not mapped
+                match self.data_frames.remove(&vpn) {
+                    Some(page) => page.set_owner(None),
+                    None => return,
+                }
+                if !page_table
+                    .translate(vpn)
+                    .map_or(false, |pte| pte.is_valid())
+                {
+                    return;
+                }
             }
             _ => {}
         }
         page_table.unmap(vpn);
     }
+    /// Change the permission of the area and of the pages mapped, whose accessed
+    /// and dirty bits are kept for swapping and writing back
+    fn protect(&mut self, page_table: &mut PageTable, permission: MapPermission) {
+        self.map_perm = permission;
+        for (vpn, page) in self.data_frames.iter() {
+            let mut pte_flags = PTEFlags::from_bits(permission.bits).unwrap();
+            if self.write_faults(*vpn) {
+                pte_flags.remove(PTEFlags::W);
+            }
+            page.lock_pte(|| {
+                page_table.update_flags(*vpn, |flags| {
+                    pte_flags | (flags & (PTEFlags::A | PTEFlags::D))
+                })
+            });
+        }
+    }
+    /// Write the dirty pages in `[start_vpn, end_vpn)` back to the file
+    /// if this area is a shared file mapping
+    fn sync(
+        &self,
+        page_table: &mut PageTable,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+    ) -> Result<(), FsError> {
+        let file = match &self.file {
+            Some(file) if file.writeback => file,
+            _ => return Ok(()),
+        };
+        if start_vpn >= end_vpn {
+            return Ok(());
+        }
+        let mut data = vec![0u8; PAGE_SIZE];
+        for (vpn, page) in self.data_frames.range(start_vpn..end_vpn) {
+            // the dirty bit is taken for the write-back below, while swapping
+            // cannot unmap the page
+            let pte_dirty = page.lock_pte(|| {
+                page_table
+                    .update_flags(*vpn, |flags| flags - PTEFlags::D)
+                    .map_or(false, |pte| pte.flags().contains(PTEFlags::D))
+            });
+            // a page stays dirty while it is shared, since the others sharing it
+            // may go on writing it without faulting
+            let dirty = if Arc::strong_count(page) > 1 {
+                page.is_dirty()
+            } else {
+                page.take_dirty()
+            };
+            if !(dirty | pte_dirty) {
+                continue;
+            }
+            let page_start: usize = VirtAddr::from(*vpn).into();
+            let start = page_start.max(file.start_va);
+            let end = (page_start + PAGE_SIZE).min(file.start_va + file.len);
+            if start >= end {
+                continue;
+            }
+            let written = page.read(&mut data).and_then(|_| {
+                file.inode.write_at(
+                    file.offset + start - file.start_va,
+                    &data[start - page_start..end - page_start],
+                )
+            });
+            if let Err(err) = written {
+                page.set_dirty();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+    /// The level of the largest page which can map the identical area from `vpn`
+    /// on, see [`PageTable::map_huge`]. Framed areas are mapped in 4 KiB pages.
+    fn huge_level(&self, vpn: VirtPageNum) -> usize {
+        if self.map_type != MapType::Identical {
+            return 0;
+        }
+        (1..=2)
+            .rev()
+            .find(|level| {
+                let pages = 1usize << (9 * level);
+                vpn.0 % pages == 0 && vpn.0 + pages <= self.vpn_range.get_end().0
+            })
+            .unwrap_or(0)
+    }
     pub fn map(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+        if self.lazy {
+            return;
+        }
+        let mut vpn = self.vpn_range.get_start();
+        while vpn < self.vpn_range.get_end() {
+            let level = self.huge_level(vpn);
+            if level == 0 {
+                self.map_one(page_table, vpn);
+            } else {
+                let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+                page_table.map_huge(vpn, PhysPageNum(vpn.0), pte_flags, level);
+            }
+            vpn = VirtPageNum(vpn.0 + (1 << (9 * level)));
         }
     }
     pub fn unmap(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.unmap_one(page_table, vpn);
+        // nobody is left to report the errors to
+        let _ = self.sync(
+            page_table,
+            self.vpn_range.get_start(),
+            self.vpn_range.get_end(),
+        );
+        let mut vpn = self.vpn_range.get_start();
+        while vpn < self.vpn_range.get_end() {
+            let level = self.huge_level(vpn);
+            if level == 0 {
+                self.unmap_one(page_table, vpn);
+            } else {
+                page_table.unmap_huge(vpn, level);
+            }
+            vpn = VirtPageNum(vpn.0 + (1 << (9 * level)));
         }
     }
     /// data: start-aligned but maybe with shorter length
@@ -368,6 +1117,13 @@ bitflags! {
     }
 }
 
+impl MapPermission {
+    /// Whether a user area may have the permission under the W^X option
+    pub fn allowed(self) -> bool {
+        !(W_XOR_X.load(Ordering::Relaxed) && self.contains(MapPermission::W | MapPermission::X))
+    }
+}
+
 #[allow(unused)]
 pub fn remap_test() {
     let mut kernel_space = KERNEL_SPACE.exclusive_access();
@@ -12,14 +12,21 @@ mod frame_allocator;
 mod heap_allocator;
 mod memory_set;
 mod page_table;
+mod shm;
+mod swap;
 
 pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 pub use address::{StepByOne, VPNRange};
 pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker};
+pub use frame_allocator::{frame_alloc_contiguous, frame_stats, ContiguousFrameTracker, FrameStats};
 pub use memory_set::{remap_test, kernel_token};
-pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE};
-pub use page_table::{translated_byte_buffer, translated_refmut, translated_ref, translated_str, PageTableEntry};
+pub use memory_set::{set_w_xor_x, w_xor_x_from_bootargs};
+pub use memory_set::{MapFile, MapPermission, MemorySet, KERNEL_SPACE};
+pub use page_table::{translated_byte_buffer, translated_byte_buffer_mut, translated_refmut, translated_str, PageTableEntry};
+pub use page_table::{copy_from_user, copy_to_user};
 pub use page_table::{PTEFlags, PageTable, UserBuffer};
+pub use shm::{shm_get, shm_remove, shm_segment, ShmSegment, IPC_PRIVATE};
+pub use swap::init_swap;
 
 /// initiate heap allocator, frame allocator and kernel space
 pub fn init() {
@@ -27,3 +34,8 @@ pub fn init() {
     frame_allocator::init_frame_allocator();
     KERNEL_SPACE.exclusive_access().activate();
 }
+
+/// activate kernel space on another hart after the boot hart has called [`init`]
+pub fn init_hart() {
+    KERNEL_SPACE.exclusive_access().activate();
+}
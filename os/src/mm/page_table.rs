@@ -1,10 +1,16 @@
 //! Implementation of [`PageTableEntry`] and [`PageTable`].
 
 use super::{frame_alloc, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use super::swap::PinnedPage;
+use super::MapPermission;
+use crate::sbi::remote_sfence_vma;
+use crate::syscall::errno::Errno;
+use crate::task::{current_process, hart_id, online_harts};
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 bitflags! {
     /// page table entry flags
@@ -54,6 +60,10 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    /// Whether the entry maps a page instead of pointing to the next level
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && (self.readable() || self.writable() || self.executable())
+    }
 }
 
 /// page table structure
@@ -78,16 +88,22 @@ impl PageTable {
             frames: Vec::new(),
         }
     }
-    fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    /// Find the entry of `vpn` at `level`, where a leaf maps a 4 KiB page at level 0,
+    /// a 2 MiB megapage at level 1 and a 1 GiB gigapage at level 2.
+    /// The page tables on the way are created, but not inside a larger page.
+    fn find_pte_create(&mut self, vpn: VirtPageNum, level: usize) -> Option<&mut PageTableEntry> {
         let mut idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
         for (i, idx) in idxs.iter_mut().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
+            if i == 2 - level {
                 result = Some(pte);
                 break;
             }
+            if pte.is_leaf() {
+                return None;
+            }
             if !pte.is_valid() {
                 let frame = frame_alloc().unwrap();
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
@@ -97,14 +113,15 @@ impl PageTable {
         }
         result
     }
-    fn find_pte(&self, vpn: VirtPageNum) -> Option<&PageTableEntry> {
+    /// Find the entry mapping `vpn` together with its level
+    fn find_pte(&self, vpn: VirtPageNum) -> Option<(&PageTableEntry, usize)> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
-        let mut result: Option<&PageTableEntry> = None;
+        let mut result: Option<(&PageTableEntry, usize)> = None;
         for (i, idx) in idxs.iter().enumerate() {
             let pte = &ppn.get_pte_array()[*idx];
-            if i == 2 {
-                result = Some(pte);
+            if i == 2 || pte.is_leaf() {
+                result = Some((pte, 2 - i));
                 break;
             }
             if !pte.is_valid() {
@@ -116,21 +133,80 @@ impl PageTable {
     }
     #[allow(unused)]
     pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
-        assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
-        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        self.map_huge(vpn, ppn, flags, 0);
     }
     #[allow(unused)]
     pub fn unmap(&mut self, vpn: VirtPageNum) {
-        let pte = self.find_pte_create(vpn).unwrap();
+        self.unmap_huge(vpn, 0);
+    }
+    /// Map a page of `level` as in [`PageTable::find_pte_create`],
+    /// whose `vpn` and `ppn` must be aligned to its size
+    pub fn map_huge(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags, level: usize) {
+        let pages = 1usize << (9 * level);
+        assert!(
+            vpn.0 % pages == 0 && ppn.0 % pages == 0,
+            "vpn {:?} is not aligned to a page of level {}",
+            vpn,
+            level
+        );
+        let pte = self.find_pte_create(vpn, level).unwrap();
+        assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+    }
+    /// Unmap a page of `level` mapped by [`PageTable::map_huge`]
+    pub fn unmap_huge(&mut self, vpn: VirtPageNum, level: usize) {
+        let pte = self.find_pte_create(vpn, level).unwrap();
         assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
         *pte = PageTableEntry::empty();
     }
+    /// Point a mapped page at `ppn` with `flags` instead
+    pub fn remap(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        let pte = self.find_pte_create(vpn, 0).unwrap();
+        assert!(pte.is_valid(), "vpn {:?} is invalid before remapping", vpn);
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+    }
+    /// The valid leaf entry of a 4 KiB page as an atomic, since the harts set
+    /// its accessed and dirty bits behind the back of the page table
+    fn find_pte_atomic(&self, vpn: VirtPageNum) -> Option<&AtomicUsize> {
+        match self.find_pte(vpn) {
+            Some((pte, 0)) if pte.is_valid() => {
+                Some(unsafe { &*(pte as *const PageTableEntry as *const AtomicUsize) })
+            }
+            _ => None,
+        }
+    }
+    /// Change the flags of the page mapped at `vpn` to `f` of them at once, so that
+    /// no accessed or dirty bit set by another hart in between is lost.
+    /// Return the entry before, or `None` if `vpn` is not mapped.
+    pub fn update_flags(
+        &mut self,
+        vpn: VirtPageNum,
+        f: impl Fn(PTEFlags) -> PTEFlags,
+    ) -> Option<PageTableEntry> {
+        let update = |bits| {
+            let pte = PageTableEntry { bits };
+            Some(PageTableEntry::new(pte.ppn(), f(pte.flags()) | PTEFlags::V).bits)
+        };
+        self.find_pte_atomic(vpn)?
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, update)
+            .ok()
+            .map(|bits| PageTableEntry { bits })
+    }
+    /// Unmap the page mapped at `vpn` at once like [`PageTable::update_flags`],
+    /// return the entry before or `None` if `vpn` is not mapped
+    pub fn take(&mut self, vpn: VirtPageNum) -> Option<PageTableEntry> {
+        let bits = self.find_pte_atomic(vpn)?.swap(0, Ordering::AcqRel);
+        Some(PageTableEntry { bits })
+    }
+    /// The entry mapping `vpn`, as if it were a 4 KiB page inside a larger one
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        self.find_pte(vpn).copied()
+        self.find_pte(vpn).map(|(pte, level)| {
+            let offset = vpn.0 & ((1usize << (9 * level)) - 1);
+            PageTableEntry::new(PhysPageNum(pte.ppn().0 + offset), pte.flags())
+        })
     }
     pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
-        self.find_pte(va.clone().floor()).map(|pte| {
+        self.translate(va.clone().floor()).map(|pte| {
             //println!("translate_va:va = {:?}", va);
             let aligned_pa: PhysAddr = pte.ppn().into();
             //println!("translate_va:pa_align = {:?}", aligned_pa);
@@ -144,16 +220,89 @@ impl PageTable {
     }
 }
 
-/// translate a pointer to a mutable u8 Vec through page table
-pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&'static mut [u8]> {
+/// Flush the TLBs of the other harts running tasks, which may still cache the
+/// mappings this hart has just removed or restricted. This hart flushes its own
+/// TLB on the way back to user space.
+pub fn flush_tlb_others() {
+    let others = online_harts() & !(1 << hart_id());
+    if others != 0 {
+        remote_sfence_vma(others);
+    }
+}
+
+/// Check that `[ptr, ptr + len)` neither overflows nor leaves one half of
+/// the Sv39 address space, whose addresses would otherwise alias each other
+fn user_range(ptr: usize, len: usize) -> Result<(usize, usize), Errno> {
+    let end = ptr.checked_add(len).ok_or(Errno::EFAULT)?;
+    if len == 0 {
+        return Ok((ptr, end));
+    }
+    let (first, last) = ((ptr as isize) >> 38, ((end - 1) as isize) >> 38);
+    if first == last && (first == 0 || first == -1) {
+        Ok((ptr, end))
+    } else {
+        Err(Errno::EFAULT)
+    }
+}
+
+/// Translate a page of user space which is mapped with U and `flags`,
+/// or fail with EFAULT.
+/// A page which is untouched in a lazy area or shared copy-on-write is resolved
+/// like a page fault of the current process, so the page table must be the current one.
+fn translate_user_page(
+    page_table: &PageTable,
+    vpn: VirtPageNum,
+    flags: PTEFlags,
+) -> Result<PhysPageNum, Errno> {
+    let permitted = |pte: Option<PageTableEntry>| {
+        matches!(pte, Some(pte) if pte.is_valid() && pte.flags().contains(flags | PTEFlags::U))
+    };
+    if !permitted(page_table.translate(vpn)) {
+        let access = if flags.contains(PTEFlags::W) {
+            MapPermission::W
+        } else {
+            MapPermission::R
+        };
+        if current_process()
+            .with_memory_set(|memory_set| memory_set.handle_page_fault(vpn, access))
+            .is_err()
+            || !permitted(page_table.translate(vpn))
+        {
+            return Err(Errno::EFAULT);
+        }
+    }
+    Ok(page_table.translate(vpn).unwrap().ppn())
+}
+
+/// Translate `[ptr, ptr + len)` of user space to slices of physical memory,
+/// checking that every page spanned is mapped with U and `flags`.
+/// The pages are pinned as long as the buffer lives, since a read or write
+/// may block before it is done with them, and set dirty if they are to be written.
+fn translated_user_buffer(
+    token: usize,
+    ptr: usize,
+    len: usize,
+    flags: PTEFlags,
+) -> Result<UserBuffer, Errno> {
     let page_table = PageTable::from_token(token);
-    let mut start = ptr as usize;
-    let end = start + len;
+    let (mut start, end) = user_range(ptr, len)?;
     let mut v = Vec::new();
+    let mut pins = Vec::new();
     while start < end {
         let start_va = VirtAddr::from(start);
         let mut vpn = start_va.floor();
-        let ppn = page_table.translate(vpn).unwrap().ppn();
+        translate_user_page(&page_table, vpn, flags)?;
+        // pin the page before translating the next one may swap it out
+        let pin = current_process().inner_exclusive_access().memory_set.pin(vpn);
+        if let Some(pin) = pin {
+            // the kernel writes the frame directly, which leaves the dirty bit of the PTE clear
+            if flags.contains(PTEFlags::W) {
+                pin.set_dirty();
+            }
+            pins.push(pin);
+        }
+        // the page may have been swapped out before it was pinned
+        let ppn = translate_user_page(&page_table, vpn, flags)?;
         vpn.step();
         let mut end_va: VirtAddr = vpn.into();
         end_va = end_va.min(VirtAddr::from(end));
@@ -164,54 +313,95 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
         }
         start = end_va.into();
     }
-    v
+    Ok(UserBuffer { buffers: v, pins })
 }
 
-pub fn translated_str(token: usize, ptr: *const u8) -> String {
+/// Translate a readable user buffer to slices of physical memory
+pub fn translated_byte_buffer(
+    token: usize,
+    ptr: *const u8,
+    len: usize,
+) -> Result<UserBuffer, Errno> {
+    translated_user_buffer(token, ptr as usize, len, PTEFlags::R)
+}
+
+/// Translate a writable user buffer to slices of physical memory
+pub fn translated_byte_buffer_mut(
+    token: usize,
+    ptr: *mut u8,
+    len: usize,
+) -> Result<UserBuffer, Errno> {
+    translated_user_buffer(token, ptr as usize, len, PTEFlags::R | PTEFlags::W)
+}
+
+/// Copy a null-terminated string from user space
+pub fn translated_str(token: usize, ptr: *const u8) -> Result<String, Errno> {
     let page_table = PageTable::from_token(token);
     let mut string = String::new();
     let mut va = ptr as usize;
     loop {
-        let ch: u8 = *(page_table
-            .translate_va(VirtAddr::from(va))
-            .unwrap()
-            .get_mut());
-        if ch == 0 {
-            break;
-        } else {
+        user_range(va, 1)?;
+        let va_start = VirtAddr::from(va);
+        let ppn = translate_user_page(&page_table, va_start.floor(), PTEFlags::R)?;
+        // scan the rest of the page at once
+        for &ch in &ppn.get_bytes_array()[va_start.page_offset()..] {
+            if ch == 0 {
+                return Ok(string);
+            }
             string.push(ch as char);
             va += 1;
         }
     }
-    string
 }
 
-pub fn translated_ref<T>(token: usize, ptr: *const T) -> &'static T {
-    let page_table = PageTable::from_token(token);
-    page_table.translate_va(VirtAddr::from(ptr as usize)).unwrap().get_mut()
+/// Copy a value of `T` from user space
+pub fn copy_from_user<T: Copy>(token: usize, src: *const T) -> Result<T, Errno> {
+    let mut dst = core::mem::MaybeUninit::<T>::uninit();
+    let dst_ptr = dst.as_mut_ptr() as *mut u8;
+    let mut offset = 0;
+    for buffer in translated_byte_buffer(token, src as *const u8, core::mem::size_of::<T>())?.buffers {
+        unsafe {
+            core::ptr::copy_nonoverlapping(buffer.as_ptr(), dst_ptr.add(offset), buffer.len());
+        }
+        offset += buffer.len();
+    }
+    Ok(unsafe { dst.assume_init() })
 }
 
+/// Copy `src` to `dst` in user space, which may span several pages
+pub fn copy_to_user<T>(token: usize, dst: *mut T, src: &T) -> Result<(), Errno> {
+    let src = unsafe {
+        core::slice::from_raw_parts(src as *const T as *const u8, core::mem::size_of::<T>())
+    };
+    let mut offset = 0;
+    for buffer in translated_byte_buffer_mut(token, dst as *mut u8, src.len())?.buffers {
+        buffer.copy_from_slice(&src[offset..offset + buffer.len()]);
+        offset += buffer.len();
+    }
+    Ok(())
+}
+
+/// Translate a pointer into an address space the kernel has just built,
+/// which must be mapped and pinned, see [`MemorySet::populate`](super::MemorySet::populate).
+/// The page is marked dirty in its PTE, since the kernel writes the frame directly.
+/// Pointers from user space must go through [`copy_to_user`] instead.
 pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
-    //println!("into translated_refmut!");
-    let page_table = PageTable::from_token(token);
-    let va = ptr as usize;
-    //println!("translated_refmut: before translate_va");
-    page_table
-        .translate_va(VirtAddr::from(va))
-        .unwrap()
-        .get_mut()
+    let mut page_table = PageTable::from_token(token);
+    let va = VirtAddr::from(ptr as usize);
+    let vpn = va.floor();
+    let pte = page_table.translate(vpn).unwrap();
+    page_table.remap(vpn, pte.ppn(), pte.flags() | PTEFlags::A | PTEFlags::D);
+    page_table.translate_va(va).unwrap().get_mut()
 }
 
 /// An abstraction over a buffer passed from user space to kernel space
 pub struct UserBuffer {
     pub buffers: Vec<&'static mut [u8]>,
+    /// Keep the pages of the buffer from being swapped out
+    pins: Vec<PinnedPage>,
 }
 
 impl UserBuffer {
-    /// Constuct a UserBuffer
-    pub fn new(buffers: Vec<&'static mut [u8]>) -> Self {
-        Self { buffers }
-    }
     /// Get the length of a UserBuffer
     pub fn len(&self) -> usize {
         let mut total: usize = 0;
@@ -228,6 +418,7 @@ impl IntoIterator for UserBuffer {
     fn into_iter(self) -> Self::IntoIter {
         UserBufferIterator {
             buffers: self.buffers,
+            _pins: self.pins,
             current_buffer: 0,
             current_idx: 0,
         }
@@ -237,6 +428,7 @@ impl IntoIterator for UserBuffer {
 // An iterator over a UserBuffer
 pub struct UserBufferIterator {
     buffers: Vec<&'static mut [u8]>,
+    _pins: Vec<PinnedPage>,
     current_buffer: usize,
     current_idx: usize,
 }
@@ -0,0 +1,94 @@
+//! System V shared memory segments
+//!
+//! A segment owns a list of pages which every address space attaching it maps,
+//! so that processes can exchange data without copying it. The pages are freed
+//! once the segment is removed and no address space maps them any more.
+
+use super::frame_alloc;
+use super::swap::Page;
+use crate::config::PAGE_SIZE;
+use crate::sync::SpinLock;
+use crate::syscall::errno::Errno;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+/// The key which always creates a new segment
+pub const IPC_PRIVATE: usize = 0;
+
+/// A shared memory segment
+pub struct ShmSegment {
+    key: usize,
+    pub(super) pages: Vec<Arc<Page>>,
+}
+
+impl ShmSegment {
+    /// A zero-filled segment of at least `size` bytes, which is not in the table
+    /// if it backs an anonymous shared mapping
+    pub fn new(key: usize, size: usize) -> Result<Self, Errno> {
+        let pages = (0..(size + PAGE_SIZE - 1) / PAGE_SIZE)
+            .map(|_| frame_alloc().map(|frame| Page::new(frame, None)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Errno::ENOMEM)?;
+        Ok(Self { key, pages })
+    }
+    /// The size of the segment in bytes
+    pub fn size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+}
+
+/// The segments which can be attached, by id
+struct ShmTable {
+    segments: BTreeMap<usize, Arc<ShmSegment>>,
+    next_id: usize,
+}
+
+lazy_static! {
+    static ref SHM_TABLE: SpinLock<ShmTable> = SpinLock::new(ShmTable {
+        segments: BTreeMap::new(),
+        next_id: 0,
+    });
+}
+
+/// Get the id of the segment of `key`, which must be at least `size` bytes.
+/// If there is none, a zero-filled segment is created if `create`,
+/// and `exclusive` fails with EEXIST if there is one.
+pub fn shm_get(key: usize, size: usize, create: bool, exclusive: bool) -> Result<usize, Errno> {
+    let mut table = SHM_TABLE.exclusive_access();
+    if key != IPC_PRIVATE {
+        if let Some((&id, segment)) = table.segments.iter().find(|(_, s)| s.key == key) {
+            if create && exclusive {
+                return Err(Errno::EEXIST);
+            }
+            if size > segment.size() {
+                return Err(Errno::EINVAL);
+            }
+            return Ok(id);
+        }
+        if !create {
+            return Err(Errno::ENOENT);
+        }
+    }
+    if size == 0 {
+        return Err(Errno::EINVAL);
+    }
+    // allocating may swap out user pages, which never touches this table
+    let segment = ShmSegment::new(key, size)?;
+    let id = table.next_id;
+    table.next_id += 1;
+    table.segments.insert(id, Arc::new(segment));
+    Ok(id)
+}
+
+/// The segment of `id`
+pub fn shm_segment(id: usize) -> Option<Arc<ShmSegment>> {
+    SHM_TABLE.exclusive_access().segments.get(&id).cloned()
+}
+
+/// Remove the segment of `id`, which cannot be attached any more,
+/// but stays mapped wherever it is attached
+pub fn shm_remove(id: usize) -> bool {
+    SHM_TABLE.exclusive_access().segments.remove(&id).is_some()
+}
@@ -0,0 +1,345 @@
+//! Swapping of user pages to a file on easy-fs
+//!
+//! When [`frame_alloc`](super::frame_alloc) runs out of frames, a resident user
+//! page is chosen in clock order and its frame is freed. A page whose PTE has been
+//! accessed since the last scan gets a second chance, and a page which stays clean
+//! since it was swapped in is dropped without being written again. The PTE of a
+//! swapped-out page is cleared, so that the next access faults and
+//! [`MemorySet::handle_page_fault`](super::MemorySet::handle_page_fault) swaps it in.
+//!
+//! Swapping changes the PTE of another address space without its lock, so the owner
+//! of a page changes that PTE only while the page is locked, see [`Page::lock_pte`].
+
+use super::page_table::flush_tlb_others;
+use super::{frame_alloc, FrameTracker, PTEFlags, PageTable, PhysPageNum, VirtPageNum};
+use crate::config::{PAGE_SIZE, SWAP_SIZE};
+use crate::drivers::BLOCK_DEVICE;
+use crate::fs::ROOT_INODE;
+use crate::sync::SpinLock;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, FsError, BLOCK_SZ};
+use lazy_static::*;
+
+/// The name of the swap file in the root directory
+const SWAP_FILE_NAME: &str = ".swap";
+/// The blocks a page takes in the swap file
+const BLOCKS_PER_SLOT: usize = PAGE_SIZE / BLOCK_SZ;
+
+/// The swap file, divided into slots of a page each.
+/// Its blocks are read and written on the block device directly, polling it,
+/// since swapping may happen under locks where the filesystem cannot be waited for.
+struct SwapFile {
+    /// The blocks of the swap file, those of a slot being consecutive here
+    blocks: Vec<u32>,
+    /// The number of slots ever allocated
+    slots: usize,
+    recycled: Vec<usize>,
+}
+
+impl SwapFile {
+    fn new() -> Self {
+        let inode = ROOT_INODE
+            .find(SWAP_FILE_NAME)
+            .or_else(|_| ROOT_INODE.create(SWAP_FILE_NAME))
+            .expect("Cannot create the swap file!");
+        // whatever was swapped out before the last shutdown is gone
+        inode.clear();
+        // without room on the disk nothing is swapped out
+        let blocks = inode.reserve_blocks(SWAP_SIZE).unwrap_or_else(|err| {
+            println!("[kernel] Error {:?} when reserving the swap file", err);
+            Vec::new()
+        });
+        Self {
+            blocks,
+            slots: 0,
+            recycled: Vec::new(),
+        }
+    }
+    /// Allocate a slot, return `None` if the swap file is full
+    fn alloc(&mut self) -> Option<usize> {
+        self.recycled.pop().or_else(|| {
+            if self.slots == self.blocks.len() / BLOCKS_PER_SLOT {
+                return None;
+            }
+            self.slots += 1;
+            Some(self.slots - 1)
+        })
+    }
+    fn dealloc(&mut self, slot: usize) {
+        self.recycled.push(slot);
+    }
+    /// The blocks of `slot` on the block device
+    fn slot_blocks(&self, slot: usize) -> impl Iterator<Item = usize> + '_ {
+        self.blocks[slot * BLOCKS_PER_SLOT..(slot + 1) * BLOCKS_PER_SLOT]
+            .iter()
+            .map(|&block_id| block_id as usize)
+    }
+    /// Write the frame `ppn` to `slot`, return false if the disk fails
+    fn write(&self, slot: usize, ppn: PhysPageNum) -> bool {
+        self.slot_blocks(slot)
+            .zip(ppn.get_bytes_array().chunks(BLOCK_SZ))
+            .all(|(block_id, buf)| BLOCK_DEVICE.write_block(block_id, buf).is_ok())
+    }
+    fn read(&self, slot: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        for (block_id, buf) in self.slot_blocks(slot).zip(buf.chunks_mut(BLOCK_SZ)) {
+            BLOCK_DEVICE.read_block(block_id, buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Open the swap file, which is done at boot, since swapping cannot wait for the filesystem
+pub fn init_swap() {
+    lazy_static::initialize(&SWAP_FILE);
+}
+
+lazy_static! {
+    /// The swap file, which is opened by [`init_swap`]
+    static ref SWAP_FILE: SpinLock<SwapFile> = SpinLock::new(SwapFile::new());
+    /// The resident user pages which may be swapped out, in clock order
+    static ref CLOCK: SpinLock<VecDeque<Weak<Page>>> = SpinLock::new(VecDeque::new());
+}
+
+/// A page of a framed area, whose data is in a frame, in the swap file,
+/// or in both while it stays clean since it was swapped in
+pub struct Page {
+    inner: SpinLock<PageInner>,
+}
+
+struct PageInner {
+    frame: Option<FrameTracker>,
+    slot: Option<usize>,
+    /// The address space and the virtual page the page is mapped at, or `None`
+    /// if it is a kernel page or shared by several address spaces, which cannot be swapped out
+    owner: Option<(usize, VirtPageNum)>,
+    /// The number of [`PinnedPage`]s keeping the frame resident
+    pins: usize,
+    /// Whether the page has been written since it was last written back to its file,
+    /// as far as the write faults, the writes of the kernel and the dirty bits
+    /// of the PTEs dropped since then tell
+    dirty: bool,
+    /// Whether the page is in [`CLOCK`]
+    enlisted: bool,
+}
+
+impl Drop for PageInner {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            SWAP_FILE.exclusive_access().dealloc(slot);
+        }
+    }
+}
+
+/// The result of offering a page to be swapped out
+enum Scan {
+    SwappedOut,
+    /// The page stays in the clock
+    Kept,
+    /// The page leaves the clock since it cannot be swapped out
+    Unlisted,
+}
+
+impl Page {
+    /// A resident page in `frame`, mapped by `owner` if it may be swapped out
+    pub fn new(frame: FrameTracker, owner: Option<(usize, VirtPageNum)>) -> Arc<Self> {
+        let page = Arc::new(Self {
+            inner: SpinLock::new(PageInner {
+                frame: Some(frame),
+                slot: None,
+                owner,
+                pins: 0,
+                dirty: false,
+                enlisted: false,
+            }),
+        });
+        page.enlist(&mut page.inner.exclusive_access());
+        page
+    }
+    /// Put the page in the clock if it is resident and owned
+    fn enlist(self: &Arc<Self>, inner: &mut PageInner) {
+        if !inner.enlisted && inner.frame.is_some() && inner.owner.is_some() {
+            inner.enlisted = true;
+            CLOCK.exclusive_access().push_back(Arc::downgrade(self));
+        }
+    }
+    /// The frame of the page if it is resident
+    pub fn ppn(&self) -> Option<PhysPageNum> {
+        self.inner
+            .exclusive_access()
+            .frame
+            .as_ref()
+            .map(|frame| frame.ppn)
+    }
+    /// Change the address space mapping the page, `None` while it is shared
+    pub fn set_owner(self: &Arc<Self>, owner: Option<(usize, VirtPageNum)>) {
+        let mut inner = self.inner.exclusive_access();
+        inner.owner = owner;
+        self.enlist(&mut inner);
+    }
+    /// Forget the copy in the swap file and remember to write the page back to its file,
+    /// since the frame has been or is about to be written.
+    /// The copy of a page swapped out is kept, which is all there is of it until it is
+    /// swapped in, and the dirty bit of its next PTE tells whether it is written after that.
+    pub fn set_dirty(&self) {
+        let mut inner = self.inner.exclusive_access();
+        inner.dirty = true;
+        if inner.frame.is_none() {
+            return;
+        }
+        if let Some(slot) = inner.slot.take() {
+            SWAP_FILE.exclusive_access().dealloc(slot);
+        }
+    }
+    /// Whether the page has been set dirty since the last [`Page::take_dirty`]
+    pub fn is_dirty(&self) -> bool {
+        self.inner.exclusive_access().dirty
+    }
+    /// Whether the page has been set dirty since the last call
+    pub fn take_dirty(&self) -> bool {
+        core::mem::take(&mut self.inner.exclusive_access().dirty)
+    }
+    /// Copy the data of the page to `buf` of a page size without swapping it in,
+    /// which fails if the swap file cannot be read
+    pub fn read(&self, buf: &mut [u8]) -> Result<(), FsError> {
+        let inner = self.inner.exclusive_access();
+        match &inner.frame {
+            Some(frame) => {
+                buf.copy_from_slice(frame.ppn.get_bytes_array());
+                Ok(())
+            }
+            None => SWAP_FILE.exclusive_access().read(inner.slot.unwrap(), buf),
+        }
+    }
+    /// Read the page back from the swap file if it has been swapped out, and let `map`
+    /// map its frame while the page is locked, so that it is not swapped out before.
+    /// Return false if there is no frame left or the swap file cannot be read.
+    pub fn swap_in(self: &Arc<Self>, map: impl FnOnce(PhysPageNum)) -> bool {
+        let mut inner = self.inner.exclusive_access();
+        if inner.frame.is_none() {
+            // allocating may swap out other pages, but never this locked one
+            let frame = match frame_alloc() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            if SWAP_FILE
+                .exclusive_access()
+                .read(inner.slot.unwrap(), frame.ppn.get_bytes_array())
+                .is_err()
+            {
+                return false;
+            }
+            inner.frame = Some(frame);
+            self.enlist(&mut inner);
+        }
+        map(inner.frame.as_ref().unwrap().ppn);
+        true
+    }
+    /// Run `update`, which changes the PTE of the owner mapping the page,
+    /// while the page is locked, so that swapping it out does not change the PTE as well
+    pub fn lock_pte<T>(&self, update: impl FnOnce() -> T) -> T {
+        let _inner = self.inner.exclusive_access();
+        update()
+    }
+    /// Keep the page resident until the returned pin is dropped
+    pub fn pin(self: &Arc<Self>) -> PinnedPage {
+        self.inner.exclusive_access().pins += 1;
+        PinnedPage(Arc::downgrade(self))
+    }
+    fn try_swap_out(&self) -> Scan {
+        // a locked page is busy, such as being swapped in and mapped,
+        // and waiting for it may never end if this hart holds the lock
+        let mut inner = match self.inner.try_exclusive_access() {
+            Some(inner) => inner,
+            None => return Scan::Kept,
+        };
+        let (token, vpn, ppn) = match (&inner.frame, inner.owner) {
+            (Some(frame), Some((token, vpn))) => (token, vpn, frame.ppn),
+            _ => {
+                inner.enlisted = false;
+                return Scan::Unlisted;
+            }
+        };
+        // the owner changes the PTE only while the page is locked, see `Page::lock_pte`
+        let mut page_table = PageTable::from_token(token);
+        let pte = match page_table.translate(vpn) {
+            // a page which is not mapped at its frame yet is being faulted in right now
+            Some(pte) if pte.is_valid() && pte.ppn() == ppn && inner.pins == 0 => pte,
+            _ => return Scan::Kept,
+        };
+        if pte.flags().contains(PTEFlags::A) {
+            page_table.update_flags(vpn, |flags| flags - PTEFlags::A);
+            return Scan::Kept;
+        }
+        // the page is unmapped before it is written out, so that no hart writes it
+        // meanwhile, and the dirty bit set until then goes away with the PTE
+        let pte = page_table.take(vpn).unwrap();
+        // the owner may run on another hart
+        flush_tlb_others();
+        if pte.flags().contains(PTEFlags::D) {
+            inner.dirty = true;
+        }
+        if pte.flags().contains(PTEFlags::D) || inner.slot.is_none() {
+            let mut swap_file = SWAP_FILE.exclusive_access();
+            let slot = inner.slot.or_else(|| swap_file.alloc());
+            if !slot.map_or(false, |slot| swap_file.write(slot, ppn)) {
+                if let (Some(slot), None) = (slot, inner.slot) {
+                    swap_file.dealloc(slot);
+                }
+                page_table.map(vpn, ppn, pte.flags());
+                return Scan::Kept;
+            }
+            inner.slot = slot;
+        }
+        inner.frame = None;
+        inner.enlisted = false;
+        Scan::SwappedOut
+    }
+}
+
+/// A page which cannot be swapped out while the kernel refers to its frame,
+/// such as the target of a blocking read
+pub struct PinnedPage(Weak<Page>);
+
+impl PinnedPage {
+    /// Set the page dirty, see [`Page::set_dirty`]
+    pub fn set_dirty(&self) {
+        if let Some(page) = self.0.upgrade() {
+            page.set_dirty();
+        }
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        if let Some(page) = self.0.upgrade() {
+            page.inner.exclusive_access().pins -= 1;
+        }
+    }
+}
+
+/// Swap out a user page chosen in clock order to free its frame,
+/// return false if there is no page to swap out
+pub fn swap_out_one() -> bool {
+    // every page gets at most one second chance
+    let scans = CLOCK.exclusive_access().len() * 2;
+    for _ in 0..scans {
+        // the clock is not locked while a page is scanned,
+        // since a page locked elsewhere may be enlisting itself
+        let page = match CLOCK.exclusive_access().pop_front() {
+            Some(page) => page.upgrade(),
+            None => return false,
+        };
+        let page = match page {
+            Some(page) => page,
+            None => continue,
+        };
+        match page.try_swap_out() {
+            Scan::SwappedOut => return true,
+            Scan::Kept => CLOCK.exclusive_access().push_back(Arc::downgrade(&page)),
+            Scan::Unlisted => {}
+        }
+    }
+    false
+}
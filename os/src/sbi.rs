@@ -6,6 +6,12 @@ const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
 const SBI_SHUTDOWN: usize = 8;
+/// The extension of SBI v0.2 to start and stop harts
+const SBI_EXT_HSM: usize = 0x48534D;
+const SBI_HSM_HART_START: usize = 0;
+/// The extension of SBI v0.2 to run fences on other harts
+const SBI_EXT_RFENCE: usize = 0x52464E43;
+const SBI_RFENCE_REMOTE_SFENCE_VMA: usize = 1;
 
 #[inline(always)]
 /// general sbi call
@@ -23,6 +29,31 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
+#[inline(always)]
+/// sbi call of a function `fid` in the extension `eid`, returning the error and the value
+fn sbi_call_ext(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> (isize, usize) {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x13") arg3,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    (error, value)
+}
+
 /// use sbi call to set timer
 pub fn set_timer(timer: usize) {
     sbi_call(SBI_SET_TIMER, timer, 0, 0);
@@ -38,6 +69,32 @@ pub fn console_getchar() -> usize {
     sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0)
 }
 
+/// use sbi call to start the hart `hartid` at the physical address `start_addr`
+/// with `a0` = `hartid` and `a1` = `opaque`, return whether it is starting
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> bool {
+    sbi_call_ext(
+        SBI_EXT_HSM,
+        SBI_HSM_HART_START,
+        hartid,
+        start_addr,
+        opaque,
+        0,
+    )
+    .0 == 0
+}
+
+/// use sbi call to flush the whole TLB of the harts in `hart_mask`, where hart `n` is bit `n`
+pub fn remote_sfence_vma(hart_mask: usize) {
+    sbi_call_ext(
+        SBI_EXT_RFENCE,
+        SBI_RFENCE_REMOTE_SFENCE_VMA,
+        hart_mask,
+        0,
+        0,
+        usize::MAX,
+    );
+}
+
 /// use sbi call to shutdown the kernel
 pub fn shutdown() -> ! {
     sbi_call(SBI_SHUTDOWN, 0, 0, 0);
@@ -1,9 +1,11 @@
-use crate::sync::{Mutex, UPSafeCell};
-use crate::task::{add_task, block_current_and_run_next, current_task, TaskControlBlock};
+use crate::sync::{Mutex, SpinLock};
+use crate::syscall::errno::Errno;
+use crate::task::{block_current_and_run_next, current_signal_pending, current_task};
+use crate::task::{wakeup_task, TaskControlBlock};
 use alloc::{collections::VecDeque, sync::Arc};
 
 pub struct Condvar {
-    pub inner: UPSafeCell<CondvarInner>,
+    pub inner: SpinLock<CondvarInner>,
 }
 
 pub struct CondvarInner {
@@ -13,27 +15,47 @@ pub struct CondvarInner {
 impl Condvar {
     pub fn new() -> Self {
         Self {
-            inner: unsafe {
-                UPSafeCell::new(CondvarInner {
-                    wait_queue: VecDeque::new(),
-                })
-            },
+            inner: SpinLock::new(CondvarInner {
+                wait_queue: VecDeque::new(),
+            }),
         }
     }
 
     pub fn signal(&self) {
         let mut inner = self.inner.exclusive_access();
         if let Some(task) = inner.wait_queue.pop_front() {
-            add_task(task);
+            wakeup_task(task);
         }
     }
 
-    pub fn wait(&self, mutex: Arc<dyn Mutex>) {
-        mutex.unlock();
+    /// Unlock `mutex` and wait to be signaled, then lock it again.
+    /// Fail with EINTR if a signal to handle arrives meanwhile, still locking it again.
+    pub fn wait(&self, mutex: Arc<dyn Mutex>) -> Result<(), Errno> {
+        let task = current_task().unwrap();
+        // queue up before unlocking, or a signal on another hart in between is lost
         let mut inner = self.inner.exclusive_access();
-        inner.wait_queue.push_back(current_task().unwrap());
+        inner.wait_queue.push_back(Arc::clone(&task));
         drop(inner);
-        block_current_and_run_next();
-        mutex.lock();
+        mutex.unlock();
+        let result = loop {
+            block_current_and_run_next();
+            let interrupted = current_signal_pending();
+            let mut inner = self.inner.exclusive_access();
+            // signal takes the task it wakes up from the queue
+            match inner
+                .wait_queue
+                .iter()
+                .position(|waiting| Arc::ptr_eq(waiting, &task))
+            {
+                None => break Ok(()),
+                Some(idx) if interrupted => {
+                    inner.wait_queue.remove(idx);
+                    break Err(Errno::EINTR);
+                }
+                Some(_) => {}
+            }
+        };
+        mutex.lock_uninterruptible();
+        result
     }
 }
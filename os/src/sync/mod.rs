@@ -3,9 +3,9 @@
 mod condvar;
 mod mutex;
 mod semaphore;
-mod up;
+mod spin;
 
 pub use condvar::Condvar;
 pub use mutex::{Mutex, MutexBlocking, MutexSpin};
 pub use semaphore::Semaphore;
-pub use up::UPSafeCell;
+pub use spin::{holds_spinlock, SpinLock, SpinLockGuard};
@@ -1,41 +1,67 @@
-use super::UPSafeCell;
-use crate::task::TaskControlBlock;
-use crate::task::{add_task, current_task};
+use super::SpinLock;
+use crate::syscall::errno::Errno;
 use crate::task::{block_current_and_run_next, suspend_current_and_run_next};
+use crate::task::{current_exiting, current_signal_pending};
+use crate::task::{current_task, wakeup_task, TaskControlBlock};
 use alloc::{collections::VecDeque, sync::Arc};
 
 pub trait Mutex: Sync + Send {
-    fn lock(&self);
+    /// Lock the mutex, or fail with EINTR if a signal to handle arrives while waiting for it
+    fn lock(&self) -> Result<(), Errno>;
+    /// Lock the mutex whatever signals arrive, as a condition variable takes it back,
+    /// unless the process exits meanwhile
+    fn lock_uninterruptible(&self);
     fn unlock(&self);
     fn get_next_queue_id(&self) -> isize;
 }
 
+/// Whether a wait for a mutex must give up, which one that is not `interruptible`
+/// only does when the process is exiting, since the holder may have left already
+fn interrupted(interruptible: bool) -> bool {
+    if interruptible {
+        current_signal_pending()
+    } else {
+        current_exiting()
+    }
+}
+
 pub struct MutexSpin {
-    locked: UPSafeCell<bool>,
+    locked: SpinLock<bool>,
 }
 
 impl MutexSpin {
     pub fn new() -> Self {
         Self {
-            locked: unsafe { UPSafeCell::new(false) },
+            locked: SpinLock::new(false),
         }
     }
-}
 
-impl Mutex for MutexSpin {
-    fn lock(&self) {
+    fn acquire(&self, interruptible: bool) -> Result<(), Errno> {
         loop {
             let mut locked = self.locked.exclusive_access();
             if *locked {
                 drop(locked);
+                if interrupted(interruptible) {
+                    return Err(Errno::EINTR);
+                }
                 suspend_current_and_run_next();
                 continue;
             } else {
                 *locked = true;
-                return;
+                return Ok(());
             }
         }
     }
+}
+
+impl Mutex for MutexSpin {
+    fn lock(&self) -> Result<(), Errno> {
+        self.acquire(true)
+    }
+
+    fn lock_uninterruptible(&self) {
+        let _ = self.acquire(false);
+    }
 
     fn unlock(&self) {
         let mut locked = self.locked.exclusive_access();
@@ -48,7 +74,7 @@ impl Mutex for MutexSpin {
 }
 
 pub struct MutexBlocking {
-    inner: UPSafeCell<MutexBlockingInner>,
+    inner: SpinLock<MutexBlockingInner>,
 }
 
 pub struct MutexBlockingInner {
@@ -59,33 +85,61 @@ pub struct MutexBlockingInner {
 impl MutexBlocking {
     pub fn new() -> Self {
         Self {
-            inner: unsafe {
-                UPSafeCell::new(MutexBlockingInner {
-                    locked: false,
-                    wait_queue: VecDeque::new(),
-                })
-            },
+            inner: SpinLock::new(MutexBlockingInner {
+                locked: false,
+                wait_queue: VecDeque::new(),
+            }),
         }
     }
 }
 
-impl Mutex for MutexBlocking {
-    fn lock(&self) {
+impl MutexBlocking {
+    fn acquire(&self, interruptible: bool) -> Result<(), Errno> {
+        let task = current_task().unwrap();
         let mut mutex_inner = self.inner.exclusive_access();
-        if mutex_inner.locked {
-            mutex_inner.wait_queue.push_back(current_task().unwrap());
+        if !mutex_inner.locked {
+            mutex_inner.locked = true;
+            return Ok(());
+        }
+        mutex_inner.wait_queue.push_back(Arc::clone(&task));
+        loop {
             drop(mutex_inner);
             block_current_and_run_next();
-        } else {
-            mutex_inner.locked = true;
+            // the signals are checked before taking this lock, which sys_mutex_unlock
+            // takes under the lock of the process
+            let interrupted = interrupted(interruptible);
+            mutex_inner = self.inner.exclusive_access();
+            // unlock hands the mutex over to the task it takes from the queue
+            match mutex_inner
+                .wait_queue
+                .iter()
+                .position(|waiting| Arc::ptr_eq(waiting, &task))
+            {
+                None => return Ok(()),
+                Some(idx) if interrupted => {
+                    mutex_inner.wait_queue.remove(idx);
+                    return Err(Errno::EINTR);
+                }
+                Some(_) => {}
+            }
         }
     }
+}
+
+impl Mutex for MutexBlocking {
+    fn lock(&self) -> Result<(), Errno> {
+        self.acquire(true)
+    }
+
+    fn lock_uninterruptible(&self) {
+        let _ = self.acquire(false);
+    }
 
     fn unlock(&self) {
         let mut mutex_inner = self.inner.exclusive_access();
         assert!(mutex_inner.locked);
         if let Some(waking_task) = mutex_inner.wait_queue.pop_front() {
-            add_task(waking_task);
+            wakeup_task(waking_task);
         } else {
             mutex_inner.locked = false;
         }
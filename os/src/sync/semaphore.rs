@@ -1,9 +1,11 @@
-use crate::sync::UPSafeCell;
-use crate::task::{add_task, block_current_and_run_next, current_task, TaskControlBlock};
+use crate::sync::SpinLock;
+use crate::syscall::errno::Errno;
+use crate::task::{block_current_and_run_next, current_signal_pending, current_task};
+use crate::task::{wakeup_task, TaskControlBlock};
 use alloc::{collections::VecDeque, sync::Arc};
 
 pub struct Semaphore {
-    pub inner: UPSafeCell<SemaphoreInner>,
+    pub inner: SpinLock<SemaphoreInner>,
 }
 
 pub struct SemaphoreInner {
@@ -26,12 +28,10 @@ impl Semaphore {
     }
     pub fn new(res_count: usize) -> Self {
         Self {
-            inner: unsafe {
-                UPSafeCell::new(SemaphoreInner {
-                    count: res_count as isize,
-                    wait_queue: VecDeque::new(),
-                })
-            },
+            inner: SpinLock::new(SemaphoreInner {
+                count: res_count as isize,
+                wait_queue: VecDeque::new(),
+            }),
         }
     }
 
@@ -40,18 +40,42 @@ impl Semaphore {
         inner.count += 1;
         if inner.count <= 0 {
             if let Some(task) = inner.wait_queue.pop_front() {
-                add_task(task);
+                wakeup_task(task);
             }
         }
     }
 
-    pub fn down(&self) {
+    /// Take a unit of the resource, or fail with EINTR if a signal to handle
+    /// arrives while waiting for it
+    pub fn down(&self) -> Result<(), Errno> {
+        let task = current_task().unwrap();
         let mut inner = self.inner.exclusive_access();
         inner.count -= 1;
-        if inner.count < 0 {
-            inner.wait_queue.push_back(current_task().unwrap());
+        if inner.count >= 0 {
+            return Ok(());
+        }
+        inner.wait_queue.push_back(Arc::clone(&task));
+        loop {
             drop(inner);
             block_current_and_run_next();
+            // the signals are checked before taking this lock, which sys_semaphore_up
+            // takes under the lock of the process
+            let interrupted = current_signal_pending();
+            inner = self.inner.exclusive_access();
+            // up hands a unit over to the task it takes from the queue
+            match inner
+                .wait_queue
+                .iter()
+                .position(|waiting| Arc::ptr_eq(waiting, &task))
+            {
+                None => return Ok(()),
+                Some(idx) if interrupted => {
+                    inner.wait_queue.remove(idx);
+                    inner.count += 1;
+                    return Err(Errno::EINTR);
+                }
+                Some(_) => {}
+            }
         }
     }
 }
@@ -0,0 +1,138 @@
+//! Spinlocks shared between harts
+
+use crate::config::MAX_HARTS;
+use crate::task::hart_id;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+
+/// The owner of a free lock
+const NO_HART: usize = usize::MAX;
+
+/// Wrap a static data structure inside it so that harts take turns
+/// to access it without any `unsafe`.
+///
+/// Interrupts stay disabled on a hart holding a lock, so that a trap
+/// on that hart never spins on the lock the interrupted code holds.
+///
+/// In order to get mutable reference of inner data, call
+/// `exclusive_access`.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    /// The hart holding the lock, to catch a hart taking it twice
+    owner: AtomicUsize,
+    /// inner data
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(NO_HART),
+            inner: UnsafeCell::new(value),
+        }
+    }
+    /// Spin until the data is free.
+    /// Panic if this hart holds it already, which would spin forever.
+    pub fn exclusive_access(&self) -> SpinLockGuard<'_, T> {
+        push_off();
+        let hart = hart_id();
+        if self.owner.load(Ordering::Relaxed) == hart {
+            panic!("hart {} takes a lock it holds", hart);
+        }
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        self.owner.store(hart, Ordering::Relaxed);
+        SpinLockGuard { lock: self }
+    }
+    /// Take the data if it is free, or return `None` at once
+    /// if it is held by any hart, this one included
+    pub fn try_exclusive_access(&self) -> Option<SpinLockGuard<'_, T>> {
+        push_off();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            pop_off();
+            return None;
+        }
+        self.owner.store(hart_id(), Ordering::Relaxed);
+        Some(SpinLockGuard { lock: self })
+    }
+}
+
+/// The data of a [`SpinLock`] held by this hart until the guard drops
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.store(NO_HART, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+        pop_off();
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+/// How many locks each hart holds
+static NOFF: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+/// Whether interrupts were enabled on each hart before it took its first lock
+static INTENA: [AtomicBool; MAX_HARTS] = [FALSE; MAX_HARTS];
+
+/// Whether this hart holds any lock, so that the running task must not switch out
+pub fn holds_spinlock() -> bool {
+    NOFF[hart_id()].load(Ordering::Relaxed) > 0
+}
+
+/// Disable interrupts on this hart, which nests with the locks it holds
+fn push_off() {
+    let enabled = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    let hart = hart_id();
+    if NOFF[hart].fetch_add(1, Ordering::Relaxed) == 0 {
+        INTENA[hart].store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Enable interrupts on this hart again when it releases its last lock,
+/// if they were enabled before
+fn pop_off() {
+    let hart = hart_id();
+    if NOFF[hart].fetch_sub(1, Ordering::Relaxed) == 1 && INTENA[hart].load(Ordering::Relaxed) {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+}
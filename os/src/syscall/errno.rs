@@ -0,0 +1,79 @@
+//! Error numbers returned by syscalls
+//!
+//! Every `sys_*` function returns a [`SysResult`], and [`super::syscall()`]
+//! hands `Err(errno)` back to user space as `-errno` like Linux does.
+
+use easy_fs::FsError;
+
+/// The Linux error numbers used by this kernel
+#[allow(clippy::upper_case_acronyms)]
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// Operation not permitted
+    EPERM = 1,
+    /// No such file or directory
+    ENOENT = 2,
+    /// No such process
+    ESRCH = 3,
+    /// Interrupted system call
+    EINTR = 4,
+    /// I/O error
+    EIO = 5,
+    /// Exec format error
+    ENOEXEC = 8,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// No child processes
+    ECHILD = 10,
+    /// Try again
+    EAGAIN = 11,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Permission denied
+    EACCES = 13,
+    /// Bad address
+    EFAULT = 14,
+    /// File exists
+    EEXIST = 17,
+    /// No such device
+    ENODEV = 19,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// No space left on device
+    ENOSPC = 28,
+    /// Result too large
+    ERANGE = 34,
+    /// Resource deadlock would occur
+    EDEADLK = 35,
+    /// File name too long
+    ENAMETOOLONG = 36,
+    /// Function not implemented
+    ENOSYS = 38,
+    /// Directory not empty
+    ENOTEMPTY = 39,
+}
+
+/// The result of a syscall, which is returned to user space as `-errno` on error
+pub type SysResult = Result<usize, Errno>;
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::Corrupted | FsError::Io => Errno::EIO,
+            FsError::Exists => Errno::EEXIST,
+            FsError::NotDir => Errno::ENOTDIR,
+            FsError::IsDir => Errno::EISDIR,
+            FsError::InvalidName => Errno::EINVAL,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::NameTooLong => Errno::ENAMETOOLONG,
+            FsError::NotEmpty => Errno::ENOTEMPTY,
+            FsError::Busy => Errno::EAGAIN,
+        }
+    }
+}
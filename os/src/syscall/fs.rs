@@ -1,79 +1,110 @@
 //! File and filesystem-related syscalls
 
+use crate::fs::find_inode_at;
+use crate::fs::join_path;
+use crate::fs::link_at;
+use crate::fs::make_dir_at;
 use crate::fs::make_pipe;
-use crate::fs::open_file;
+use crate::fs::open_file_at;
 use crate::fs::OpenFlags;
 use crate::fs::Stat;
+use crate::fs::unlink_at;
+use crate::mm::copy_to_user;
 use crate::mm::translated_byte_buffer;
-use crate::mm::translated_refmut;
+use crate::mm::translated_byte_buffer_mut;
 use crate::mm::translated_str;
-use crate::mm::UserBuffer;
 use crate::task::current_process;
 use crate::task::current_user_token;
+use super::errno::{Errno, SysResult};
 use alloc::sync::Arc;
+use easy_fs::Inode;
 
-pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
-    let token = current_user_token();
+/// Special dirfd meaning the current working directory
+pub const AT_FDCWD: isize = -100;
+/// Flag of unlinkat to remove a directory instead
+const AT_REMOVEDIR: usize = 0x200;
+
+/// The directory which `dirfd` refers to,
+/// or the current working directory for `AT_FDCWD`
+pub fn dir_inode(dirfd: isize) -> Result<Arc<Inode>, Errno> {
     let process = current_process();
     let inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
-        // release current process TCB manually to avoid multi-borrow
-        drop(inner);
-        file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+    let inode = if dirfd == AT_FDCWD {
+        inner.cwd.clone()
     } else {
-        -1
+        let file = usize::try_from(dirfd)
+            .ok()
+            .and_then(|fd| inner.fd_table.get(fd)?.clone())
+            .ok_or(Errno::EBADF)?;
+        file.inode().ok_or(Errno::ENOTDIR)?
+    };
+    // release current process PCB before the disk is touched
+    drop(inner);
+    if inode.is_dir() {
+        Ok(inode)
+    } else {
+        Err(Errno::ENOTDIR)
     }
 }
 
-pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
+pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> SysResult {
     let token = current_user_token();
     let process = current_process();
     let inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return Err(Errno::EBADF),
+    };
+    if !file.writable() {
+        return Err(Errno::EBADF);
     }
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
-        // release current process TCB manually to avoid multi-borrow
-        drop(inner);
-        file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
-    } else {
-        -1
+    // release current process TCB manually to avoid multi-borrow
+    drop(inner);
+    file.write(translated_byte_buffer(token, buf, len)?)
+}
+
+pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> SysResult {
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return Err(Errno::EBADF),
+    };
+    if !file.readable() {
+        return Err(Errno::EBADF);
     }
+    // release current process TCB manually to avoid multi-borrow
+    drop(inner);
+    file.read(translated_byte_buffer_mut(token, buf, len)?)
 }
 
-pub fn sys_open(path: *const u8, flags: u32) -> isize {
+pub fn sys_openat(dirfd: isize, path: *const u8, flags: u32) -> SysResult {
     let process = current_process();
     let token = current_user_token();
-    let path = translated_str(token, path);
-    if let Some(inode) = open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
-        let mut inner = process.inner_exclusive_access();
-        let fd = inner.alloc_fd();
-        inner.fd_table[fd] = Some(inode);
-        fd as isize
-    } else {
-        -1
-    }
+    let path = translated_str(token, path)?;
+    let flags = OpenFlags::from_bits(flags).ok_or(Errno::EINVAL)?;
+    let dir = dir_inode(dirfd)?;
+    let inode = open_file_at(&dir, path.as_str(), flags)?;
+    let mut inner = process.inner_exclusive_access();
+    let fd = inner.alloc_fd();
+    inner.fd_table[fd] = Some(inode);
+    Ok(fd)
 }
 
-pub fn sys_close(fd: usize) -> isize {
+pub fn sys_close(fd: usize) -> SysResult {
     let process = current_process();
     let mut inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if inner.fd_table[fd].is_none() {
-        return -1;
+    match inner.fd_table.get_mut(fd) {
+        Some(file) if file.is_some() => {
+            file.take();
+            Ok(0)
+        }
+        _ => Err(Errno::EBADF),
     }
-    inner.fd_table[fd].take();
-    0
 }
 
-pub fn sys_pipe(pipe: *mut usize) -> isize {
+pub fn sys_pipe(pipe: *mut usize) -> SysResult {
     let process = current_process();
     let token = current_user_token();
     let mut inner = process.inner_exclusive_access();
@@ -82,33 +113,107 @@ pub fn sys_pipe(pipe: *mut usize) -> isize {
     inner.fd_table[read_fd] = Some(pipe_read);
     let write_fd = inner.alloc_fd();
     inner.fd_table[write_fd] = Some(pipe_write);
-    *translated_refmut(token, pipe) = read_fd;
-    *translated_refmut(token, unsafe { pipe.add(1) }) = write_fd;
-    0
+    // release current process TCB manually to avoid multi-borrow
+    drop(inner);
+    if let Err(errno) = copy_to_user(token, pipe as *mut [usize; 2], &[read_fd, write_fd]) {
+        let mut inner = process.inner_exclusive_access();
+        inner.fd_table[read_fd].take();
+        inner.fd_table[write_fd].take();
+        return Err(errno);
+    }
+    Ok(0)
 }
 
-pub fn sys_dup(fd: usize) -> isize {
+pub fn sys_dup(fd: usize) -> SysResult {
     let process = current_process();
     let mut inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if inner.fd_table[fd].is_none() {
-        return -1;
-    }
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => Arc::clone(file),
+        _ => return Err(Errno::EBADF),
+    };
     let new_fd = inner.alloc_fd();
-    inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
-    new_fd as isize
+    inner.fd_table[new_fd] = Some(file);
+    Ok(new_fd)
+}
+
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> SysResult {
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let inode = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.inode(),
+        _ => return Err(Errno::EBADF),
+    };
+    drop(inner);
+    // pipes and stdio have no inode to describe
+    let inode = inode.ok_or(Errno::EINVAL)?;
+    copy_to_user(token, st, &Stat::from_inode(&inode))?;
+    Ok(0)
+}
+
+pub fn sys_linkat(
+    old_dirfd: isize,
+    old_path: *const u8,
+    new_dirfd: isize,
+    new_path: *const u8,
+) -> SysResult {
+    let token = current_user_token();
+    let old_path = translated_str(token, old_path)?;
+    let new_path = translated_str(token, new_path)?;
+    let old_dir = dir_inode(old_dirfd)?;
+    let new_dir = dir_inode(new_dirfd)?;
+    link_at(&old_dir, old_path.as_str(), &new_dir, new_path.as_str())?;
+    Ok(0)
+}
+
+pub fn sys_unlinkat(dirfd: isize, path: *const u8, flags: usize) -> SysResult {
+    let token = current_user_token();
+    let path = translated_str(token, path)?;
+    if flags & !AT_REMOVEDIR != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let dir = dir_inode(dirfd)?;
+    unlink_at(&dir, path.as_str(), flags & AT_REMOVEDIR != 0)?;
+    Ok(0)
 }
 
-pub fn sys_fstat(_fd: usize, _st: *mut Stat) -> isize {
-    -1
+pub fn sys_mkdirat(dirfd: isize, path: *const u8) -> SysResult {
+    let token = current_user_token();
+    let path = translated_str(token, path)?;
+    let dir = dir_inode(dirfd)?;
+    make_dir_at(&dir, path.as_str())?;
+    Ok(0)
 }
 
-pub fn sys_linkat(_old_name: *const u8, _new_name: *const u8) -> isize {
-    -1
+pub fn sys_chdir(path: *const u8) -> SysResult {
+    let token = current_user_token();
+    let path = translated_str(token, path)?;
+    let dir = dir_inode(AT_FDCWD)?;
+    let cwd = find_inode_at(&dir, path.as_str())?;
+    if !cwd.is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    inner.cwd_path = join_path(&inner.cwd_path, path.as_str());
+    inner.cwd = cwd;
+    Ok(0)
 }
 
-pub fn sys_unlinkat(_name: *const u8) -> isize {
-    -1
+/// Copy the null-terminated current working directory into `buf`,
+/// return its length including the terminator
+pub fn sys_getcwd(buf: *mut u8, len: usize) -> SysResult {
+    let token = current_user_token();
+    let process = current_process();
+    let mut cwd = process.inner_exclusive_access().cwd_path.clone();
+    cwd.push('\0');
+    if len < cwd.len() {
+        return Err(Errno::ERANGE);
+    }
+    let mut copied = 0;
+    for slice in translated_byte_buffer_mut(token, buf, cwd.len())?.buffers {
+        slice.copy_from_slice(&cwd.as_bytes()[copied..copied + slice.len()]);
+        copied += slice.len();
+    }
+    Ok(cwd.len())
 }
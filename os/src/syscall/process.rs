@@ -1,16 +1,43 @@
 //! Process management syscalls
 
-use crate::config::MAX_SYSCALL_NUM;
-use crate::fs::{open_file, OpenFlags};
-use crate::mm::{translated_ref, translated_refmut, translated_str, PageTable, VirtAddr};
+use crate::config::{BIG_STRIDE, MAX_SYSCALL_NUM, PAGE_SIZE, SHM_END, SHM_START, USER_SPACE_END};
+use super::errno::{Errno, SysResult};
+use super::fs::{dir_inode, AT_FDCWD};
+use crate::fs::find_inode_at;
+use crate::mm::{
+    copy_from_user, copy_to_user, shm_get, shm_remove, shm_segment, translated_str, MapFile,
+    MapPermission, PageTable, ShmSegment, VirtAddr, IPC_PRIVATE,
+};
 use crate::task::{
-    current_process, current_task, current_user_token, exit_current_and_run_next,
-    suspend_current_and_run_next, TaskStatus,
+    block_current_and_run_next, current_process, current_task, current_user_token,
+    exit_current_and_run_next, suspend_current_and_run_next, TaskStatus,
 };
-use crate::timer::get_time_us;
+use crate::timer::{get_time_ms, get_time_us};
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use easy_fs::Inode;
+
+/// Flag of waitpid and waittid to fail with EAGAIN instead of blocking
+pub const WNOHANG: usize = 1;
+/// Flag of shmget to create the segment if there is none
+const IPC_CREAT: usize = 0o1000;
+/// Flag of shmget to fail if the segment exists
+const IPC_EXCL: usize = 0o2000;
+/// Flag of shmat to attach the segment read-only
+const SHM_RDONLY: usize = 0o10000;
+/// Command of shmctl to remove the segment
+const IPC_RMID: usize = 0;
+/// Flag of mmap to share the mapping with forked children and write it back to the file
+const MAP_SHARED: usize = 0x01;
+/// Flag of mmap to copy the mapping on write
+const MAP_PRIVATE: usize = 0x02;
+/// Flag of mmap to map zero-filled memory instead of a file
+const MAP_ANONYMOUS: usize = 0x20;
+/// Flags of msync, which always writes back synchronously
+const MS_ASYNC: usize = 1;
+const MS_INVALIDATE: usize = 2;
+const MS_SYNC: usize = 4;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -19,6 +46,7 @@ pub struct TimeVal {
     pub usec: usize,
 }
 
+#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct TaskInfo {
     pub status: TaskStatus,
@@ -33,19 +61,25 @@ pub fn sys_exit(exit_code: i32) -> ! {
 }
 
 /// current task gives up resources for other tasks
-pub fn sys_yield() -> isize {
+pub fn sys_yield() -> SysResult {
     suspend_current_and_run_next();
-    0
+    Ok(0)
 }
 
-pub fn sys_getpid() -> isize {
-    current_task().unwrap().process.upgrade().unwrap().getpid() as isize
+pub fn sys_getpid() -> SysResult {
+    Ok(current_task().unwrap().process.upgrade().unwrap().getpid())
 }
 
 /// Syscall Fork which returns 0 for child process and child_pid for parent process
-pub fn sys_fork() -> isize {
+pub fn sys_fork() -> SysResult {
     let current_process = current_process();
-    let new_process = current_process.fork();
+    // the shared file mappings are loaded with the parent locked, which cannot wait for the disk
+    let new_process = loop {
+        match current_process.fork() {
+            Err(Errno::EAGAIN) => suspend_current_and_run_next(),
+            result => break result?,
+        }
+    };
     let new_pid = new_process.getpid();
     // modify trap context of new_task, because it returns immediately after switching
     let new_process_inner = new_process.inner_exclusive_access();
@@ -54,73 +88,107 @@ pub fn sys_fork() -> isize {
     // we do not have to move to next instruction since we have done it before
     // for child process, fork returns 0
     trap_cx.x[10] = 0;
-    new_pid as isize
+    Ok(new_pid)
 }
 
-/// Syscall Exec which accepts the elf path
-pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
-    let token = current_user_token();
-    let path = translated_str(token, path);
+/// Read a null-terminated array of string pointers from user space
+fn translated_args(token: usize, mut args: *const usize) -> Result<Vec<String>, Errno> {
     let mut args_vec: Vec<String> = Vec::new();
+    if args.is_null() {
+        return Ok(args_vec);
+    }
     loop {
-        let arg_str_ptr = *translated_ref(token, args);
+        let arg_str_ptr = copy_from_user(token, args)?;
         if arg_str_ptr == 0 {
             break;
         }
-        args_vec.push(translated_str(token, arg_str_ptr as *const u8));
-        unsafe {
-            args = args.add(1);
-        }
+        args_vec.push(translated_str(token, arg_str_ptr as *const u8)?);
+        args = args.wrapping_add(1);
     }
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
-        let process = current_process();
-        let argc = args_vec.len();
-        process.exec(all_data.as_slice(), args_vec);
-        argc as isize
-    } else {
-        -1
+    Ok(args_vec)
+}
+
+/// Look up the elf file at `path`, which is relative to the current working directory
+fn find_app(path: &str) -> Result<Arc<Inode>, Errno> {
+    let cwd = dir_inode(AT_FDCWD)?;
+    let app_inode = find_inode_at(&cwd, path)?;
+    if app_inode.is_dir() {
+        return Err(Errno::EACCES);
     }
+    Ok(app_inode)
 }
 
-/// If there is not a child process whose pid is same as given, return -1.
-/// Else if there is a child process but it is still running, return -2.
-pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
+/// Syscall Exec which accepts the elf path,
+/// failing with ENOEXEC if the elf is malformed
+pub fn sys_exec(path: *const u8, args: *const usize) -> SysResult {
+    let token = current_user_token();
+    let path = translated_str(token, path)?;
+    let args_vec = translated_args(token, args)?;
+    let app_inode = find_app(path.as_str())?;
     let process = current_process();
-    // find a child process
+    let argc = args_vec.len();
+    process.exec(&app_inode, args_vec)?;
+    Ok(argc)
+}
 
-    // ---- access current TCB exclusively
-    let mut inner = process.inner_exclusive_access();
-    if !inner
-        .children
-        .iter()
-        .any(|p| pid == -1 || pid as usize == p.getpid())
-    {
-        return -1;
-        // ---- release current PCB
-    }
-    let pair = inner.children.iter().enumerate().find(|(_, p)| {
-        // ++++ temporarily access child PCB lock exclusively
-        p.inner_exclusive_access().is_zombie && (pid == -1 || pid as usize == p.getpid())
-        // ++++ release child PCB
-    });
-    if let Some((idx, _)) = pair {
-        let child = inner.children.remove(idx);
-        // confirm that child will be deallocated after removing from children list
-        assert_eq!(Arc::strong_count(&child), 1);
-        let found_pid = child.getpid();
-        // ++++ temporarily access child TCB exclusively
-        let exit_code = child.inner_exclusive_access().exit_code;
-        // ++++ release child PCB
-        *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
-        found_pid as isize
-    } else {
-        -2
+/// Wait for the child `pid`, or any child if it is -1, to exit and reap it,
+/// or fail with ECHILD if there is no such child.
+/// With [`WNOHANG`] it fails with EAGAIN instead of blocking,
+/// and a signal to handle interrupts the wait with EINTR.
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32, options: usize) -> SysResult {
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    loop {
+        let process = current_process();
+        // find a child process
+
+        // ---- access current TCB exclusively
+        let mut inner = process.inner_exclusive_access();
+        if !inner
+            .children
+            .iter()
+            .any(|p| pid == -1 || pid as usize == p.getpid())
+        {
+            return Err(Errno::ECHILD);
+            // ---- release current PCB
+        }
+        let pair = inner.children.iter().enumerate().find(|(_, p)| {
+            // ++++ temporarily access child PCB lock exclusively
+            p.inner_exclusive_access().is_zombie && (pid == -1 || pid as usize == p.getpid())
+            // ++++ release child PCB
+        });
+        if let Some((idx, _)) = pair {
+            // ++++ temporarily access child TCB exclusively
+            let exit_code = inner.children[idx].inner_exclusive_access().exit_code;
+            // ++++ release child PCB
+            let token = inner.memory_set.token();
+            // release current PCB, since storing into a copy-on-write page needs it
+            drop(inner);
+            // the child stays a zombie if its exit code cannot be stored
+            if !exit_code_ptr.is_null() {
+                copy_to_user(token, exit_code_ptr, &exit_code)?;
+            }
+            let child = process.inner_exclusive_access().children.remove(idx);
+            // confirm that child will be deallocated after removing from children list
+            assert_eq!(Arc::strong_count(&child), 1);
+            return Ok(child.getpid());
+        }
+        if options & WNOHANG != 0 {
+            return Err(Errno::EAGAIN);
+        }
+        if inner.is_zombie || inner.signals.has_deliverable() {
+            return Err(Errno::EINTR);
+        }
+        // sleep until a child exits, holding no reference to the process meanwhile
+        inner.wait_queue.push_back(current_task().unwrap());
+        drop(inner);
+        drop(process);
+        block_current_and_run_next();
     }
-    // ---- release current PCB lock automatically
 }
 
-pub fn sys_get_time(_ts: *mut TimeVal, _tz: usize) -> isize {
+pub fn sys_get_time(_ts: *mut TimeVal, _tz: usize) -> SysResult {
     let _us = get_time_us();
     // unsafe {
     //     *ts = TimeVal {
@@ -128,31 +196,326 @@ pub fn sys_get_time(_ts: *mut TimeVal, _tz: usize) -> isize {
     //         usec: us % 1_000_000,
     //     };
     // }
-    *translated_refmut(current_user_token(), _ts) = TimeVal {
+    let time = TimeVal {
         sec: _us / 1_000_000,
         usec: _us % 1_000_000,
     };
-    0
+    copy_to_user(current_user_token(), _ts, &time)?;
+    Ok(0)
 }
 
-pub fn sys_task_info(_ti: *mut TaskInfo) -> isize {
-    -1
+/// Report the status, syscall counts and running time in ms of the current task
+pub fn sys_task_info(ti: *mut TaskInfo) -> SysResult {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let info = TaskInfo {
+        status: inner.task_status,
+        syscall_times: inner.syscall_times,
+        time: inner
+            .first_time
+            .map_or(0, |first_time| get_time_ms() - first_time),
+    };
+    drop(inner);
+    copy_to_user(current_user_token(), ti, &info)?;
+    Ok(0)
+}
+
+/// Set the priority of the current task, which must be at least 2,
+/// and return it. The stride of the task becomes BIG_STRIDE / priority.
+pub fn sys_set_priority(prio: isize) -> SysResult {
+    if prio < 2 {
+        return Err(Errno::EINVAL);
+    }
+    current_task().unwrap().inner_exclusive_access().stride = BIG_STRIDE / prio as usize;
+    Ok(prio as usize)
 }
 
-pub fn sys_set_priority(_prio: isize) -> isize {
-    -1
+/// The permission of user pages with `prot` bits R(1), W(2) and X(4),
+/// which fails with EACCES if it is both writable and executable under W^X
+fn user_permission(prot: usize) -> Result<MapPermission, Errno> {
+    if prot & !0x7 != 0 || prot & 0x7 == 0 {
+        return Err(Errno::EINVAL);
+    }
+    // a writable page must also be readable in Sv39
+    if prot & 0x2 != 0 && prot & 0x1 == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let permission = MapPermission::from_bits((prot << 1) as u8).unwrap() | MapPermission::U;
+    if !permission.allowed() {
+        return Err(Errno::EACCES);
+    }
+    Ok(permission)
 }
 
-pub fn sys_mmap(_start: usize, _len: usize, _port: usize) -> isize {
-    -1
+/// The end of `[start, start + len)` if the range lies in the user half of the address space,
+/// whose addresses above would wrap around as virtual addresses
+fn user_range_end(start: usize, len: usize) -> Option<VirtAddr> {
+    start
+        .checked_add(len)
+        .filter(|&end| end <= USER_SPACE_END)
+        .map(VirtAddr::from)
 }
 
-pub fn sys_munmap(_start: usize, _len: usize) -> isize {
-    -1
+/// Map `[start, start + len)` with `prot` bits R(1), W(2) and X(4) to anonymous memory
+/// if `flags` has MAP_ANONYMOUS, or to the file `fd` from the page-aligned `offset`.
+/// `start` must be page-aligned and the range must not overlap any mapped area.
+/// A MAP_SHARED mapping is shared with forked children, and written back to its file
+/// on msync, munmap and exit, while a MAP_PRIVATE one is copied on write.
+pub fn sys_mmap(
+    start: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> SysResult {
+    let start_va = VirtAddr::from(start);
+    if !start_va.aligned() || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    if flags & !(MAP_SHARED | MAP_PRIVATE | MAP_ANONYMOUS) != 0
+        || (flags & MAP_SHARED != 0) == (flags & MAP_PRIVATE != 0)
+    {
+        return Err(Errno::EINVAL);
+    }
+    let shared = flags & MAP_SHARED != 0;
+    let end_va = user_range_end(start, len).ok_or(Errno::EINVAL)?;
+    let permission = user_permission(prot)?;
+    let process = current_process();
+    // the file is looked into before locking the process, as the disk may put the task to sleep
+    let file = if flags & MAP_ANONYMOUS == 0 {
+        let file = match process.inner_exclusive_access().fd_table.get(fd) {
+            Some(Some(file)) => file.clone(),
+            _ => return Err(Errno::EBADF),
+        };
+        let inode = match file.inode() {
+            Some(inode) if !inode.is_dir() => inode,
+            _ => return Err(Errno::ENODEV),
+        };
+        if !file.readable() || (shared && permission.contains(MapPermission::W) && !file.writable())
+        {
+            return Err(Errno::EACCES);
+        }
+        let mut max_perm = MapPermission::all();
+        if shared && !file.writable() {
+            max_perm.remove(MapPermission::W);
+        }
+        if offset % PAGE_SIZE != 0 {
+            return Err(Errno::EINVAL);
+        }
+        // the pages beyond the end of the file are zero-filled and never written back
+        let file = MapFile {
+            len: len.min(inode.size().saturating_sub(offset)),
+            inode,
+            start_va: start,
+            offset,
+            writeback: shared,
+        };
+        Some((file, max_perm))
+    } else {
+        None
+    };
+    let mut inner = process.inner_exclusive_access();
+    if inner
+        .memory_set
+        .conflicts_with(start_va.floor(), end_va.ceil())
+    {
+        return Err(Errno::EINVAL);
+    }
+    match file {
+        Some((file, max_perm)) => {
+            inner
+                .memory_set
+                .insert_file_area(start_va, end_va, permission, max_perm, file);
+        }
+        None if shared => {
+            // a segment nobody else can attach, whose pages are shared on fork
+            let segment = ShmSegment::new(IPC_PRIVATE, len)?;
+            inner.memory_set.insert_shared_area(
+                start_va,
+                &segment,
+                permission,
+                MapPermission::all(),
+                true,
+            );
+        }
+        None => {
+            // the pages are allocated on first touch
+            inner
+                .memory_set
+                .insert_anonymous_area(start_va, end_va, permission);
+        }
+    }
+    Ok(0)
 }
 
-//
-// ALERT: 注意在实现 SPAWN 时不需要复制父进程地址空间，SPAWN != FORK + EXEC
-pub fn sys_spawn(_path: *const u8) -> isize {
-    -1
+/// Change the permission of `[start, start + len)` to `prot` bits R(1), W(2) and X(4),
+/// which may cover only part of a mapped area.
+/// Fail if some page in the range is not mapped.
+pub fn sys_mprotect(start: usize, len: usize, prot: usize) -> SysResult {
+    let start_va = VirtAddr::from(start);
+    if !start_va.aligned() {
+        return Err(Errno::EINVAL);
+    }
+    let permission = user_permission(prot)?;
+    let end_va = user_range_end(start, len).ok_or(Errno::ENOMEM)?;
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    inner
+        .memory_set
+        .protect_range(start_va.floor(), end_va.ceil(), permission)?;
+    Ok(0)
+}
+
+/// Write the shared file mappings in `[start, start + len)` back to their files.
+/// Fail if some page in the range is not mapped.
+pub fn sys_msync(start: usize, len: usize, flags: usize) -> SysResult {
+    let start_va = VirtAddr::from(start);
+    if !start_va.aligned()
+        || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC
+    {
+        return Err(Errno::EINVAL);
+    }
+    let end_va = user_range_end(start, len).ok_or(Errno::ENOMEM)?;
+    current_process()
+        .with_memory_set(|memory_set| memory_set.sync_range(start_va.floor(), end_va.ceil()))?;
+    Ok(0)
+}
+
+/// Unmap `[start, start + len)`, which may cover only part of a mapped area.
+/// Fail if some page in the range is not mapped by mmap or brk.
+pub fn sys_munmap(start: usize, len: usize) -> SysResult {
+    let start_va = VirtAddr::from(start);
+    if !start_va.aligned() || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let end_va = user_range_end(start, len).ok_or(Errno::EINVAL)?;
+    current_process().with_memory_set(|memory_set| {
+        // the shared file mappings are written back first, which may have to be tried again,
+        // while the other errors are ignored as unmapping the areas does
+        if let Err(Errno::EAGAIN) = memory_set.sync_range(start_va.floor(), end_va.ceil()) {
+            return Err(Errno::EAGAIN);
+        }
+        if memory_set.remove_user_range(start_va.floor(), end_va.ceil()) {
+            Ok(0)
+        } else {
+            Err(Errno::EINVAL)
+        }
+    })
+}
+
+/// Move the program break to `addr` and return it, or return the current one if `addr` is 0.
+/// The heap after the elf grows or shrinks with it.
+pub fn sys_brk(addr: usize) -> SysResult {
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if addr != 0 && !inner.memory_set.set_brk(addr) {
+        return Err(Errno::ENOMEM);
+    }
+    Ok(inner.memory_set.brk())
+}
+
+/// Move the program break by `increment` bytes and return the old one
+pub fn sys_sbrk(increment: isize) -> SysResult {
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let old_brk = inner.memory_set.brk();
+    let new_brk = if increment >= 0 {
+        old_brk.checked_add(increment as usize)
+    } else {
+        old_brk.checked_sub(increment.unsigned_abs())
+    };
+    match new_brk {
+        Some(new_brk) if inner.memory_set.set_brk(new_brk) => Ok(old_brk),
+        _ => Err(Errno::ENOMEM),
+    }
+}
+
+/// Get the id of the shared memory segment of `key`, or of a new one of at
+/// least `size` bytes if `key` is IPC_PRIVATE or `flags` has IPC_CREAT
+pub fn sys_shmget(key: usize, size: usize, flags: usize) -> SysResult {
+    shm_get(key, size, flags & IPC_CREAT != 0, flags & IPC_EXCL != 0)
+}
+
+/// Attach the shared memory segment `id` at `addr`, or at a free address
+/// chosen by the kernel if it is 0, and return the address
+pub fn sys_shmat(id: usize, addr: usize, flags: usize) -> SysResult {
+    let segment = shm_segment(id).ok_or(Errno::EINVAL)?;
+    let mut permission = MapPermission::R | MapPermission::U;
+    if flags & SHM_RDONLY == 0 {
+        permission |= MapPermission::W;
+    }
+    let pages = segment.size() / PAGE_SIZE;
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let start_vpn = if addr == 0 {
+        inner
+            .memory_set
+            .find_free_range(
+                VirtAddr::from(SHM_START).floor(),
+                VirtAddr::from(SHM_END).floor(),
+                pages,
+            )
+            .ok_or(Errno::ENOMEM)?
+    } else {
+        let start_va = VirtAddr::from(addr);
+        match user_range_end(addr, segment.size()) {
+            Some(end_va)
+                if start_va.aligned()
+                    && !inner
+                        .memory_set
+                        .conflicts_with(start_va.floor(), end_va.floor()) =>
+            {
+                start_va.floor()
+            }
+            _ => return Err(Errno::EINVAL),
+        }
+    };
+    let start_va = VirtAddr::from(start_vpn);
+    // shmdt detaches the whole segment, which munmap must not split
+    inner
+        .memory_set
+        .insert_shared_area(start_va, &segment, permission, permission, false);
+    Ok(start_va.into())
+}
+
+/// Detach the shared memory segment attached at `addr`
+pub fn sys_shmdt(addr: usize) -> SysResult {
+    let start_va = VirtAddr::from(addr);
+    if !start_va.aligned() {
+        return Err(Errno::EINVAL);
+    }
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if inner.memory_set.remove_shared_area(start_va.floor()) {
+        Ok(0)
+    } else {
+        Err(Errno::EINVAL)
+    }
+}
+
+/// Control the shared memory segment `id`, where only IPC_RMID is supported.
+/// A removed segment cannot be attached any more, and its memory is freed
+/// once every process has detached it.
+pub fn sys_shmctl(id: usize, cmd: usize, _buf: usize) -> SysResult {
+    if cmd != IPC_RMID {
+        return Err(Errno::EINVAL);
+    }
+    if shm_remove(id) {
+        Ok(0)
+    } else {
+        Err(Errno::EINVAL)
+    }
+}
+
+/// Syscall Spawn which creates a child process from the elf path and
+/// returns its pid, without copying the address space of the caller
+pub fn sys_spawn(path: *const u8, args: *const usize) -> SysResult {
+    let token = current_user_token();
+    let path = translated_str(token, path)?;
+    let args_vec = translated_args(token, args)?;
+    let app_inode = find_app(path.as_str())?;
+    let new_process = current_process().spawn(&app_inode, args_vec)?;
+    Ok(new_process.getpid())
 }
@@ -0,0 +1,114 @@
+//! Signal syscalls
+
+use super::errno::{Errno, SysResult};
+use crate::mm::{copy_from_user, copy_to_user};
+use crate::task::signal::{SignalAction, SignalFlags, SignalFrame, MAX_SIG};
+use crate::task::{current_process, current_trap_cx, current_user_token, pid2process, INITPROC};
+use crate::task::{wakeup_task, TaskControlBlock};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// How of sigprocmask to block the signals in the set
+const SIG_BLOCK: usize = 0;
+/// How of sigprocmask to unblock the signals in the set
+const SIG_UNBLOCK: usize = 1;
+/// How of sigprocmask to block exactly the signals in the set
+const SIG_SETMASK: usize = 2;
+
+/// Send the signal `signum` to the process `pid`,
+/// or only check that the process exists if `signum` is 0
+pub fn sys_kill(pid: usize, signum: usize) -> SysResult {
+    if signum > MAX_SIG {
+        return Err(Errno::EINVAL);
+    }
+    let process = pid2process(pid).ok_or(Errno::ESRCH)?;
+    // every orphan is left to initproc to reap
+    if Arc::ptr_eq(&process, &INITPROC) {
+        return Err(Errno::EPERM);
+    }
+    if signum != 0 {
+        let mut inner = process.inner_exclusive_access();
+        inner.signals.send(signum);
+        let tasks: Vec<Arc<TaskControlBlock>> = inner.tasks.iter().flatten().cloned().collect();
+        drop(inner);
+        // interrupt whatever the threads are blocked in to handle it
+        for task in tasks {
+            wakeup_task(task);
+        }
+    }
+    Ok(0)
+}
+
+/// Change the action of the signal `signum` to `*action` unless it is null,
+/// and store the previous one at `old_action` unless it is null.
+/// SIGKILL and SIGSTOP cannot be caught or ignored.
+pub fn sys_sigaction(
+    signum: usize,
+    action: *const SignalAction,
+    old_action: *mut SignalAction,
+) -> SysResult {
+    if signum == 0
+        || signum > MAX_SIG
+        || SignalFlags::unblockable().contains(SignalFlags::from_signum(signum))
+    {
+        return Err(Errno::EINVAL);
+    }
+    let token = current_user_token();
+    let action = if action.is_null() {
+        None
+    } else {
+        let mut action = copy_from_user(token, action)?;
+        action.mask = SignalFlags::from_bits_truncate(action.mask.bits());
+        Some(action)
+    };
+    let process = current_process();
+    let old = process.inner_exclusive_access().signals.actions[signum];
+    if !old_action.is_null() {
+        copy_to_user(token, old_action, &old)?;
+    }
+    if let Some(action) = action {
+        process.inner_exclusive_access().signals.actions[signum] = action;
+    }
+    Ok(0)
+}
+
+/// Change the blocked signals by `*set` as `how` tells unless it is null,
+/// and store the previous ones at `old_set` unless it is null.
+/// SIGKILL and SIGSTOP cannot be blocked.
+pub fn sys_sigprocmask(how: usize, set: *const u32, old_set: *mut u32) -> SysResult {
+    let token = current_user_token();
+    let set = if set.is_null() {
+        None
+    } else {
+        Some(SignalFlags::from_bits_truncate(copy_from_user(token, set)?))
+    };
+    let process = current_process();
+    let old = process.inner_exclusive_access().signals.blocked;
+    let blocked = match (how, set) {
+        (_, None) => old,
+        (SIG_BLOCK, Some(set)) => old | set,
+        (SIG_UNBLOCK, Some(set)) => old - set,
+        (SIG_SETMASK, Some(set)) => set,
+        _ => return Err(Errno::EINVAL),
+    };
+    if !old_set.is_null() {
+        copy_to_user(token, old_set, &old.bits())?;
+    }
+    process.inner_exclusive_access().signals.blocked = blocked - SignalFlags::unblockable();
+    Ok(0)
+}
+
+/// Return from a signal handler to the code it interrupted, restoring the
+/// registers and the blocked signals from the frame at the user stack pointer
+pub fn sys_sigreturn() -> SysResult {
+    let token = current_user_token();
+    let trap_cx = current_trap_cx();
+    let frame: SignalFrame = copy_from_user(token, trap_cx.x[2] as *const SignalFrame)?;
+    current_process().inner_exclusive_access().signals.blocked =
+        SignalFlags::from_bits_truncate(frame.blocked.bits()) - SignalFlags::unblockable();
+    // sstatus is left alone, which user space must never choose
+    trap_cx.x = frame.x;
+    trap_cx.sepc = frame.sepc;
+    // the result goes to a0, which must keep its value
+    Ok(frame.x[10])
+}
@@ -1,19 +1,28 @@
 use crate::sync::{Condvar, Mutex, MutexBlocking, MutexSpin, Semaphore};
 use crate::task::{block_current_and_run_next, current_process, current_task};
-use crate::timer::{add_timer, get_time_ms};
+use crate::task::current_signal_pending;
+use crate::timer::{add_timer, get_time_ms, remove_timer};
+use super::errno::{Errno, SysResult};
 use alloc::sync::Arc;
 
-pub fn sys_sleep(ms: usize) -> isize {
+/// Sleep for `ms` milliseconds, or fail with EINTR if a signal to handle arrives meanwhile
+pub fn sys_sleep(ms: usize) -> SysResult {
     let expire_ms = get_time_ms() + ms;
     let task = current_task().unwrap();
-    add_timer(expire_ms, task);
-    block_current_and_run_next();
-    0
+    add_timer(expire_ms, Arc::clone(&task));
+    while get_time_ms() < expire_ms {
+        if current_signal_pending() {
+            remove_timer(&task);
+            return Err(Errno::EINTR);
+        }
+        block_current_and_run_next();
+    }
+    Ok(0)
 }
 
 // LAB5 HINT: you might need to maintain data structures used for deadlock detection
 // during sys_mutex_* and sys_semaphore_* syscalls
-pub fn sys_mutex_create(blocking: bool) -> isize {
+pub fn sys_mutex_create(blocking: bool) -> SysResult {
     let process = current_process();
     let mutex: Option<Arc<dyn Mutex>> = if !blocking {
         Some(Arc::new(MutexSpin::new()))
@@ -35,7 +44,7 @@ pub fn sys_mutex_create(blocking: bool) -> isize {
             process_inner.mutex_allocation[i][id] = 0;
             process_inner.mutex_need[i][id] = 0;
         }
-        id as isize
+        Ok(id)
     } else {
         process_inner.mutex_list.push(mutex);
         process_inner.mutex_available.push(1);
@@ -43,16 +52,20 @@ pub fn sys_mutex_create(blocking: bool) -> isize {
             process_inner.mutex_allocation[i].push(0);
             process_inner.mutex_need[i].push(0);
         }
-        process_inner.mutex_list.len() as isize - 1
+        Ok(process_inner.mutex_list.len() - 1)
     }
-    
 }
 
-// LAB5 HINT: Return -0xDEAD if deadlock is detected
-pub fn sys_mutex_lock(mutex_id: usize) -> isize {
+/// Fail with EDEADLK instead of blocking if deadlock detection is enabled
+/// and waiting for the mutex would deadlock,
+/// or with EINTR if a signal to handle arrives while waiting
+pub fn sys_mutex_lock(mutex_id: usize) -> SysResult {
     let process = current_process();
     let mut process_inner = process.inner_exclusive_access();
-    let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
+    let mutex = match process_inner.mutex_list.get(mutex_id) {
+        Some(Some(mutex)) => Arc::clone(mutex),
+        _ => return Err(Errno::EINVAL),
+    };
     let tid = current_task().unwrap().inner_exclusive_access().res.as_ref().unwrap().tid as usize;
     if process_inner.mutex_available[mutex_id] == 1 {
         process_inner.mutex_available[mutex_id] = 0;
@@ -60,24 +73,26 @@ pub fn sys_mutex_lock(mutex_id: usize) -> isize {
     } else {
         process_inner.mutex_need[tid][mutex_id] = 1;
         if process_inner.deadlock_detect == true {
-            let res = process_inner.check_deadlock_mutex();
-            if res != 0{
-                drop(process_inner);
-                drop(process);
-                return res;
+            if process_inner.check_deadlock_mutex() {
+                return Err(Errno::EDEADLK);
             }
         }
     }
     drop(process_inner);
-    drop(process);
-    mutex.lock();
-    0
+    if let Err(errno) = mutex.lock() {
+        process.inner_exclusive_access().mutex_need[tid][mutex_id] = 0;
+        return Err(errno);
+    }
+    Ok(0)
 }
 
-pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
+pub fn sys_mutex_unlock(mutex_id: usize) -> SysResult {
     let process = current_process();
     let mut process_inner = process.inner_exclusive_access();
-    let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
+    let mutex = match process_inner.mutex_list.get(mutex_id) {
+        Some(Some(mutex)) => Arc::clone(mutex),
+        _ => return Err(Errno::EINVAL),
+    };
     let tid = current_task().unwrap().inner_exclusive_access().res.as_ref().unwrap().tid as usize;
     let next_queue_id = mutex.get_next_queue_id();
     if next_queue_id == -1 { // 队列内没有东西
@@ -91,10 +106,10 @@ pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
     drop(process_inner);
     drop(process);
     mutex.unlock();
-    0
+    Ok(0)
 }
 
-pub fn sys_semaphore_create(res_count: usize) -> isize {
+pub fn sys_semaphore_create(res_count: usize) -> SysResult {
     let process = current_process();
     let mut process_inner = process.inner_exclusive_access();
     let id = if let Some(id) = process_inner
@@ -122,13 +137,16 @@ pub fn sys_semaphore_create(res_count: usize) -> isize {
         }
         process_inner.semaphore_list.len() - 1
     };
-    id as isize
+    Ok(id)
 }
 
-pub fn sys_semaphore_up(sem_id: usize) -> isize {
+pub fn sys_semaphore_up(sem_id: usize) -> SysResult {
     let process = current_process();
     let mut process_inner = process.inner_exclusive_access();
-    let sem = Arc::clone(process_inner.semaphore_list[sem_id].as_ref().unwrap());
+    let sem = match process_inner.semaphore_list.get(sem_id) {
+        Some(Some(sem)) => Arc::clone(sem),
+        _ => return Err(Errno::EINVAL),
+    };
     let tid = current_task().unwrap().inner_exclusive_access().res.as_ref().unwrap().tid as usize;
     let next_queue_id = sem.get_next_queue_id();
     if next_queue_id == -1 { // 队列内没有东西
@@ -142,14 +160,19 @@ pub fn sys_semaphore_up(sem_id: usize) -> isize {
     drop(process_inner);
     drop(process);
     sem.up();
-    0
+    Ok(0)
 }
 
-// LAB5 HINT: Return -0xDEAD if deadlock is detected
-pub fn sys_semaphore_down(sem_id: usize) -> isize {
+/// Fail with EDEADLK instead of blocking if deadlock detection is enabled
+/// and waiting for the semaphore would deadlock,
+/// or with EINTR if a signal to handle arrives while waiting
+pub fn sys_semaphore_down(sem_id: usize) -> SysResult {
     let process = current_process();
     let mut process_inner = process.inner_exclusive_access();
-    let sem = Arc::clone(process_inner.semaphore_list[sem_id].as_ref().unwrap());
+    let sem = match process_inner.semaphore_list.get(sem_id) {
+        Some(Some(sem)) => Arc::clone(sem),
+        _ => return Err(Errno::EINVAL),
+    };
     let tid = current_task().unwrap().inner_exclusive_access().res.as_ref().unwrap().tid as usize;
     if process_inner.semaphore_available[sem_id] > 0 {
         process_inner.semaphore_available[sem_id] -= 1;
@@ -157,21 +180,20 @@ pub fn sys_semaphore_down(sem_id: usize) -> isize {
     } else {
         process_inner.semaphore_need[tid][sem_id] += 1;
         if process_inner.deadlock_detect == true {
-            let res = process_inner.check_deadlock_semaphore();
-            if res != 0{
-                drop(process_inner);
-                drop(process);
-                return res;
+            if process_inner.check_deadlock_semaphore() {
+                return Err(Errno::EDEADLK);
             }
         }
     }
     drop(process_inner);
-    drop(process);
-    sem.down();
-    0
+    if let Err(errno) = sem.down() {
+        process.inner_exclusive_access().semaphore_need[tid][sem_id] -= 1;
+        return Err(errno);
+    }
+    Ok(0)
 }
 
-pub fn sys_condvar_create(_arg: usize) -> isize {
+pub fn sys_condvar_create(_arg: usize) -> SysResult {
     let process = current_process();
     let mut process_inner = process.inner_exclusive_access();
     let id = if let Some(id) = process_inner
@@ -189,43 +211,51 @@ pub fn sys_condvar_create(_arg: usize) -> isize {
             .push(Some(Arc::new(Condvar::new())));
         process_inner.condvar_list.len() - 1
     };
-    id as isize
+    Ok(id)
 }
 
-pub fn sys_condvar_signal(condvar_id: usize) -> isize {
+pub fn sys_condvar_signal(condvar_id: usize) -> SysResult {
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let condvar = Arc::clone(process_inner.condvar_list[condvar_id].as_ref().unwrap());
+    let condvar = match process_inner.condvar_list.get(condvar_id) {
+        Some(Some(condvar)) => Arc::clone(condvar),
+        _ => return Err(Errno::EINVAL),
+    };
     drop(process_inner);
     condvar.signal();
-    0
+    Ok(0)
 }
 
-pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
+pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> SysResult {
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let condvar = Arc::clone(process_inner.condvar_list[condvar_id].as_ref().unwrap());
-    let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
+    let (condvar, mutex) = match (
+        process_inner.condvar_list.get(condvar_id),
+        process_inner.mutex_list.get(mutex_id),
+    ) {
+        (Some(Some(condvar)), Some(Some(mutex))) => (Arc::clone(condvar), Arc::clone(mutex)),
+        _ => return Err(Errno::EINVAL),
+    };
     drop(process_inner);
-    condvar.wait(mutex);
-    0
+    condvar.wait(mutex)?;
+    Ok(0)
 }
 
 // LAB5 YOUR JOB: Implement deadlock detection, but might not all in this syscall
-pub fn sys_enable_deadlock_detect(_enabled: usize) -> isize {
+pub fn sys_enable_deadlock_detect(_enabled: usize) -> SysResult {
     if _enabled == 1 {
         let process = current_process();
         let mut process_inner = process.inner_exclusive_access();
         process_inner.deadlock_detect = true;
         drop(process_inner);
-        0
+        Ok(0)
     } else if _enabled == 0 {
         let process = current_process();
         let mut process_inner = process.inner_exclusive_access();
         process_inner.deadlock_detect = false;
         drop(process_inner);
-        0
+        Ok(0)
     } else {
-        -1
+        Err(Errno::EINVAL)
     }
 }
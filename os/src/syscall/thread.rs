@@ -1,11 +1,13 @@
 use crate::{
-    mm::kernel_token,
-    task::{add_task, current_task, TaskControlBlock},
+    mm::{copy_to_user, kernel_token},
+    task::{add_task, block_current_and_run_next, current_task, TaskControlBlock},
     trap::{trap_handler, TrapContext},
 };
+use super::errno::{Errno, SysResult};
+use super::process::WNOHANG;
 use alloc::{sync::Arc, vec};
 
-pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
+pub fn sys_thread_create(entry: usize, arg: usize) -> SysResult {
     let task = current_task().unwrap();
     let process = task.process.upgrade().unwrap();
     // create a new thread
@@ -30,6 +32,7 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
         trap_handler as usize,
     );
     (*new_task_trap_cx).x[10] = arg;
+    drop(new_task_inner);
 
     let mut process_inner = process.inner_exclusive_access();
     // add new thread to current process
@@ -46,47 +49,76 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
     process_inner.semaphore_need.push(vec![0;semaphore_len]);
     // add new task to scheduler
     add_task(Arc::clone(&new_task));
-    new_task_tid as isize
+    Ok(new_task_tid)
 }
 
-pub fn sys_gettid() -> isize {
-    current_task()
+pub fn sys_gettid() -> SysResult {
+    Ok(current_task()
         .unwrap()
         .inner_exclusive_access()
         .res
         .as_ref()
         .unwrap()
-        .tid as isize
+        .tid)
 }
 
-/// thread does not exist, return -1
-/// thread has not exited yet, return -2
-/// otherwise, return thread's exit code
-pub fn sys_waittid(tid: usize) -> i32 {
-    let task = current_task().unwrap();
-    let process = task.process.upgrade().unwrap();
-    let task_inner = task.inner_exclusive_access();
-    let mut process_inner = process.inner_exclusive_access();
-    // a thread cannot wait for itself
-    if task_inner.res.as_ref().unwrap().tid == tid {
-        return -1;
+/// thread is the caller itself, fail with EDEADLK
+/// thread does not exist, fail with ESRCH
+/// thread has not exited yet, block until it does, or fail with EAGAIN under WNOHANG
+/// a signal to handle arrives meanwhile, fail with EINTR
+/// otherwise, store thread's exit code into `exit_code_ptr` unless it is null and return tid
+pub fn sys_waittid(tid: usize, exit_code_ptr: *mut i32, options: usize) -> SysResult {
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
     }
-    let mut exit_code: Option<i32> = None;
-    let waited_task = process_inner.tasks[tid].as_ref();
-    if let Some(waited_task) = waited_task {
-        if let Some(waited_exit_code) = waited_task.inner_exclusive_access().exit_code {
-            exit_code = Some(waited_exit_code);
+    loop {
+        let task = current_task().unwrap();
+        let process = task.process.upgrade().unwrap();
+        // a thread cannot wait for itself
+        if task.inner_exclusive_access().res.as_ref().unwrap().tid == tid {
+            return Err(Errno::EDEADLK);
+        }
+        let mut process_inner = process.inner_exclusive_access();
+        let mut exited: Option<(Arc<TaskControlBlock>, i32)> = None;
+        let waited_task = process_inner.tasks.get(tid).and_then(Option::as_ref);
+        if let Some(waited_task) = waited_task {
+            if let Some(waited_exit_code) = waited_task.inner_exclusive_access().exit_code {
+                exited = Some((Arc::clone(waited_task), waited_exit_code));
+            }
+        } else {
+            // waited thread does not exist
+            return Err(Errno::ESRCH);
+        }
+        if let Some((waited_task, exit_code)) = exited {
+            let token = process_inner.memory_set.token();
+            // release current PCB, since storing into a copy-on-write page needs it
+            drop(process_inner);
+            // the thread stays unreaped if its exit code cannot be stored
+            if !exit_code_ptr.is_null() {
+                copy_to_user(token, exit_code_ptr, &exit_code)?;
+            }
+            let mut process_inner = process.inner_exclusive_access();
+            // another thread may have reaped it meanwhile
+            match process_inner.tasks.get(tid).and_then(Option::as_ref) {
+                Some(task) if Arc::ptr_eq(task, &waited_task) => {}
+                _ => return Err(Errno::ESRCH),
+            }
+            // dealloc the exited thread
+            process_inner.tasks[tid] = None;
+            return Ok(tid);
         }
-    } else {
-        // waited thread does not exist
-        return -1;
-    }
-    if let Some(exit_code) = exit_code {
-        // dealloc the exited thread
-        process_inner.tasks[tid] = None;
-        exit_code
-    } else {
         // waited thread has not exited
-        -2
+        if options & WNOHANG != 0 {
+            return Err(Errno::EAGAIN);
+        }
+        if process_inner.is_zombie || process_inner.signals.has_deliverable() {
+            return Err(Errno::EINTR);
+        }
+        // sleep until a thread exits, holding no reference to the process meanwhile
+        process_inner.wait_queue.push_back(Arc::clone(&task));
+        drop(process_inner);
+        drop(process);
+        drop(task);
+        block_current_and_run_next();
     }
 }
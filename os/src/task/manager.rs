@@ -1,40 +1,59 @@
 //! Implementation of [`TaskManager`]
 //!
-//! It is only used to manage processes and schedule process based on ready queue.
+//! It is only used to manage processes and schedule process based on ready queue,
+//! following the [`Scheduler`] policy chosen at boot.
 //! Other CPU process monitoring functions are in Processor.
 
 
-use super::TaskControlBlock;
-use crate::sync::UPSafeCell;
-use alloc::collections::VecDeque;
+use super::scheduler::{SchedPolicy, Scheduler};
+use super::{ProcessControlBlock, TaskControlBlock};
+use crate::sync::SpinLock;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use lazy_static::*;
 
 pub struct TaskManager {
-    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+    scheduler: Box<dyn Scheduler>,
 }
 
-/// A simple FIFO scheduler.
 impl TaskManager {
-    pub fn new() -> Self {
+    pub fn new(policy: SchedPolicy) -> Self {
         Self {
-            ready_queue: VecDeque::new(),
+            scheduler: policy.scheduler(),
         }
     }
     /// Add process back to ready queue
     pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_queue.push_back(task);
+        self.scheduler.add(task);
     }
     /// Take a process out of the ready queue
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        self.ready_queue.pop_front()
+        self.scheduler.fetch()
+    }
+    /// Remove a process from the ready queue if it is there
+    #[allow(unused)]
+    pub fn remove(&mut self, task: &Arc<TaskControlBlock>) {
+        self.scheduler.remove(task);
+    }
+    /// Account a timer tick to the running process
+    pub fn on_tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        self.scheduler.on_tick(task)
     }
 }
 
 lazy_static! {
     /// TASK_MANAGER instance through lazy_static!
-    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
-        unsafe { UPSafeCell::new(TaskManager::new()) };
+    pub static ref TASK_MANAGER: SpinLock<TaskManager> =
+        SpinLock::new(TaskManager::new(SchedPolicy::from_build_option()));
+    /// The processes which have not exited, by pid
+    pub static ref PID2PROCESS: SpinLock<BTreeMap<usize, Arc<ProcessControlBlock>>> =
+        SpinLock::new(BTreeMap::new());
+}
+
+/// Replace the scheduling policy, only before any task has been added
+pub fn set_sched_policy(policy: SchedPolicy) {
+    *TASK_MANAGER.exclusive_access() = TaskManager::new(policy);
 }
 
 pub fn add_task(task: Arc<TaskControlBlock>) {
@@ -44,3 +63,25 @@ pub fn add_task(task: Arc<TaskControlBlock>) {
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
     TASK_MANAGER.exclusive_access().fetch()
 }
+
+#[allow(unused)]
+pub fn remove_task(task: &Arc<TaskControlBlock>) {
+    TASK_MANAGER.exclusive_access().remove(task);
+}
+
+pub fn tick_task(task: &Arc<TaskControlBlock>) -> bool {
+    TASK_MANAGER.exclusive_access().on_tick(task)
+}
+
+/// The process of `pid` if it has not exited
+pub fn pid2process(pid: usize) -> Option<Arc<ProcessControlBlock>> {
+    PID2PROCESS.exclusive_access().get(&pid).cloned()
+}
+
+pub fn insert_into_pid2process(pid: usize, process: Arc<ProcessControlBlock>) {
+    PID2PROCESS.exclusive_access().insert(pid, process);
+}
+
+pub fn remove_from_pid2process(pid: usize) {
+    PID2PROCESS.exclusive_access().remove(&pid);
+}
@@ -4,7 +4,7 @@
 //! (such as syscall or clock interrupt).
 //! By suspending or exiting the current process, you can
 //! modify the process state, manage the process queue through TASK_MANAGER,
-//! and switch the control flow through PROCESSOR.
+//! and switch the control flow through the PROCESSORS of the harts.
 //!
 //! Be careful when you see [`__switch`]. Control flow around this function
 //! might not be what you expect.
@@ -15,6 +15,8 @@ pub mod kthread;
 mod manager;
 mod process;
 mod processor;
+mod scheduler;
+pub mod signal;
 pub mod stackless_coroutine;
 mod switch;
 #[allow(clippy::module_inception)]
@@ -22,34 +24,97 @@ mod task;
 
 pub use crate::syscall::process::TaskInfo;
 use crate::{
-    fs::{open_file, OpenFlags},
+    config::MAX_SYSCALL_NUM,
+    fs::ROOT_INODE,
+    mm::copy_to_user,
     task::id::TaskUserRes,
 };
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 pub use context::TaskContext;
+use core::sync::atomic::Ordering;
 pub use id::{kstack_alloc, pid_alloc, KernelStack, PidHandle};
 pub use kthread::kernel_stackful_coroutine_test;
 use lazy_static::*;
-pub use manager::add_task;
-use manager::fetch_task;
+pub use manager::{add_task, pid2process, set_sched_policy};
+use manager::{fetch_task, tick_task};
+use manager::{insert_into_pid2process, remove_from_pid2process};
 use process::ProcessControlBlock;
 pub use processor::{
     current_process, current_task, current_trap_cx, current_trap_cx_user_va, current_user_token,
-    run_tasks, schedule, take_current_task,
+    hart_id, online_harts, run_tasks, schedule, take_current_task,
 };
+pub use scheduler::SchedPolicy;
+use signal::{DefaultAction, SignalFlags, SignalFrame, SIGSEGV, SIG_DFL, SIG_IGN};
 pub use stackless_coroutine::kernel_stackless_coroutine_test;
 use switch::__switch;
 pub use task::{TaskControlBlock, TaskStatus};
 
+/// Count a syscall invoked by the current task
+pub fn record_syscall(syscall_id: usize) {
+    if syscall_id < MAX_SYSCALL_NUM {
+        current_task()
+            .unwrap()
+            .inner_exclusive_access()
+            .syscall_times[syscall_id] += 1;
+    }
+}
+
+/// Account a timer tick to the current task through the scheduler,
+/// return whether the current task should give up the CPU
+pub fn tick_current_task() -> bool {
+    tick_task(&current_task().unwrap())
+}
+
+/// Make current task blocked and switch to the next task, until [`wakeup_task`] wakes it up.
+/// The task may be woken up for another reason than the one it waits for,
+/// so it must check again what it waits for.
 pub fn block_current_and_run_next() {
-    let task = take_current_task().unwrap();
+    let task = current_task().unwrap();
     let mut task_inner = task.inner_exclusive_access();
+    // it has been woken up already
+    if core::mem::take(&mut task_inner.wakeup_pending) {
+        return;
+    }
     let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
     task_inner.task_status = TaskStatus::Blocking;
     drop(task_inner);
+    take_current_task();
     schedule(task_cx_ptr);
 }
 
+/// Make a task blocked in [`block_current_and_run_next`] ready again, or keep it from
+/// sleeping the next time it blocks if it is still running, such as when it has queued
+/// up to wait but not switched out yet. Waking up a task twice never runs it twice.
+pub fn wakeup_task(task: Arc<TaskControlBlock>) {
+    let mut task_inner = task.inner_exclusive_access();
+    match task_inner.task_status {
+        TaskStatus::Blocking => {
+            task_inner.task_status = TaskStatus::Ready;
+            drop(task_inner);
+            add_task(task);
+        }
+        TaskStatus::Running => task_inner.wakeup_pending = true,
+        _ => {}
+    }
+}
+
+/// Whether the current process has a signal to handle or is exiting,
+/// which interrupts the blocking waits of its threads with EINTR
+pub fn current_signal_pending() -> bool {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    inner.is_zombie || inner.signals.has_deliverable()
+}
+
+/// Whether the current process is exiting, which even the waits
+/// that signals do not interrupt give up, since it waits for its threads to leave
+pub fn current_exiting() -> bool {
+    current_process().inner_exclusive_access().is_zombie
+}
+
 /// Make current task suspended and switch to the next task
 pub fn suspend_current_and_run_next() {
     // There must be an application running.
@@ -72,12 +137,46 @@ pub fn suspend_current_and_run_next() {
 
 /// Exit current task, recycle process resources and switch to the next task
 pub fn exit_current_and_run_next(exit_code: i32) {
+    exit_current(exit_code, false);
+}
+
+/// Exit the whole process of the current task whichever thread it is,
+/// such as when it is killed by a signal, and switch to the next task
+pub fn exit_current_process_and_run_next(exit_code: i32) {
+    exit_current(exit_code, true);
+}
+
+/// Exit current task, and its process as well if `whole_process` or it is the main thread
+fn exit_current(exit_code: i32, whole_process: bool) {
+    let tid = current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .res
+        .as_ref()
+        .unwrap()
+        .tid;
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    // the first thread to exit the process tears it down, while the others
+    // leave when they see it exiting
+    let teardown = (tid == 0 || whole_process) && !process_inner.is_zombie;
+    if teardown {
+        // mark this process as a zombie process
+        process_inner.is_zombie = true;
+        // record exit code of main process
+        process_inner.exit_code = exit_code;
+        drop(process_inner);
+        wait_other_threads(&process);
+        // the shared file mappings are written back while this task can still give way
+        let _ = process.with_memory_set(|memory_set| memory_set.sync_all());
+    } else {
+        drop(process_inner);
+    }
+
     // take from Processor
     let task = take_current_task().unwrap();
     // **** access current TCB exclusively
     let mut task_inner = task.inner_exclusive_access();
-    let process = task.process.upgrade().unwrap();
-    let tid = task_inner.res.as_ref().unwrap().tid;
     // Record exit code
     task_inner.exit_code = Some(exit_code);
     task_inner.res = None;
@@ -85,15 +184,18 @@ pub fn exit_current_and_run_next(exit_code: i32) {
     // here we do not remove the thread since we are still using the kstack
     // it will be deallocated when sys_waittid is called
     drop(task_inner);
-    drop(task);
+    let current = task;
     // debug!("task {} dropped", tid);
 
-    if tid == 0 {
-        let mut process_inner = process.inner_exclusive_access();
-        // mark this process as a zombie process
-        process_inner.is_zombie = true;
-        // record exit code of main process
-        process_inner.exit_code = exit_code;
+    let mut process_inner = process.inner_exclusive_access();
+    if teardown {
+        let children = core::mem::take(&mut process_inner.children);
+        let parent = process_inner.parent.clone();
+        let tasks: Vec<Arc<TaskControlBlock>> =
+            process_inner.tasks.iter().flatten().cloned().collect();
+        // the other locks are taken without this one, as waitpid takes them the other way
+        drop(process_inner);
+        remove_from_pid2process(process.getpid());
 
         // do not move to its parent but under initproc
         // debug!("reparent");
@@ -101,10 +203,18 @@ pub fn exit_current_and_run_next(exit_code: i32) {
         // ++++++ access initproc PCB exclusively
         {
             let mut initproc_inner = INITPROC.inner_exclusive_access();
-            for child in process_inner.children.iter() {
+            for child in children.iter() {
                 child.inner_exclusive_access().parent = Some(Arc::downgrade(&INITPROC));
                 initproc_inner.children.push(child.clone());
             }
+            // some of them may have exited already
+            if !children.is_empty() {
+                initproc_inner.wake_waiters();
+            }
+        }
+        // let the parent reap this process
+        if let Some(parent) = parent.as_ref().and_then(Weak::upgrade) {
+            parent.inner_exclusive_access().wake_waiters();
         }
         let mut recycle_res = Vec::<TaskUserRes>::new();
 
@@ -112,42 +222,162 @@ pub fn exit_current_and_run_next(exit_code: i32) {
         // deallocate user res (including tid/trap_cx/ustack) of all threads
         // it has to be done before we dealloc the whole memory_set
         // otherwise they will be deallocated twice
-        for task in process_inner.tasks.iter().filter(|t| t.is_some()) {
-            let task = task.as_ref().unwrap();
+        for task in tasks.iter() {
             let mut task_inner = task.inner_exclusive_access();
             if let Some(res) = task_inner.res.take() {
                 recycle_res.push(res);
             }
         }
-        drop(process_inner);
         recycle_res.clear();
         let mut process_inner = process.inner_exclusive_access();
         // debug!("deallocate pcb res");
-        process_inner.children.clear();
         // deallocate other data in user space i.e. program code/data section
         process_inner.memory_set.recycle_data_pages();
         // drop file descriptors
         process_inner.fd_table.clear();
+        // the other threads are gone, so none of them can be woken
+        process_inner.wait_queue.clear();
+    } else {
+        // let the threads joining this one reap it
+        process_inner.wake_waiters();
+        drop(process_inner);
     }
     // debug!("pcb dropped");
 
     // ++++++ release parent PCB
     drop(process);
+    // the idle control flow keeps the kernel stack until the switch is done
+    drop(current);
 
     // we do not have to save task context
     let mut _unused = TaskContext::zero_init();
     schedule(&mut _unused as *mut _);
 }
 
+/// Wake up the other threads of the exiting `process` from whatever they wait for,
+/// and give way to them until all of them have exited and switched out.
+/// They leave by themselves, since they may be in the middle of a syscall,
+/// such as holding the filesystem while sleeping on the disk.
+fn wait_other_threads(process: &Arc<ProcessControlBlock>) {
+    let current = current_task().unwrap();
+    loop {
+        // the threads may create more threads before they leave
+        let tasks: Vec<Arc<TaskControlBlock>> = process
+            .inner_exclusive_access()
+            .tasks
+            .iter()
+            .flatten()
+            .filter(|task| !Arc::ptr_eq(task, &current))
+            .cloned()
+            .collect();
+        let mut waiting = false;
+        for task in tasks {
+            if task.inner_exclusive_access().exit_code.is_none()
+                || task.on_cpu.load(Ordering::Acquire)
+            {
+                waiting = true;
+                wakeup_task(task);
+            }
+        }
+        if !waiting {
+            return;
+        }
+        suspend_current_and_run_next();
+    }
+}
+
+/// Make the fault `signum` pending for the current process,
+/// see [`SignalState::send_fault`](signal::SignalState::send_fault)
+pub fn current_add_fault_signal(signum: usize) {
+    current_process()
+        .inner_exclusive_access()
+        .signals
+        .send_fault(signum);
+}
+
+/// Handle the pending signals of the current process before returning to user space,
+/// taking their default actions or entering the user handler of one of them
+pub fn handle_signals() {
+    loop {
+        let process = current_process();
+        let mut inner = process.inner_exclusive_access();
+        if inner.is_zombie {
+            // another thread is exiting the process and waits for this one to leave
+            let exit_code = inner.exit_code;
+            drop(inner);
+            drop(process);
+            exit_current_and_run_next(exit_code);
+            return;
+        }
+        let signum = match inner.signals.take_next() {
+            Some(signum) => signum,
+            None if inner.signals.stopped => {
+                // wait for SIGCONT or SIGKILL
+                drop(inner);
+                drop(process);
+                suspend_current_and_run_next();
+                continue;
+            }
+            None => return,
+        };
+        let action = inner.signals.actions[signum];
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL => match DefaultAction::of(signum) {
+                DefaultAction::Terminate => {
+                    drop(inner);
+                    drop(process);
+                    println!("[kernel] Killed by signal {}.", signum);
+                    exit_current_process_and_run_next(-(signum as i32));
+                    return;
+                }
+                DefaultAction::Stop => inner.signals.stopped = true,
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+            },
+            handler => {
+                let blocked = inner.signals.blocked;
+                inner.signals.blocked |= action.mask | SignalFlags::from_signum(signum);
+                inner.signals.blocked -= SignalFlags::unblockable();
+                let token = inner.memory_set.token();
+                // release current PCB, since storing on the user stack may fault a page in
+                drop(inner);
+                drop(process);
+                let trap_cx = current_trap_cx();
+                let frame = SignalFrame {
+                    x: trap_cx.x,
+                    sepc: trap_cx.sepc,
+                    blocked,
+                };
+                let frame_size = core::mem::size_of::<SignalFrame>();
+                // keep the stack aligned to 16 bytes as the calling convention requires
+                let frame_ptr = (trap_cx.x[2].wrapping_sub(frame_size) & !0xf) as *mut SignalFrame;
+                if copy_to_user(token, frame_ptr, &frame).is_err() {
+                    println!(
+                        "[kernel] No room for the signal frame, killed by signal {}.",
+                        SIGSEGV
+                    );
+                    exit_current_process_and_run_next(-(SIGSEGV as i32));
+                    return;
+                }
+                // the handler returns to the restorer, which calls sigreturn
+                trap_cx.x[1] = action.restorer;
+                trap_cx.x[2] = frame_ptr as usize;
+                trap_cx.x[10] = signum;
+                trap_cx.sepc = handler;
+                return;
+            }
+        }
+    }
+}
+
 lazy_static! {
     /// Creation of initial process
     ///
     /// the name "initproc" may be changed to any other app name like "usertests",
     /// but we have user_shell, so we don't need to change it.
     pub static ref INITPROC: Arc<ProcessControlBlock> = {
-        let inode = open_file("ch8b_initproc", OpenFlags::RDONLY).unwrap();
-        let v = inode.read_all();
-        ProcessControlBlock::new(v.as_slice())
+        let inode = ROOT_INODE.find("ch8b_initproc").unwrap();
+        ProcessControlBlock::new(&inode)
     };
 }
 
@@ -1,20 +1,27 @@
 use super::id::RecycleAllocator;
-use super::{add_task, pid_alloc, PidHandle, TaskControlBlock};
-use crate::fs::{File, Stdin, Stdout};
+use super::signal::SignalState;
+use super::{
+    add_task, insert_into_pid2process, pid_alloc, suspend_current_and_run_next, wakeup_task,
+    PidHandle, TaskControlBlock,
+};
+use crate::fs::{File, Stdin, Stdout, ROOT_INODE};
 use crate::mm::{translated_refmut, MemorySet, KERNEL_SPACE};
-use crate::sync::{Condvar, Mutex, Semaphore, UPSafeCell};
+use crate::sync::{holds_spinlock, Condvar, Mutex, Semaphore, SpinLock, SpinLockGuard};
+use crate::syscall::errno::Errno;
 use crate::trap::{trap_handler, TrapContext};
+use alloc::collections::VecDeque;
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
 use alloc::vec;
 use alloc::vec::Vec;
-use core::cell::RefMut;
+use core::hint::spin_loop;
+use easy_fs::Inode;
 
 pub struct ProcessControlBlock {
     // immutable
     pub pid: PidHandle,
     // mutable
-    inner: UPSafeCell<ProcessControlBlockInner>,
+    inner: SpinLock<ProcessControlBlockInner>,
 }
 
 // LAB5 HINT: you may add data structures for deadlock detection here
@@ -25,6 +32,10 @@ pub struct ProcessControlBlockInner {
     pub children: Vec<Arc<ProcessControlBlock>>,
     pub exit_code: i32,
     pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    /// the current working directory, kept even if it is removed
+    pub cwd: Arc<Inode>,
+    /// absolute path the current working directory was entered by
+    pub cwd_path: String,
     pub tasks: Vec<Option<Arc<TaskControlBlock>>>,
     pub task_res_allocator: RecycleAllocator,
     pub mutex_list: Vec<Option<Arc<dyn Mutex>>>,
@@ -37,6 +48,9 @@ pub struct ProcessControlBlockInner {
     pub mutex_need: Vec<Vec<usize>>,
     pub semaphore_need: Vec<Vec<usize>>,
     pub deadlock_detect: bool,
+    pub signals: SignalState,
+    /// The threads blocked in waitpid or waittid until a child process or a thread exits
+    pub wait_queue: VecDeque<Arc<TaskControlBlock>>,
 }
 
 impl ProcessControlBlockInner {
@@ -70,7 +84,15 @@ impl ProcessControlBlockInner {
         self.tasks[tid].as_ref().unwrap().clone()
     }
 
-    pub fn check_deadlock_mutex(&self) -> isize {
+    /// Wake the threads in waitpid or waittid to check again what they wait for
+    pub fn wake_waiters(&mut self) {
+        for task in self.wait_queue.drain(..) {
+            wakeup_task(task);
+        }
+    }
+
+    /// Whether the threads waiting for mutexes can never all finish
+    pub fn check_deadlock_mutex(&self) -> bool {
         let mut work: Vec<usize> = Vec::new();
         let mut finish: Vec<bool> = Vec::new();
         for i in 0..self.thread_count() {
@@ -106,15 +128,16 @@ impl ProcessControlBlockInner {
             } else {
                 for n in 0..self.thread_count() {
                     if finish[n] == false {
-                        return -0xDEAD;
+                        return true;
                     } 
                 }
                 break;
             }
         }
-        return 0;
+        return false;
     }
-    pub fn check_deadlock_semaphore(&self) -> isize {
+    /// Whether the threads waiting for semaphores can never all finish
+    pub fn check_deadlock_semaphore(&self) -> bool {
         let mut work: Vec<usize> = Vec::new();
         let mut finish: Vec<bool> = Vec::new();
         for i in 0..self.thread_count() {
@@ -149,59 +172,79 @@ impl ProcessControlBlockInner {
             } else {
                 for n in 0..self.thread_count() {
                     if finish[n] == false {
-                        return -0xDEAD;
+                        return true;
                     } 
                 }
                 break;
             }
         }
-        return 0;
+        return false;
     }
 
 }
 
 impl ProcessControlBlock {
-    pub fn inner_exclusive_access(&self) -> RefMut<'_, ProcessControlBlockInner> {
+    pub fn inner_exclusive_access(&self) -> SpinLockGuard<'_, ProcessControlBlockInner> {
         self.inner.exclusive_access()
     }
+    /// Call `f` over the memory set with the process locked, and again after giving way
+    /// while it fails with EAGAIN, since the filesystem of the files it maps may be held
+    /// by a task sleeping on the disk, which cannot be waited for under the lock
+    pub fn with_memory_set<T>(
+        &self,
+        mut f: impl FnMut(&mut MemorySet) -> Result<T, Errno>,
+    ) -> Result<T, Errno> {
+        loop {
+            let result = f(&mut self.inner_exclusive_access().memory_set);
+            match result {
+                Err(Errno::EAGAIN) if !holds_spinlock() => suspend_current_and_run_next(),
+                // a caller under another lock cannot switch out, and can only poll
+                Err(Errno::EAGAIN) => spin_loop(),
+                result => return result,
+            }
+        }
+    }
 
     // LAB5 HINT: How to initialize deadlock data structures?
-    pub fn new(elf_data: &[u8]) -> Arc<Self> {
+    pub fn new(elf_inode: &Arc<Inode>) -> Arc<Self> {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, ustack_base, entry_point) =
+            MemorySet::from_elf(elf_inode).expect("The elf of initproc cannot be loaded!");
         // allocate a pid
         let pid_handle = pid_alloc();
         let process = Arc::new(Self {
             pid: pid_handle,
-            inner: unsafe {
-                UPSafeCell::new(ProcessControlBlockInner {
-                    is_zombie: false,
-                    memory_set,
-                    parent: None,
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: vec![
-                        // 0 -> stdin
-                        Some(Arc::new(Stdin)),
-                        // 1 -> stdout
-                        Some(Arc::new(Stdout)),
-                        // 2 -> stderr
-                        Some(Arc::new(Stdout)),
-                    ],
-                    tasks: Vec::new(),
-                    task_res_allocator: RecycleAllocator::new(),
-                    mutex_list: Vec::new(),
-                    semaphore_list: Vec::new(),
-                    condvar_list: Vec::new(),
-                    mutex_available: Vec::new(),
-                    semaphore_available: Vec::new(),
-                    mutex_allocation: Vec::new(),
-                    semaphore_allocation: Vec::new(),
-                    mutex_need: Vec::new(),
-                    semaphore_need: Vec::new(),
-                    deadlock_detect: false,
-                })
-            },
+            inner: SpinLock::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                fd_table: vec![
+                    // 0 -> stdin
+                    Some(Arc::new(Stdin)),
+                    // 1 -> stdout
+                    Some(Arc::new(Stdout)),
+                    // 2 -> stderr
+                    Some(Arc::new(Stdout)),
+                ],
+                cwd: Arc::clone(&ROOT_INODE),
+                cwd_path: String::from("/"),
+                tasks: Vec::new(),
+                task_res_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                mutex_available: Vec::new(),
+                semaphore_available: Vec::new(),
+                mutex_allocation: Vec::new(),
+                semaphore_allocation: Vec::new(),
+                mutex_need: Vec::new(),
+                semaphore_need: Vec::new(),
+                deadlock_detect: false,
+                signals: SignalState::new(),
+                wait_queue: VecDeque::new(),
+            }),
         });
         // create a main thread, we should allocate ustack and trap_cx here
         let task = Arc::new(TaskControlBlock::new(
@@ -230,6 +273,7 @@ impl ProcessControlBlock {
         let mut process_inner = process.inner_exclusive_access();
         process_inner.tasks.push(Some(Arc::clone(&task)));
         drop(process_inner);
+        insert_into_pid2process(process.getpid(), Arc::clone(&process));
         // add main thread to scheduler
         add_task(task);
         process
@@ -238,13 +282,20 @@ impl ProcessControlBlock {
     // LAB5 HINT: How to initialize deadlock data structures?
     /// Load a new elf to replace the original application address space and start execution
     /// Only support processes with a single thread.
-    pub fn exec(self: &Arc<Self>, elf_data: &[u8], args: Vec<String>) {
+    /// Fail without changing anything if the elf cannot be loaded, as [`MemorySet::from_elf`] does.
+    pub fn exec(
+        self: &Arc<Self>,
+        elf_inode: &Arc<Inode>,
+        args: Vec<String>,
+    ) -> Result<(), Errno> {
         assert_eq!(self.inner_exclusive_access().thread_count(), 1);
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data);
-        let new_token = memory_set.token();
+        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_inode)?;
+        // the shared file mappings are written back before the lock is held to drop them
+        let _ = self.with_memory_set(|memory_set| memory_set.sync_all());
         // substitute memory_set
         self.inner_exclusive_access().memory_set = memory_set;
+        self.inner_exclusive_access().signals.exec();
         // then we alloc user resource for main thread again
         // since memory_set has been changed
         let task = self.inner_exclusive_access().get_task(0);
@@ -265,15 +316,42 @@ impl ProcessControlBlock {
         process_inner.semaphore_allocation.push(vec![0;0]);
         process_inner.semaphore_need.push(vec![0;0]);
         drop(process_inner);
-        // self.inner_exclusive_access()
-        // push arguments on user stack
-        let mut user_sp = task_inner.res.as_mut().unwrap().ustack_top();
+        // push arguments on user stack and initialize trap_cx
+        let trap_cx = Self::init_main_trap_cx(
+            &mut self.inner_exclusive_access().memory_set,
+            task_inner.res.as_ref().unwrap().ustack_top(),
+            entry_point,
+            task.kernel_stack.get_top(),
+            &args,
+        );
+        *task_inner.get_trap_cx() = trap_cx;
+        Ok(())
+    }
+
+    /// Push `args` on the user stack whose top is `ustack_top` in `memory_set`,
+    /// and build the trap context of a main thread from them.
+    fn init_main_trap_cx(
+        memory_set: &mut MemorySet,
+        ustack_top: usize,
+        entry_point: usize,
+        kernel_stack_top: usize,
+        args: &[String],
+    ) -> TrapContext {
+        // the pages of user stack are allocated lazily, map those to be written
+        // and keep them resident while they are written through their frames
+        let args_size = (args.len() + 1) * core::mem::size_of::<usize>()
+            + args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+        let _pins = memory_set
+            .populate((ustack_top - args_size).into(), ustack_top.into())
+            .expect("No memory for the arguments!");
+        let token = memory_set.token();
+        let mut user_sp = ustack_top;
         user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
         let argv_base = user_sp;
         let mut argv: Vec<_> = (0..=args.len())
             .map(|arg| {
                 translated_refmut(
-                    new_token,
+                    token,
                     (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize,
                 )
             })
@@ -284,34 +362,114 @@ impl ProcessControlBlock {
             *argv[i] = user_sp;
             let mut p = user_sp;
             for c in args[i].as_bytes() {
-                *translated_refmut(new_token, p as *mut u8) = *c;
+                *translated_refmut(token, p as *mut u8) = *c;
                 p += 1;
             }
-            *translated_refmut(new_token, p as *mut u8) = 0;
+            *translated_refmut(token, p as *mut u8) = 0;
         }
         // make the user_sp aligned to 8B for k210 platform
         user_sp -= user_sp % core::mem::size_of::<usize>();
-        // initialize trap_cx
         let mut trap_cx = TrapContext::app_init_context(
             entry_point,
             user_sp,
             KERNEL_SPACE.exclusive_access().token(),
-            task.kernel_stack.get_top(),
+            kernel_stack_top,
             trap_handler as usize,
         );
         trap_cx.x[10] = args.len();
         trap_cx.x[11] = argv_base;
+        trap_cx
+    }
+
+    /// Create a child process running a new elf directly, without copying
+    /// the address space of the parent as fork does.
+    /// The child inherits the fd table of the parent.
+    /// Fail if the elf cannot be loaded, as [`MemorySet::from_elf`] does.
+    pub fn spawn(
+        self: &Arc<Self>,
+        elf_inode: &Arc<Inode>,
+        args: Vec<String>,
+    ) -> Result<Arc<Self>, Errno> {
+        // memory_set with elf program headers/trampoline
+        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_inode)?;
+        // alloc a pid
+        let pid = pid_alloc();
+        // copy fd table
+        let new_fd_table = self.inner_exclusive_access().fd_table.clone();
+        let cwd = self.inner_exclusive_access().cwd.clone();
+        let cwd_path = self.inner_exclusive_access().cwd_path.clone();
+        // the mask is inherited and the handlers are forgotten as by fork and exec
+        let mut signals = self.inner_exclusive_access().signals.fork();
+        signals.exec();
+        // create child process pcb
+        let child = Arc::new(Self {
+            pid,
+            inner: SpinLock::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                fd_table: new_fd_table,
+                cwd,
+                cwd_path,
+                tasks: Vec::new(),
+                task_res_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                mutex_available: Vec::new(),
+                semaphore_available: Vec::new(),
+                mutex_allocation: Vec::new(),
+                semaphore_allocation: Vec::new(),
+                mutex_need: Vec::new(),
+                semaphore_need: Vec::new(),
+                deadlock_detect: false,
+                signals,
+                wait_queue: VecDeque::new(),
+            }),
+        });
+        // add child
+        self.inner_exclusive_access()
+            .children
+            .push(Arc::clone(&child));
+        insert_into_pid2process(child.getpid(), Arc::clone(&child));
+        // create main thread of child process, allocating ustack and trap_cx
+        let task = Arc::new(TaskControlBlock::new(Arc::clone(&child), ustack_base, true));
+        // attach task to child process
+        let mut child_inner = child.inner_exclusive_access();
+        child_inner.tasks.push(Some(Arc::clone(&task)));
+        child_inner.mutex_allocation.push(vec![0; 0]);
+        child_inner.mutex_need.push(vec![0; 0]);
+        child_inner.semaphore_allocation.push(vec![0; 0]);
+        child_inner.semaphore_need.push(vec![0; 0]);
+        drop(child_inner);
+        // push arguments on user stack and initialize trap_cx
+        let task_inner = task.inner_exclusive_access();
+        let trap_cx = Self::init_main_trap_cx(
+            &mut child.inner_exclusive_access().memory_set,
+            task_inner.res.as_ref().unwrap().ustack_top(),
+            entry_point,
+            task.kernel_stack.get_top(),
+            &args,
+        );
         *task_inner.get_trap_cx() = trap_cx;
+        drop(task_inner);
+        // add main thread to scheduler
+        add_task(task);
+        Ok(child)
     }
 
     // LAB5 HINT: How to initialize deadlock data structures?
     /// Fork from parent to child
     /// Only support processes with a single thread.
-    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
+    /// Fail if the memory of the parent cannot be shared, as [`MemorySet::from_existed_user`] does.
+    pub fn fork(self: &Arc<Self>) -> Result<Arc<Self>, Errno> {
         let mut parent = self.inner_exclusive_access();
         assert_eq!(parent.thread_count(), 1);
-        // clone parent's memory_set completely including trampoline/ustacks/trap_cxs
-        let memory_set = MemorySet::from_existed_user(&parent.memory_set);
+        // clone parent's memory_set including trampoline/ustacks/trap_cxs,
+        // whose user pages are shared copy-on-write
+        let memory_set = MemorySet::from_existed_user(&mut parent.memory_set)?;
         // alloc a pid
         let pid = pid_alloc();
         // copy fd table
@@ -326,31 +484,34 @@ impl ProcessControlBlock {
         // create child process pcb
         let child = Arc::new(Self {
             pid,
-            inner: unsafe {
-                UPSafeCell::new(ProcessControlBlockInner {
-                    is_zombie: false,
-                    memory_set,
-                    parent: Some(Arc::downgrade(self)),
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: new_fd_table,
-                    tasks: Vec::new(),
-                    task_res_allocator: RecycleAllocator::new(),
-                    mutex_list: Vec::new(),
-                    semaphore_list: Vec::new(),
-                    condvar_list: Vec::new(),
-                    mutex_available: Vec::new(),
-                    semaphore_available: Vec::new(),
-                    mutex_allocation: Vec::new(),
-                    semaphore_allocation: Vec::new(),
-                    mutex_need: Vec::new(),
-                    semaphore_need: Vec::new(),
-                    deadlock_detect: false,
-                })
-            },
+            inner: SpinLock::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                fd_table: new_fd_table,
+                cwd: parent.cwd.clone(),
+                cwd_path: parent.cwd_path.clone(),
+                tasks: Vec::new(),
+                task_res_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                mutex_available: Vec::new(),
+                semaphore_available: Vec::new(),
+                mutex_allocation: Vec::new(),
+                semaphore_allocation: Vec::new(),
+                mutex_need: Vec::new(),
+                semaphore_need: Vec::new(),
+                deadlock_detect: false,
+                signals: parent.signals.fork(),
+                wait_queue: VecDeque::new(),
+            }),
         });
         // add child
         parent.children.push(Arc::clone(&child));
+        insert_into_pid2process(child.getpid(), Arc::clone(&child));
         // create main thread of child process
         let task = Arc::new(TaskControlBlock::new(
             Arc::clone(&child),
@@ -380,7 +541,7 @@ impl ProcessControlBlock {
         drop(task_inner);
         // add this thread to scheduler
         add_task(task);
-        child
+        Ok(child)
     }
 
     pub fn getpid(&self) -> usize {
@@ -391,28 +552,30 @@ impl ProcessControlBlock {
         let memory_set = MemorySet::kernel_copy();
         let process = Arc::new(ProcessControlBlock {
             pid: super::pid_alloc(),
-            inner: unsafe {
-                UPSafeCell::new(ProcessControlBlockInner {
-                    is_zombie: false,
-                    memory_set: memory_set,
-                    parent: None,
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: Vec::new(),
-                    tasks: Vec::new(),
-                    task_res_allocator: RecycleAllocator::new(),
-                    mutex_list: Vec::new(),
-                    semaphore_list: Vec::new(),
-                    condvar_list: Vec::new(),
-                    mutex_available: Vec::new(),
-                    semaphore_available: Vec::new(),
-                    mutex_allocation: Vec::new(),
-                    semaphore_allocation: Vec::new(),
-                    mutex_need: Vec::new(),
-                    semaphore_need: Vec::new(),
-                    deadlock_detect: false,
-                })
-            },
+            inner: SpinLock::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set: memory_set,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                fd_table: Vec::new(),
+                cwd: Arc::clone(&ROOT_INODE),
+                cwd_path: String::from("/"),
+                tasks: Vec::new(),
+                task_res_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                mutex_available: Vec::new(),
+                semaphore_available: Vec::new(),
+                mutex_allocation: Vec::new(),
+                semaphore_allocation: Vec::new(),
+                mutex_need: Vec::new(),
+                semaphore_need: Vec::new(),
+                deadlock_detect: false,
+                signals: SignalState::new(),
+                wait_queue: VecDeque::new(),
+            }),
         });
         process
     }
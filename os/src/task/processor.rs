@@ -3,15 +3,23 @@
 //! Here, the continuous operation of user apps in CPU is maintained,
 //! the current running state of CPU is recorded,
 //! and the replacement and transfer of control flow of different applications are executed.
+//! Every hart has its own [`Processor`], found by the hart id kept in `tp`,
+//! and all of them fetch tasks from the shared ready queue.
 
 use super::__switch;
 use super::process::ProcessControlBlock;
 use super::{fetch_task, TaskStatus};
 use super::{TaskContext, TaskControlBlock};
-use crate::sync::UPSafeCell;
+use crate::config::MAX_HARTS;
+use crate::sync::SpinLock;
+use crate::timer::{check_timer, get_time_ms};
 use crate::trap::TrapContext;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::*;
+use riscv::register::sstatus;
 
 /// Processor management structure
 pub struct Processor {
@@ -40,8 +48,31 @@ impl Processor {
 }
 
 lazy_static! {
-    /// PROCESSOR instance through lazy_static!
-    pub static ref PROCESSOR: UPSafeCell<Processor> = unsafe { UPSafeCell::new(Processor::new()) };
+    /// The processors of the harts, by hart id
+    pub static ref PROCESSORS: Vec<SpinLock<Processor>> =
+        (0..MAX_HARTS).map(|_| SpinLock::new(Processor::new())).collect();
+}
+
+/// The harts running tasks, where hart `n` is bit `n`
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// The id of this hart, which entry.asm keeps in `tp` and the trap entry restores
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) id);
+    }
+    id
+}
+
+/// The harts running tasks, where hart `n` is bit `n`
+pub fn online_harts() -> usize {
+    ONLINE_HARTS.load(Ordering::Acquire)
+}
+
+/// The processor of this hart
+fn current_processor() -> &'static SpinLock<Processor> {
+    &PROCESSORS[hart_id()]
 }
 
 /// The main part of process execution and scheduling
@@ -49,37 +80,58 @@ lazy_static! {
 /// Loop fetch_task to get the process that needs to run,
 /// and switch the process through __switch
 pub fn run_tasks() {
+    ONLINE_HARTS.fetch_or(1 << hart_id(), Ordering::AcqRel);
     loop {
-        let mut processor = PROCESSOR.exclusive_access();
+        // the kernel runs with interrupts disabled, so take the pending ones here,
+        // such as the completion of a disk request a sleeping task waits for
+        unsafe {
+            sstatus::set_sie();
+            sstatus::clear_sie();
+        }
+        let mut processor = current_processor().exclusive_access();
         if let Some(task) = fetch_task() {
             // println!("task get!");
+            // a task woken up by another hart may not have switched out yet
+            while task.on_cpu.load(Ordering::Acquire) {
+                spin_loop();
+            }
+            task.on_cpu.store(true, Ordering::Relaxed);
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             // access coming task TCB exclusively
             let mut task_inner = task.inner_exclusive_access();
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
             task_inner.task_status = TaskStatus::Running;
+            if task_inner.first_time.is_none() {
+                task_inner.first_time = Some(get_time_ms());
+            }
             drop(task_inner);
             // release coming task TCB manually
-            processor.current = Some(task);
+            processor.current = Some(Arc::clone(&task));
             // release processor manually
             drop(processor);
             unsafe {
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
+            // the task has saved its context, so other harts may run it from now on,
+            // and its kernel stack lives until `task` drops here at the latest
+            task.on_cpu.store(false, Ordering::Release);
         } else {
-            println!("no tasks available in run_tasks");
+            drop(processor);
+            // nothing to run, but the sleeping tasks may be due
+            check_timer();
+            spin_loop();
         }
     }
 }
 
 /// Get current task through take, leaving a None in its place
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().take_current()
+    current_processor().exclusive_access().take_current()
 }
 
 /// Get a copy of the current task
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().current()
+    current_processor().exclusive_access().current()
 }
 
 pub fn current_process() -> Arc<ProcessControlBlock> {
@@ -112,7 +164,7 @@ pub fn current_trap_cx_user_va() -> usize {
 
 /// Return to idle control flow for new scheduling
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
-    let mut processor = PROCESSOR.exclusive_access();
+    let mut processor = current_processor().exclusive_access();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
     drop(processor);
     unsafe {
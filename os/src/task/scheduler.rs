@@ -0,0 +1,270 @@
+//! Scheduling policies behind the [`Scheduler`] trait
+//!
+//! [`TaskManager`](super::manager::TaskManager) holds one of them, chosen at
+//! boot through [`SchedPolicy`].
+
+use super::TaskControlBlock;
+use crate::config::{DEFAULT_TIME_SLICE, MLFQ_BOOST_INTERVAL, MLFQ_LEVELS};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// A scheduling policy over the tasks which are ready to run
+pub trait Scheduler {
+    /// Add a task which is ready to run
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    /// Take the next task to run out of the ready tasks
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>>;
+    /// Remove a task from the ready tasks if it is there
+    fn remove(&mut self, task: &Arc<TaskControlBlock>);
+    /// Account a timer tick to the running task,
+    /// return whether it should give up the CPU
+    fn on_tick(&mut self, task: &Arc<TaskControlBlock>) -> bool;
+}
+
+/// Remove `task` from `queue` if it is there
+fn remove_from(queue: &mut VecDeque<Arc<TaskControlBlock>>, task: &Arc<TaskControlBlock>) {
+    if let Some(idx) = queue.iter().position(|t| Arc::ptr_eq(t, task)) {
+        queue.remove(idx);
+    }
+}
+
+/// A non-preemptive FIFO scheduler: a task runs until it yields, blocks or exits.
+pub struct FifoScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl FifoScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler for FifoScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) {
+        remove_from(&mut self.ready_queue, task);
+    }
+    fn on_tick(&mut self, _task: &Arc<TaskControlBlock>) -> bool {
+        false
+    }
+}
+
+/// A round-robin scheduler which preempts a task after `time_slice` ticks.
+pub struct RoundRobinScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+    time_slice: usize,
+}
+
+impl RoundRobinScheduler {
+    pub fn new(time_slice: usize) -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+            time_slice: time_slice.max(1),
+        }
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        // a task gets a whole time slice every time it is queued
+        task.inner_exclusive_access().ticks = 0;
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) {
+        remove_from(&mut self.ready_queue, task);
+    }
+    fn on_tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.ticks += 1;
+        task_inner.ticks >= self.time_slice
+    }
+}
+
+/// Compare two passes which may have wrapped around.
+///
+/// As long as every stride is at most half of the range, the distance
+/// between any two passes in the ready queue stays within half of the range,
+/// so the sign of the wrapping difference gives the real order.
+fn cmp_pass(a: usize, b: usize) -> Ordering {
+    (a.wrapping_sub(b) as isize).cmp(&0)
+}
+
+/// A stride scheduler: the task with the smallest pass runs next and its pass
+/// is advanced by its stride, which `sys_set_priority` sets to BIG_STRIDE / priority.
+pub struct StrideScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl StrideScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler for StrideScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let (idx, _) = self
+            .ready_queue
+            .iter()
+            .map(|task| task.inner_exclusive_access().pass)
+            .enumerate()
+            .min_by(|(_, a), (_, b)| cmp_pass(*a, *b))?;
+        let task = self.ready_queue.remove(idx)?;
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.pass = task_inner.pass.wrapping_add(task_inner.stride);
+        drop(task_inner);
+        Some(task)
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) {
+        remove_from(&mut self.ready_queue, task);
+    }
+    fn on_tick(&mut self, _task: &Arc<TaskControlBlock>) -> bool {
+        true
+    }
+}
+
+/// A multi-level feedback queue scheduler.
+///
+/// Tasks start at level 0. A task using up the time slice of its level,
+/// which doubles at every level, is moved one level down. Every
+/// `MLFQ_BOOST_INTERVAL` ticks all tasks are moved back to level 0 so that
+/// long running tasks do not starve.
+pub struct MlfqScheduler {
+    queues: Vec<VecDeque<Arc<TaskControlBlock>>>,
+    time_slice: usize,
+    ticks_since_boost: usize,
+}
+
+impl MlfqScheduler {
+    pub fn new(time_slice: usize) -> Self {
+        Self {
+            queues: (0..MLFQ_LEVELS).map(|_| VecDeque::new()).collect(),
+            time_slice: time_slice.max(1),
+            ticks_since_boost: 0,
+        }
+    }
+    fn boost(&mut self) {
+        for level in 1..MLFQ_LEVELS {
+            while let Some(task) = self.queues[level].pop_front() {
+                let mut task_inner = task.inner_exclusive_access();
+                task_inner.level = 0;
+                task_inner.ticks = 0;
+                drop(task_inner);
+                self.queues[0].push_back(task);
+            }
+        }
+    }
+}
+
+impl Scheduler for MlfqScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let level = task.inner_exclusive_access().level.min(MLFQ_LEVELS - 1);
+        self.queues[level].push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) {
+        for queue in self.queues.iter_mut() {
+            remove_from(queue, task);
+        }
+    }
+    fn on_tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        self.ticks_since_boost += 1;
+        let mut task_inner = task.inner_exclusive_access();
+        if self.ticks_since_boost >= MLFQ_BOOST_INTERVAL {
+            self.ticks_since_boost = 0;
+            task_inner.level = 0;
+            task_inner.ticks = 0;
+            drop(task_inner);
+            self.boost();
+            return true;
+        }
+        let level = task_inner.level.min(MLFQ_LEVELS - 1);
+        task_inner.ticks += 1;
+        if task_inner.ticks >= self.time_slice << level {
+            // used up the whole slice of this level
+            task_inner.ticks = 0;
+            task_inner.level = (level + 1).min(MLFQ_LEVELS - 1);
+            return true;
+        }
+        // preempt if a task of a higher level became ready
+        self.queues[..level].iter().any(|queue| !queue.is_empty())
+    }
+}
+
+/// Scheduling policies the kernel can be booted with
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SchedPolicy {
+    Fifo,
+    /// round robin with a time slice in ticks
+    RoundRobin(usize),
+    Stride,
+    /// multi-level feedback queue with a time slice of the top level in ticks
+    Mlfq(usize),
+}
+
+impl SchedPolicy {
+    /// Parse a policy name, with an optional time slice in ticks
+    fn parse(name: &str, time_slice: Option<&str>) -> Option<Self> {
+        let time_slice = time_slice
+            .and_then(|ticks| ticks.parse::<usize>().ok())
+            .filter(|ticks| *ticks > 0)
+            .unwrap_or(DEFAULT_TIME_SLICE);
+        match name {
+            "fifo" => Some(Self::Fifo),
+            "rr" => Some(Self::RoundRobin(time_slice)),
+            "stride" => Some(Self::Stride),
+            "mlfq" => Some(Self::Mlfq(time_slice)),
+            _ => None,
+        }
+    }
+    /// The policy chosen at build time through the `SCHED` and `TIMESLICE`
+    /// environment variables, stride scheduling by default
+    pub fn from_build_option() -> Self {
+        option_env!("SCHED")
+            .and_then(|name| Self::parse(name, option_env!("TIMESLICE")))
+            .unwrap_or(Self::Stride)
+    }
+    /// The policy chosen by `sched=<name>` and `timeslice=<ticks>`
+    /// on the kernel command line, if any
+    pub fn from_bootargs(bootargs: &str) -> Option<Self> {
+        let mut name = None;
+        let mut time_slice = None;
+        for arg in bootargs.split_whitespace() {
+            if let Some(value) = arg.strip_prefix("sched=") {
+                name = Some(value);
+            } else if let Some(value) = arg.strip_prefix("timeslice=") {
+                time_slice = Some(value);
+            }
+        }
+        Self::parse(name?, time_slice)
+    }
+    /// Create a scheduler implementing this policy
+    pub fn scheduler(&self) -> Box<dyn Scheduler> {
+        match *self {
+            Self::Fifo => Box::new(FifoScheduler::new()),
+            Self::RoundRobin(time_slice) => Box::new(RoundRobinScheduler::new(time_slice)),
+            Self::Stride => Box::new(StrideScheduler::new()),
+            Self::Mlfq(time_slice) => Box::new(MlfqScheduler::new(time_slice)),
+        }
+    }
+}
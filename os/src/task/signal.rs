@@ -0,0 +1,241 @@
+//! POSIX-style signals sent to processes
+//!
+//! A signal sent by `sys_kill` or raised by a
+//! fault stays pending in the [`SignalState`] of the process until a thread of it
+//! returns to user space while the signal is not blocked. Then the default action
+//! is taken, or the user handler registered with sigaction is entered on the user
+//! stack, which returns to the interrupted code through sigreturn.
+
+/// The largest signal number
+pub const MAX_SIG: usize = 31;
+/// The handler taking the default action of a signal
+pub const SIG_DFL: usize = 0;
+/// The handler ignoring a signal
+pub const SIG_IGN: usize = 1;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+bitflags! {
+    /// A set of signals, where signal `n` is bit `n`
+    pub struct SignalFlags: u32 {
+        const SIGHUP = 1 << SIGHUP;
+        const SIGINT = 1 << SIGINT;
+        const SIGQUIT = 1 << SIGQUIT;
+        const SIGILL = 1 << SIGILL;
+        const SIGTRAP = 1 << SIGTRAP;
+        const SIGABRT = 1 << SIGABRT;
+        const SIGBUS = 1 << SIGBUS;
+        const SIGFPE = 1 << SIGFPE;
+        const SIGKILL = 1 << SIGKILL;
+        const SIGUSR1 = 1 << SIGUSR1;
+        const SIGSEGV = 1 << SIGSEGV;
+        const SIGUSR2 = 1 << SIGUSR2;
+        const SIGPIPE = 1 << SIGPIPE;
+        const SIGALRM = 1 << SIGALRM;
+        const SIGTERM = 1 << SIGTERM;
+        const SIGSTKFLT = 1 << 16;
+        const SIGCHLD = 1 << SIGCHLD;
+        const SIGCONT = 1 << SIGCONT;
+        const SIGSTOP = 1 << SIGSTOP;
+        const SIGTSTP = 1 << SIGTSTP;
+        const SIGTTIN = 1 << SIGTTIN;
+        const SIGTTOU = 1 << SIGTTOU;
+        const SIGURG = 1 << SIGURG;
+        const SIGXCPU = 1 << 24;
+        const SIGXFSZ = 1 << 25;
+        const SIGVTALRM = 1 << 26;
+        const SIGPROF = 1 << 27;
+        const SIGWINCH = 1 << SIGWINCH;
+        const SIGIO = 1 << 29;
+        const SIGPWR = 1 << 30;
+        const SIGSYS = 1 << 31;
+    }
+}
+
+impl SignalFlags {
+    /// The set of the single signal `signum`, which must be in `1..=MAX_SIG`
+    pub fn from_signum(signum: usize) -> Self {
+        Self::from_bits_truncate(1 << signum)
+    }
+    /// The signals which can be neither caught, blocked nor ignored
+    pub fn unblockable() -> Self {
+        Self::SIGKILL | Self::SIGSTOP
+    }
+    fn stop_signals() -> Self {
+        Self::SIGSTOP | Self::SIGTSTP | Self::SIGTTIN | Self::SIGTTOU
+    }
+}
+
+/// What is done with a signal handled by [`SIG_DFL`]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+impl DefaultAction {
+    pub fn of(signum: usize) -> Self {
+        match signum {
+            SIGCHLD | SIGURG | SIGWINCH => Self::Ignore,
+            SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => Self::Stop,
+            SIGCONT => Self::Continue,
+            _ => Self::Terminate,
+        }
+    }
+}
+
+/// How a signal is handled, as registered with sigaction
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SignalAction {
+    /// The entry of the user handler taking the signal number,
+    /// or [`SIG_DFL`] or [`SIG_IGN`]
+    pub handler: usize,
+    /// Where the handler returns to, which must call sigreturn
+    pub restorer: usize,
+    /// The signals blocked while the handler runs besides the signal itself
+    pub mask: SignalFlags,
+}
+
+impl SignalAction {
+    const DEFAULT: Self = Self {
+        handler: SIG_DFL,
+        restorer: 0,
+        mask: SignalFlags::empty(),
+    };
+}
+
+/// The registers of the interrupted user code and its signal mask, which are saved
+/// on the user stack while a handler runs and restored by sigreturn
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SignalFrame {
+    /// General-Purpose Register x0-31
+    pub x: [usize; 32],
+    pub sepc: usize,
+    pub blocked: SignalFlags,
+}
+
+/// The signals of a process
+pub struct SignalState {
+    /// The signals sent but not handled yet
+    pub pending: SignalFlags,
+    /// The signals which stay pending instead of being handled
+    pub blocked: SignalFlags,
+    /// The actions of the signals, by signal number
+    pub actions: [SignalAction; MAX_SIG + 1],
+    /// Whether the process is stopped until SIGCONT or SIGKILL arrives
+    pub stopped: bool,
+}
+
+impl SignalState {
+    pub fn new() -> Self {
+        Self {
+            pending: SignalFlags::empty(),
+            blocked: SignalFlags::empty(),
+            actions: [SignalAction::DEFAULT; MAX_SIG + 1],
+            stopped: false,
+        }
+    }
+    /// The state of a child created by fork, with the same actions and mask
+    /// but no pending signal
+    pub fn fork(&self) -> Self {
+        Self {
+            actions: self.actions,
+            blocked: self.blocked,
+            ..Self::new()
+        }
+    }
+    /// Forget the handlers on exec, since the code they point to is gone,
+    /// while the ignored signals stay ignored
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SignalAction::DEFAULT;
+            }
+        }
+    }
+    /// Make `signum` pending. SIGCONT resumes a stopped process at once
+    /// and cancels the pending stop signals, which a stop signal does in turn.
+    pub fn send(&mut self, signum: usize) {
+        if signum == SIGCONT {
+            self.stopped = false;
+            self.pending -= SignalFlags::stop_signals();
+        } else if SignalFlags::stop_signals().contains(SignalFlags::from_signum(signum)) {
+            self.pending -= SignalFlags::SIGCONT;
+        }
+        self.pending |= SignalFlags::from_signum(signum);
+    }
+    /// Make the fault `signum` pending, which cannot be blocked or ignored since the
+    /// faulting instruction would run again, so the default action is taken instead
+    pub fn send_fault(&mut self, signum: usize) {
+        let signal = SignalFlags::from_signum(signum);
+        if self.blocked.contains(signal) || self.actions[signum].handler == SIG_IGN {
+            self.blocked -= signal;
+            self.actions[signum] = SignalAction::DEFAULT;
+        }
+        self.send(signum);
+    }
+    /// The pending signals which are not blocked, where a stopped process only takes SIGKILL
+    fn deliverable(&self) -> SignalFlags {
+        if self.stopped {
+            self.pending & SignalFlags::SIGKILL
+        } else {
+            self.pending - (self.blocked - SignalFlags::unblockable())
+        }
+    }
+    /// Whether taking `signum` does nothing, since it is ignored by its handler
+    /// or by default, where SIGCONT only resumes a stopped process
+    fn ignored(&self, signum: usize) -> bool {
+        match self.actions[signum].handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(
+                DefaultAction::of(signum),
+                DefaultAction::Ignore | DefaultAction::Continue
+            ),
+            _ => false,
+        }
+    }
+    /// Whether a signal is waiting to be handled other than by being ignored,
+    /// which interrupts a blocking syscall
+    pub fn has_deliverable(&self) -> bool {
+        let deliverable = self.deliverable();
+        (1..=MAX_SIG).any(|signum| {
+            deliverable.contains(SignalFlags::from_signum(signum)) && !self.ignored(signum)
+        })
+    }
+    /// Take the lowest pending signal which is not blocked
+    pub fn take_next(&mut self) -> Option<usize> {
+        let deliverable = self.deliverable();
+        if deliverable.is_empty() {
+            return None;
+        }
+        let signum = deliverable.bits().trailing_zeros() as usize;
+        self.pending.remove(SignalFlags::from_signum(signum));
+        Some(signum)
+    }
+}
@@ -2,10 +2,14 @@
 
 use super::id::TaskUserRes;
 use super::{kstack_alloc, KernelStack, ProcessControlBlock, TaskContext};
+use crate::config::{BIG_STRIDE, DEFAULT_PRIORITY, MAX_SYSCALL_NUM};
 use crate::trap::TrapContext;
-use crate::{mm::PhysPageNum, sync::UPSafeCell};
+use crate::{
+    mm::PhysPageNum,
+    sync::{SpinLock, SpinLockGuard},
+};
 use alloc::sync::{Arc, Weak};
-use core::cell::RefMut;
+use core::sync::atomic::AtomicBool;
 
 /// Task control block structure
 ///
@@ -15,14 +19,17 @@ pub struct TaskControlBlock {
     pub process: Weak<ProcessControlBlock>,
     /// Kernel stack corresponding to TID
     pub kernel_stack: KernelStack,
+    /// Whether a hart is on the kernel stack, from switching to the task
+    /// until the task has switched out and may run on another hart
+    pub on_cpu: AtomicBool,
     // mutable
-    inner: UPSafeCell<TaskControlBlockInner>,
+    inner: SpinLock<TaskControlBlockInner>,
 }
 
 /// Structure containing more process content
 ///
 /// Store the contents that will change during operation
-/// and are wrapped by UPSafeCell to provide mutual exclusion
+/// and are wrapped by SpinLock to provide mutual exclusion
 pub struct TaskControlBlockInner {
     /// The physical page number of the frame where the trap context is placed
     pub trap_cx_ppn: PhysPageNum,
@@ -34,6 +41,21 @@ pub struct TaskControlBlockInner {
     pub exit_code: Option<i32>,
     /// Tid and ustack will be deallocated when this goes None
     pub res: Option<TaskUserRes>,
+    /// Number of times each syscall has been invoked by this task
+    pub syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// Time in ms when the task was first scheduled
+    pub first_time: Option<usize>,
+    /// Stride scheduling: pass advanced by stride every time the task is scheduled
+    pub pass: usize,
+    /// Stride scheduling: BIG_STRIDE / priority
+    pub stride: usize,
+    /// Timer ticks used in the current time slice
+    pub ticks: usize,
+    /// Multi-level feedback queue level, 0 is the highest
+    pub level: usize,
+    /// Whether the task has been woken up before it blocked, so that it does not sleep
+    /// the next time it blocks, see [`wakeup_task`](super::wakeup_task)
+    pub wakeup_pending: bool,
 }
 
 /// Simple access to its internal fields
@@ -66,20 +88,26 @@ impl TaskControlBlock {
         Self {
             process: Arc::downgrade(&process),
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    res: Some(res),
-                    trap_cx_ppn,
-                    task_cx: TaskContext::goto_trap_return(kstack_top),
-                    task_status: TaskStatus::Ready,
-                    exit_code: None,
-                })
-            },
+            on_cpu: AtomicBool::new(false),
+            inner: SpinLock::new(TaskControlBlockInner {
+                res: Some(res),
+                trap_cx_ppn,
+                task_cx: TaskContext::goto_trap_return(kstack_top),
+                task_status: TaskStatus::Ready,
+                exit_code: None,
+                syscall_times: [0; MAX_SYSCALL_NUM],
+                first_time: None,
+                pass: 0,
+                stride: BIG_STRIDE / DEFAULT_PRIORITY,
+                ticks: 0,
+                level: 0,
+                wakeup_pending: false,
+            }),
         }
     }
 
-    /// Get the mutex to get the RefMut TaskControlBlockInner
-    pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
+    /// Lock the TaskControlBlockInner
+    pub fn inner_exclusive_access(&self) -> SpinLockGuard<'_, TaskControlBlockInner> {
         let inner = self.inner.exclusive_access();
         // if self.process.upgrade().unwrap().pid.0 > 1 {
         //     if let Some(res) = inner.res.as_ref() {
@@ -117,15 +145,21 @@ impl TaskControlBlock {
             process,
             kernel_stack: KernelStack(kstack_top),
             //kstack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    res: None,
-                    trap_cx_ppn: context_ppn,
-                    task_cx: context,
-                    task_status: TaskStatus::Ready,
-                    exit_code: None,
-                })
-            },
+            on_cpu: AtomicBool::new(false),
+            inner: SpinLock::new(TaskControlBlockInner {
+                res: None,
+                trap_cx_ppn: context_ppn,
+                task_cx: context,
+                task_status: TaskStatus::Ready,
+                exit_code: None,
+                syscall_times: [0; MAX_SYSCALL_NUM],
+                first_time: None,
+                pass: 0,
+                stride: BIG_STRIDE / DEFAULT_PRIORITY,
+                ticks: 0,
+                level: 0,
+                wakeup_pending: false,
+            }),
         }
     }
 }
@@ -2,8 +2,8 @@
 
 use crate::config::CLOCK_FREQ;
 use crate::sbi::set_timer;
-use crate::sync::UPSafeCell;
-use crate::task::{add_task, TaskControlBlock};
+use crate::sync::SpinLock;
+use crate::task::{wakeup_task, TaskControlBlock};
 use alloc::collections::BinaryHeap;
 use alloc::sync::Arc;
 use core::cmp::Ordering;
@@ -60,8 +60,8 @@ impl Ord for TimerCondVar {
 }
 
 lazy_static! {
-    static ref TIMERS: UPSafeCell<BinaryHeap<TimerCondVar>> =
-        unsafe { UPSafeCell::new(BinaryHeap::<TimerCondVar>::new()) };
+    static ref TIMERS: SpinLock<BinaryHeap<TimerCondVar>> =
+        SpinLock::new(BinaryHeap::<TimerCondVar>::new());
 }
 
 pub fn add_timer(expire_ms: usize, task: Arc<TaskControlBlock>) {
@@ -69,12 +69,22 @@ pub fn add_timer(expire_ms: usize, task: Arc<TaskControlBlock>) {
     timers.push(TimerCondVar { expire_ms, task });
 }
 
+/// Forget the timers of `task`, which has stopped sleeping before they expire
+pub fn remove_timer(task: &Arc<TaskControlBlock>) {
+    let mut timers = TIMERS.exclusive_access();
+    let remaining = timers
+        .drain()
+        .filter(|timer| !Arc::ptr_eq(&timer.task, task))
+        .collect();
+    *timers = remaining;
+}
+
 pub fn check_timer() {
     let current_ms = get_time_ms();
     let mut timers = TIMERS.exclusive_access();
     while let Some(timer) = timers.peek() {
         if timer.expire_ms <= current_ms {
-            add_task(Arc::clone(&timer.task));
+            wakeup_task(Arc::clone(&timer.task));
             timers.pop();
         } else {
             break;
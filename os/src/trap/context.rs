@@ -17,6 +17,8 @@ pub struct TrapContext {
     pub kernel_sp: usize,
     /// Virtual address of trap handler entry point in kernel
     pub trap_handler: usize,
+    /// Id of the hart running the application, which goes back to tp on a trap
+    pub hart_id: usize,
 }
 
 impl TrapContext {
@@ -40,6 +42,7 @@ impl TrapContext {
             kernel_satp,
             kernel_sp,
             trap_handler,
+            hart_id: 0,
         };
         cx.set_sp(sp);
         cx
@@ -11,20 +11,27 @@
 //! It then calls different functionality based on what exactly the exception
 //! was. For example, timer interrupts trigger task preemption, and syscalls go
 //! to [`syscall()`].
+//!
+//! In the kernel, traps go through `__alltraps_k` to [`trap_from_kernel()`]
+//! instead. Only the interrupts taken by an idle hart end up there.
 
 mod context;
 
 use crate::config::TRAMPOLINE;
+use crate::drivers::handle_irq;
+use crate::mm::{MapPermission, VirtAddr};
 use crate::syscall::syscall;
+use crate::task::signal::{SIGILL, SIGSEGV};
 use crate::task::{
-    current_trap_cx, current_trap_cx_user_va, current_user_token, exit_current_and_run_next,
-    suspend_current_and_run_next,
+    current_add_fault_signal, current_process, current_trap_cx, current_trap_cx_user_va,
+    current_user_token, handle_signals, hart_id, record_syscall, suspend_current_and_run_next,
+    tick_current_task,
 };
 use crate::timer::{check_timer, set_next_trigger};
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt, Trap},
-    sie, stval, stvec,
+    sie, sscratch, stval, stvec,
 };
 
 core::arch::global_asm!(include_str!("trap.S"));
@@ -34,8 +41,12 @@ pub fn init() {
 }
 
 fn set_kernel_trap_entry() {
+    extern "C" {
+        fn __alltraps_k();
+    }
     unsafe {
-        stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+        stvec::write(__alltraps_k as usize, TrapMode::Direct);
+        sscratch::write(trap_from_kernel as usize);
     }
 }
 
@@ -51,6 +62,12 @@ pub fn enable_timer_interrupt() {
     }
 }
 
+pub fn enable_external_interrupt() {
+    unsafe {
+        sie::set_sext();
+    }
+}
+
 #[no_mangle]
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
@@ -61,36 +78,54 @@ pub fn trap_handler() -> ! {
             // jump to next instruction anyway
             let mut cx = current_trap_cx();
             cx.sepc += 4;
+            let syscall_id = cx.x[17];
+            record_syscall(syscall_id);
             // get system call return value
-            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12], cx.x[13]]);
+            let result = syscall(
+                syscall_id,
+                [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]],
+            );
             // cx is changed during sys_exec, so we have to call it again
             cx = current_trap_cx();
             cx.x[10] = result as usize;
         }
+        Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::InstructionPageFault)
+            if handle_page_fault(scause.cause(), stval) =>
+        {
+            // the page is mapped now, retry the access
+        }
         Trap::Exception(Exception::StoreFault)
         | Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionFault)
         | Trap::Exception(Exception::InstructionPageFault)
         | Trap::Exception(Exception::LoadFault)
         | Trap::Exception(Exception::LoadPageFault) => {
-            println!(
-                "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}, core dumped.",
+            debug!(
+                "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}.",
                 scause.cause(),
                 stval,
                 current_trap_cx().sepc,
             );
-            // page fault exit code
-            exit_current_and_run_next(-2);
+            current_add_fault_signal(SIGSEGV);
         }
         Trap::Exception(Exception::IllegalInstruction) => {
-            println!("[kernel] IllegalInstruction in application, core dumped.");
-            // illegal instruction exit code
-            exit_current_and_run_next(-3);
+            debug!(
+                "[kernel] IllegalInstruction in application, bad instruction = {:#x}.",
+                current_trap_cx().sepc,
+            );
+            current_add_fault_signal(SIGILL);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             set_next_trigger();
             check_timer();
-            suspend_current_and_run_next();
+            if tick_current_task() {
+                suspend_current_and_run_next();
+            }
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            handle_irq();
         }
         _ => {
             panic!(
@@ -103,9 +138,28 @@ pub fn trap_handler() -> ! {
     trap_return();
 }
 
+/// Resolve a page fault of user at `addr` against the area owning the page,
+/// return whether the faulting instruction can be retried
+fn handle_page_fault(cause: Trap, addr: usize) -> bool {
+    let access = match cause {
+        Trap::Exception(Exception::LoadPageFault) => MapPermission::R,
+        Trap::Exception(Exception::StorePageFault) => MapPermission::W,
+        _ => MapPermission::X,
+    };
+    current_process()
+        .with_memory_set(|memory_set| {
+            memory_set.handle_page_fault(VirtAddr::from(addr).floor(), access)
+        })
+        .is_ok()
+}
+
 #[no_mangle]
 pub fn trap_return() -> ! {
+    // this may switch to other tasks, which set their own trap entry
+    handle_signals();
     set_user_trap_entry();
+    // the trap from user space finds the hart it happens on here
+    current_trap_cx().hart_id = hart_id();
     let trap_cx_ptr = current_trap_cx_user_va();
     let user_satp = current_user_token();
     extern "C" {
@@ -126,8 +180,24 @@ pub fn trap_return() -> ! {
 }
 
 #[no_mangle]
-pub fn trap_from_kernel() -> ! {
-    panic!("a trap {:?} from kernel!", scause::read().cause());
+/// Serve an interrupt taken by an idle hart, where no task needs a tick
+pub fn trap_from_kernel() {
+    match scause::read().cause() {
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            handle_irq();
+        }
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            set_next_trigger();
+            check_timer();
+        }
+        cause => {
+            panic!(
+                "a trap {:?} from kernel, stval = {:#x}!",
+                cause,
+                stval::read()
+            );
+        }
+    }
 }
 
 pub use context::TrapContext;
@@ -0,0 +1,63 @@
+//! Error numbers returned by the kernel as negative syscall results
+
+/// A Linux error number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub isize);
+
+impl Errno {
+    /// Operation not permitted
+    pub const EPERM: Errno = Errno(1);
+    /// No such file or directory
+    pub const ENOENT: Errno = Errno(2);
+    /// No such process
+    pub const ESRCH: Errno = Errno(3);
+    /// Interrupted system call
+    pub const EINTR: Errno = Errno(4);
+    /// I/O error
+    pub const EIO: Errno = Errno(5);
+    /// Exec format error
+    pub const ENOEXEC: Errno = Errno(8);
+    /// Bad file descriptor
+    pub const EBADF: Errno = Errno(9);
+    /// No child processes
+    pub const ECHILD: Errno = Errno(10);
+    /// Try again
+    pub const EAGAIN: Errno = Errno(11);
+    /// Out of memory
+    pub const ENOMEM: Errno = Errno(12);
+    /// Permission denied
+    pub const EACCES: Errno = Errno(13);
+    /// Bad address
+    pub const EFAULT: Errno = Errno(14);
+    /// File exists
+    pub const EEXIST: Errno = Errno(17);
+    /// No such device
+    pub const ENODEV: Errno = Errno(19);
+    /// Not a directory
+    pub const ENOTDIR: Errno = Errno(20);
+    /// Is a directory
+    pub const EISDIR: Errno = Errno(21);
+    /// Invalid argument
+    pub const EINVAL: Errno = Errno(22);
+    /// No space left on device
+    pub const ENOSPC: Errno = Errno(28);
+    /// Result too large
+    pub const ERANGE: Errno = Errno(34);
+    /// Resource deadlock would occur
+    pub const EDEADLK: Errno = Errno(35);
+    /// File name too long
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    /// Function not implemented
+    pub const ENOSYS: Errno = Errno(38);
+    /// Directory not empty
+    pub const ENOTEMPTY: Errno = Errno(39);
+}
+
+/// Split the raw return value of a syscall into its result or `Errno`
+pub fn check(ret: isize) -> Result<usize, Errno> {
+    if ret < 0 {
+        Err(Errno(-ret))
+    } else {
+        Ok(ret as usize)
+    }
+}
@@ -0,0 +1,48 @@
+//! The global allocator, which starts in a static buffer and grows
+//! through the program break whenever it runs out
+
+use crate::syscall::sys_sbrk;
+use buddy_system_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+
+const USER_HEAP_SIZE: usize = 16384;
+/// The least the heap grows by at a time
+const HEAP_GROW_SIZE: usize = 16384;
+
+static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
+
+#[global_allocator]
+static HEAP: GrowableHeap = GrowableHeap(LockedHeap::empty());
+
+/// A buddy heap extending itself with sbrk on allocation failure
+struct GrowableHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        if let Ok(ptr) = heap.alloc(layout) {
+            return ptr.as_ptr();
+        }
+        // a region twice the size of a block always holds an aligned one
+        let block = layout.size().max(layout.align()).next_power_of_two();
+        let size = (block * 2).max(HEAP_GROW_SIZE);
+        let start = sys_sbrk(size as isize);
+        if start < 0 {
+            return null_mut();
+        }
+        heap.add_to_heap(start as usize, start as usize + size);
+        heap.alloc(layout).map_or(null_mut(), |ptr| ptr.as_ptr())
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+pub fn init_heap() {
+    unsafe {
+        HEAP.0
+            .lock()
+            .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
+    }
+}
@@ -5,7 +5,10 @@
 
 #[macro_use]
 pub mod console;
+pub mod errno;
+mod heap;
 mod lang_items;
+mod signal;
 mod syscall;
 
 extern crate alloc;
@@ -14,17 +17,11 @@ extern crate core;
 extern crate bitflags;
 
 use alloc::vec::Vec;
-use buddy_system_allocator::LockedHeap;
 pub use console::{flush, STDIN, STDOUT};
+pub use errno::Errno;
+pub use signal::*;
 pub use syscall::*;
 
-const USER_HEAP_SIZE: usize = 16384;
-
-static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
-
-#[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
-
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
     panic!("Heap allocation error, layout = {:?}", layout);
@@ -48,10 +45,7 @@ fn clear_bss() {
 #[link_section = ".text.entry"]
 pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
     clear_bss();
-    unsafe {
-        HEAP.lock()
-            .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
-    }
+    heap::init_heap();
     let mut v: Vec<&'static str> = Vec::new();
     for i in 0..argc {
         let str_start =
@@ -114,6 +108,7 @@ pub struct SyscallInfo {
 
 const MAX_SYSCALL_NUM: usize = 500;
 
+#[repr(C)]
 #[derive(Debug)]
 pub struct TaskInfo {
     pub status: TaskStatus,
@@ -174,12 +169,54 @@ bitflags! {
     }
 }
 
-const AT_FDCWD: isize = -100;
+pub const AT_FDCWD: isize = -100;
+pub const AT_REMOVEDIR: usize = 0x200;
+
+/// Flag of waitpid and waittid to fail with EAGAIN instead of blocking
+pub const WNOHANG: usize = 1;
+
+/// Key of shmget which always creates a new segment
+pub const IPC_PRIVATE: usize = 0;
+pub const IPC_CREAT: usize = 0o1000;
+pub const IPC_EXCL: usize = 0o2000;
+/// Flag of shmat to attach the segment read-only
+pub const SHM_RDONLY: usize = 0o10000;
+/// Command of shmctl to remove the segment
+pub const IPC_RMID: usize = 0;
+
+/// Flag of mmap to share the mapping with forked children and write it back to the file
+pub const MAP_SHARED: usize = 0x01;
+/// Flag of mmap to copy the mapping on write
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_ANONYMOUS: usize = 0x20;
+pub const MS_ASYNC: usize = 1;
+pub const MS_INVALIDATE: usize = 2;
+pub const MS_SYNC: usize = 4;
 
 pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_openat(AT_FDCWD as usize, path, flags.bits, OpenFlags::RDWR.bits)
 }
 
+pub fn openat(dirfd: usize, path: &str, flags: OpenFlags) -> isize {
+    sys_openat(dirfd, path, flags.bits, OpenFlags::RDWR.bits)
+}
+
+pub fn mkdir(path: &str) -> isize {
+    sys_mkdirat(AT_FDCWD as usize, path, 0)
+}
+
+pub fn mkdirat(dirfd: usize, path: &str) -> isize {
+    sys_mkdirat(dirfd, path, 0)
+}
+
+pub fn chdir(path: &str) -> isize {
+    sys_chdir(path)
+}
+
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    sys_getcwd(buf)
+}
+
 pub fn close(fd: usize) -> isize {
     if fd == STDOUT {
         console::flush();
@@ -203,6 +240,10 @@ pub fn unlink(path: &str) -> isize {
     sys_unlinkat(AT_FDCWD as usize, path, 0)
 }
 
+pub fn rmdir(path: &str) -> isize {
+    sys_unlinkat(AT_FDCWD as usize, path, AT_REMOVEDIR)
+}
+
 pub fn fstat(fd: usize, st: &Stat) -> isize {
     sys_fstat(fd, st)
 }
@@ -249,24 +290,14 @@ pub fn set_priority(prio: isize) -> isize {
 }
 
 pub fn wait(exit_code: &mut i32) -> isize {
-    loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
-            -2 => {
-                sys_yield();
-            }
-            n => {
-                return n;
-            }
-        }
-    }
+    waitpid(usize::MAX, exit_code)
 }
 
+/// Block until the child `pid` exits, restarting the wait after a signal handler
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(pid as isize, exit_code as *mut _) {
-            -2 => {
-                sys_yield();
-            }
+        match sys_waitpid(pid as isize, exit_code as *mut _, 0) {
+            ret if ret == -Errno::EINTR.0 => {}
             n => {
                 return n;
             }
@@ -284,16 +315,119 @@ pub fn sleep(period_ms: usize) {
         sys_yield();
     }
 }
+/// Map private anonymous memory at `start`
 pub fn mmap(start: usize, len: usize, prot: usize) -> isize {
-    sys_mmap(start, len, prot)
+    sys_mmap(start, len, prot, MAP_PRIVATE | MAP_ANONYMOUS, usize::MAX, 0)
+}
+
+/// Map the file `fd` from `offset` at `start`, shared or private as `flags` tells
+pub fn mmap_file(
+    start: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> isize {
+    sys_mmap(start, len, prot, flags, fd, offset)
+}
+
+/// Move the program break to `addr` and return it, or return the current one if `addr` is 0
+pub fn brk(addr: usize) -> isize {
+    sys_brk(addr)
+}
+
+/// Move the program break by `increment` bytes and return the old one
+pub fn sbrk(increment: isize) -> isize {
+    sys_sbrk(increment)
+}
+
+/// Send the signal `signum` to the process `pid`
+pub fn kill(pid: usize, signum: usize) -> isize {
+    sys_kill(pid, signum)
+}
+
+/// Change the action of the signal `signum`, storing the previous one in `old_action`.
+/// A handler returns through sigreturn, which is filled in as its restorer.
+pub fn sigaction(
+    signum: usize,
+    action: Option<&SignalAction>,
+    old_action: Option<&mut SignalAction>,
+) -> isize {
+    let action = action.map(|action| {
+        let mut action = *action;
+        if action.handler != SIG_DFL && action.handler != SIG_IGN {
+            action.restorer = signal::sigreturn_trampoline();
+        }
+        action
+    });
+    sys_sigaction(
+        signum,
+        action
+            .as_ref()
+            .map_or(core::ptr::null(), |action| action as *const _),
+        old_action.map_or(core::ptr::null_mut(), |action| action as *mut _),
+    )
+}
+
+/// Change the blocked signals by `set` as `how` tells, storing the previous ones in `old_set`
+pub fn sigprocmask(
+    how: usize,
+    set: Option<SignalFlags>,
+    old_set: Option<&mut SignalFlags>,
+) -> isize {
+    let set = set.map(|set| set.bits());
+    let mut old = 0u32;
+    let ret = sys_sigprocmask(
+        how,
+        set.as_ref().map_or(core::ptr::null(), |set| set as *const _),
+        if old_set.is_some() {
+            &mut old as *mut _
+        } else {
+            core::ptr::null_mut()
+        },
+    );
+    if let Some(old_set) = old_set {
+        *old_set = SignalFlags::from_bits_truncate(old);
+    }
+    ret
+}
+
+pub fn mprotect(start: usize, len: usize, prot: usize) -> isize {
+    sys_mprotect(start, len, prot)
+}
+
+pub fn msync(start: usize, len: usize, flags: usize) -> isize {
+    sys_msync(start, len, flags)
 }
 
 pub fn munmap(start: usize, len: usize) -> isize {
     sys_munmap(start, len)
 }
 
+pub fn shmget(key: usize, size: usize, flags: usize) -> isize {
+    sys_shmget(key, size, flags)
+}
+
+/// Attach a shared memory segment at `addr`, or where the kernel chooses if it is 0
+pub fn shmat(id: usize, addr: usize, flags: usize) -> isize {
+    sys_shmat(id, addr, flags)
+}
+
+pub fn shmdt(addr: usize) -> isize {
+    sys_shmdt(addr)
+}
+
+pub fn shmctl(id: usize, cmd: usize) -> isize {
+    sys_shmctl(id, cmd)
+}
+
 pub fn spawn(path: &str) -> isize {
-    sys_spawn(path)
+    sys_spawn(path, &[path.as_ptr(), core::ptr::null::<u8>()])
+}
+
+pub fn spawn_with_args(path: &str, args: &[*const u8]) -> isize {
+    sys_spawn(path, args)
 }
 
 pub fn dup(fd: usize) -> isize {
@@ -313,13 +447,12 @@ pub fn thread_create(entry: usize, arg: usize) -> isize {
 pub fn gettid() -> isize {
     sys_gettid()
 }
-pub fn waittid(tid: usize) -> isize {
+/// Block until the thread `tid` exits and store its exit code, restarting the wait after a signal handler
+pub fn waittid(tid: usize, exit_code: &mut i32) -> isize {
     loop {
-        match sys_waittid(tid) {
-            -2 => {
-                yield_();
-            }
-            exit_code => return exit_code,
+        match sys_waittid(tid, exit_code as *mut _, 0) {
+            ret if ret == -Errno::EINTR.0 => {}
+            n => return n,
         }
     }
 }
@@ -330,8 +463,14 @@ pub fn mutex_create() -> isize {
 pub fn mutex_blocking_create() -> isize {
     sys_mutex_create(true)
 }
+/// Lock the mutex `mutex_id`, restarting the wait after a signal handler
 pub fn mutex_lock(mutex_id: usize) -> isize {
-    sys_mutex_lock(mutex_id)
+    loop {
+        match sys_mutex_lock(mutex_id) {
+            ret if ret == -Errno::EINTR.0 => {}
+            n => return n,
+        }
+    }
 }
 pub fn mutex_unlock(mutex_id: usize) {
     sys_mutex_unlock(mutex_id);
@@ -345,8 +484,14 @@ pub fn semaphore_up(sem_id: usize) {
 pub fn enable_deadlock_detect(enabled: bool) -> isize {
     sys_enable_deadlock_detect(enabled as usize)
 }
+/// Take a unit of the semaphore `sem_id`, restarting the wait after a signal handler
 pub fn semaphore_down(sem_id: usize) -> isize {
-    sys_semaphore_down(sem_id)
+    loop {
+        match sys_semaphore_down(sem_id) {
+            ret if ret == -Errno::EINTR.0 => {}
+            n => return n,
+        }
+    }
 }
 pub fn condvar_create() -> isize {
     sys_condvar_create(0)
@@ -354,6 +499,153 @@ pub fn condvar_create() -> isize {
 pub fn condvar_signal(condvar_id: usize) {
     sys_condvar_signal(condvar_id);
 }
+/// Wait on the condition variable `condvar_id`, restarting the wait after a signal handler
 pub fn condvar_wait(condvar_id: usize, mutex_id: usize) {
-    sys_condvar_wait(condvar_id, mutex_id);
+    while sys_condvar_wait(condvar_id, mutex_id) == -Errno::EINTR.0 {}
+}
+
+// The wrappers below return `Err(errno)` instead of a negative value,
+// so that callers can tell the reasons of failure apart.
+
+pub fn try_open(path: &str, flags: OpenFlags) -> Result<usize, Errno> {
+    errno::check(open(path, flags))
+}
+pub fn try_openat(dirfd: usize, path: &str, flags: OpenFlags) -> Result<usize, Errno> {
+    errno::check(openat(dirfd, path, flags))
+}
+pub fn try_close(fd: usize) -> Result<(), Errno> {
+    errno::check(close(fd)).map(|_| ())
+}
+pub fn try_read(fd: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    errno::check(read(fd, buf))
+}
+pub fn try_write(fd: usize, buf: &[u8]) -> Result<usize, Errno> {
+    errno::check(write(fd, buf))
+}
+pub fn try_dup(fd: usize) -> Result<usize, Errno> {
+    errno::check(dup(fd))
+}
+pub fn try_pipe(pipe_fd: &mut [usize]) -> Result<(), Errno> {
+    errno::check(pipe(pipe_fd)).map(|_| ())
+}
+pub fn try_fstat(fd: usize, st: &Stat) -> Result<(), Errno> {
+    errno::check(fstat(fd, st)).map(|_| ())
+}
+pub fn try_link(old_path: &str, new_path: &str) -> Result<(), Errno> {
+    errno::check(link(old_path, new_path)).map(|_| ())
+}
+pub fn try_unlink(path: &str) -> Result<(), Errno> {
+    errno::check(unlink(path)).map(|_| ())
+}
+pub fn try_mkdir(path: &str) -> Result<(), Errno> {
+    errno::check(mkdir(path)).map(|_| ())
+}
+pub fn try_rmdir(path: &str) -> Result<(), Errno> {
+    errno::check(rmdir(path)).map(|_| ())
+}
+pub fn try_chdir(path: &str) -> Result<(), Errno> {
+    errno::check(chdir(path)).map(|_| ())
+}
+/// Return the length of the current working directory including the terminator
+pub fn try_getcwd(buf: &mut [u8]) -> Result<usize, Errno> {
+    errno::check(getcwd(buf))
+}
+pub fn try_fork() -> Result<usize, Errno> {
+    errno::check(fork())
+}
+pub fn try_exec(path: &str, args: &[*const u8]) -> Result<usize, Errno> {
+    errno::check(exec(path, args))
+}
+pub fn try_spawn(path: &str) -> Result<usize, Errno> {
+    errno::check(spawn(path))
+}
+/// Block until the child `pid` exits as [`waitpid`] does
+pub fn try_waitpid(pid: usize, exit_code: &mut i32) -> Result<usize, Errno> {
+    errno::check(waitpid(pid, exit_code))
+}
+/// Return the pid of an exited child `pid`, or fail with EAGAIN instead of waiting for it
+pub fn try_waitpid_nohang(pid: usize, exit_code: &mut i32) -> Result<usize, Errno> {
+    errno::check(sys_waitpid(pid as isize, exit_code as *mut _, WNOHANG))
+}
+/// Return the tid of the exited thread `tid`, or fail with EAGAIN instead of waiting for it
+pub fn try_waittid_nohang(tid: usize, exit_code: &mut i32) -> Result<usize, Errno> {
+    errno::check(sys_waittid(tid, exit_code as *mut _, WNOHANG))
+}
+pub fn try_set_priority(prio: isize) -> Result<usize, Errno> {
+    errno::check(set_priority(prio))
+}
+pub fn try_mmap(start: usize, len: usize, prot: usize) -> Result<(), Errno> {
+    errno::check(mmap(start, len, prot)).map(|_| ())
+}
+pub fn try_mmap_file(
+    start: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> Result<(), Errno> {
+    errno::check(mmap_file(start, len, prot, flags, fd, offset)).map(|_| ())
+}
+pub fn try_brk(addr: usize) -> Result<usize, Errno> {
+    errno::check(brk(addr))
+}
+pub fn try_sbrk(increment: isize) -> Result<usize, Errno> {
+    errno::check(sbrk(increment))
+}
+pub fn try_kill(pid: usize, signum: usize) -> Result<(), Errno> {
+    errno::check(kill(pid, signum)).map(|_| ())
+}
+pub fn try_sigaction(
+    signum: usize,
+    action: Option<&SignalAction>,
+    old_action: Option<&mut SignalAction>,
+) -> Result<(), Errno> {
+    errno::check(sigaction(signum, action, old_action)).map(|_| ())
+}
+pub fn try_sigprocmask(
+    how: usize,
+    set: Option<SignalFlags>,
+    old_set: Option<&mut SignalFlags>,
+) -> Result<(), Errno> {
+    errno::check(sigprocmask(how, set, old_set)).map(|_| ())
+}
+pub fn try_mprotect(start: usize, len: usize, prot: usize) -> Result<(), Errno> {
+    errno::check(mprotect(start, len, prot)).map(|_| ())
+}
+pub fn try_msync(start: usize, len: usize, flags: usize) -> Result<(), Errno> {
+    errno::check(msync(start, len, flags)).map(|_| ())
+}
+pub fn try_munmap(start: usize, len: usize) -> Result<(), Errno> {
+    errno::check(munmap(start, len)).map(|_| ())
+}
+pub fn try_shmget(key: usize, size: usize, flags: usize) -> Result<usize, Errno> {
+    errno::check(shmget(key, size, flags))
+}
+pub fn try_shmat(id: usize, addr: usize, flags: usize) -> Result<usize, Errno> {
+    errno::check(shmat(id, addr, flags))
+}
+pub fn try_shmdt(addr: usize) -> Result<(), Errno> {
+    errno::check(shmdt(addr)).map(|_| ())
+}
+pub fn try_shmctl(id: usize, cmd: usize) -> Result<(), Errno> {
+    errno::check(shmctl(id, cmd)).map(|_| ())
+}
+pub fn try_mutex_lock(mutex_id: usize) -> Result<(), Errno> {
+    errno::check(mutex_lock(mutex_id)).map(|_| ())
+}
+pub fn try_mutex_unlock(mutex_id: usize) -> Result<(), Errno> {
+    errno::check(sys_mutex_unlock(mutex_id)).map(|_| ())
+}
+pub fn try_semaphore_up(sem_id: usize) -> Result<(), Errno> {
+    errno::check(sys_semaphore_up(sem_id)).map(|_| ())
+}
+pub fn try_semaphore_down(sem_id: usize) -> Result<(), Errno> {
+    errno::check(semaphore_down(sem_id)).map(|_| ())
+}
+pub fn try_condvar_signal(condvar_id: usize) -> Result<(), Errno> {
+    errno::check(sys_condvar_signal(condvar_id)).map(|_| ())
+}
+pub fn try_condvar_wait(condvar_id: usize, mutex_id: usize) -> Result<(), Errno> {
+    errno::check(sys_condvar_wait(condvar_id, mutex_id)).map(|_| ())
 }
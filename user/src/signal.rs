@@ -0,0 +1,144 @@
+//! Signals, as the kernel takes them in kill, sigaction and sigprocmask
+
+use core::arch::global_asm;
+
+/// The largest signal number
+pub const MAX_SIG: usize = 31;
+/// The handler taking the default action of a signal
+pub const SIG_DFL: usize = 0;
+/// The handler ignoring a signal
+pub const SIG_IGN: usize = 1;
+
+/// How of sigprocmask to block the signals in the set
+pub const SIG_BLOCK: usize = 0;
+/// How of sigprocmask to unblock the signals in the set
+pub const SIG_UNBLOCK: usize = 1;
+/// How of sigprocmask to block exactly the signals in the set
+pub const SIG_SETMASK: usize = 2;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+bitflags! {
+    /// A set of signals, where signal `n` is bit `n`
+    pub struct SignalFlags: u32 {
+        const SIGHUP = 1 << SIGHUP;
+        const SIGINT = 1 << SIGINT;
+        const SIGQUIT = 1 << SIGQUIT;
+        const SIGILL = 1 << SIGILL;
+        const SIGTRAP = 1 << SIGTRAP;
+        const SIGABRT = 1 << SIGABRT;
+        const SIGBUS = 1 << SIGBUS;
+        const SIGFPE = 1 << SIGFPE;
+        const SIGKILL = 1 << SIGKILL;
+        const SIGUSR1 = 1 << SIGUSR1;
+        const SIGSEGV = 1 << SIGSEGV;
+        const SIGUSR2 = 1 << SIGUSR2;
+        const SIGPIPE = 1 << SIGPIPE;
+        const SIGALRM = 1 << SIGALRM;
+        const SIGTERM = 1 << SIGTERM;
+        const SIGSTKFLT = 1 << 16;
+        const SIGCHLD = 1 << SIGCHLD;
+        const SIGCONT = 1 << SIGCONT;
+        const SIGSTOP = 1 << SIGSTOP;
+        const SIGTSTP = 1 << SIGTSTP;
+        const SIGTTIN = 1 << SIGTTIN;
+        const SIGTTOU = 1 << SIGTTOU;
+        const SIGURG = 1 << SIGURG;
+        const SIGXCPU = 1 << 24;
+        const SIGXFSZ = 1 << 25;
+        const SIGVTALRM = 1 << 26;
+        const SIGPROF = 1 << 27;
+        const SIGWINCH = 1 << SIGWINCH;
+        const SIGIO = 1 << 29;
+        const SIGPWR = 1 << 30;
+        const SIGSYS = 1 << 31;
+    }
+}
+
+impl SignalFlags {
+    /// The set of the single signal `signum`
+    pub fn from_signum(signum: usize) -> Self {
+        Self::from_bits_truncate(1 << signum)
+    }
+}
+
+/// How a signal is handled
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SignalAction {
+    /// The entry of the handler taking the signal number, or [`SIG_DFL`] or [`SIG_IGN`]
+    pub handler: usize,
+    /// Where the handler returns to, filled in by [`sigaction`](crate::sigaction)
+    pub restorer: usize,
+    /// The signals blocked while the handler runs besides the signal itself
+    pub mask: SignalFlags,
+}
+
+impl SignalAction {
+    /// Run `handler` when the signal arrives
+    pub fn new(handler: extern "C" fn(usize), mask: SignalFlags) -> Self {
+        Self {
+            handler: handler as usize,
+            restorer: 0,
+            mask,
+        }
+    }
+    /// Take the default action of the signal
+    pub fn default_action() -> Self {
+        Self {
+            handler: SIG_DFL,
+            restorer: 0,
+            mask: SignalFlags::empty(),
+        }
+    }
+    /// Ignore the signal
+    pub fn ignore() -> Self {
+        Self {
+            handler: SIG_IGN,
+            ..Self::default_action()
+        }
+    }
+}
+
+// A handler returns here with the stack pointer still at the frame the kernel saved,
+// and a7 is SYSCALL_SIGRETURN
+global_asm!(
+    "
+    .section .text
+    .globl __sigreturn_trampoline
+__sigreturn_trampoline:
+    li a7, 139
+    ecall
+    "
+);
+
+extern "C" {
+    fn __sigreturn_trampoline();
+}
+
+/// The restorer every handler registered through [`sigaction`](crate::sigaction) returns to
+pub(crate) fn sigreturn_trampoline() -> usize {
+    __sigreturn_trampoline as usize
+}